@@ -0,0 +1,64 @@
+#!/bin/bash
+# Measures the client transaction throughput of a cluster of two servers built from a git
+# revision, each backed by the in-memory inner provider of examples/inner_stub.rs. The load is
+# generated by the `bench` command of the working tree, so that every revision sees the same
+# client. Prints one JSON line per run, see RESULTS.md.
+#
+# usage: bench/cluster/run.sh <revision> [transactions] [concurrency] [runs]
+set -euo pipefail
+
+REV=$1
+N=${2:-20000}
+C=${3:-64}
+RUNS=${4:-3}
+
+ROOT=$(git rev-parse --show-toplevel)
+WORK=$(mktemp -d)
+export CARGO_TARGET_DIR="$ROOT/target/bench"
+
+cleanup() {
+    kill $(jobs -p) 2>/dev/null || true
+    wait 2>/dev/null || true
+    git -C "$ROOT" worktree remove --force "$WORK/tree" 2>/dev/null || true
+    rm -rf "$WORK"
+}
+trap cleanup EXIT
+
+build() {
+    cargo build -q --release --manifest-path "$@" 2> "$WORK/build.log" || {
+        cat "$WORK/build.log" >&2
+        exit 1
+    }
+}
+
+# the client and the inner provider come from the working tree
+build "$ROOT/Cargo.toml" --bin MerkleVerseWrapper --example inner_stub
+cp "$CARGO_TARGET_DIR/release/MerkleVerseWrapper" "$WORK/client"
+cp "$CARGO_TARGET_DIR/release/examples/inner_stub" "$WORK/inner_stub"
+
+# the servers, and the configs they read, come from the revision
+git -C "$ROOT" worktree add -q --detach "$WORK/tree" "$REV"
+build "$WORK/tree/Cargo.toml" --bin MerkleVerseWrapper
+SERVER="$WORK/server"
+cp "$CARGO_TARGET_DIR/release/MerkleVerseWrapper" "$SERVER"
+
+mkdir "$WORK/configs"
+"$SERVER" gen-peers -s "$ROOT/bench/cluster/meta.toml" -t "$WORK/configs" > /dev/null
+for i in 0 1; do
+    # admission must not be what is measured
+    cat >> "$WORK/configs/root_$i.toml" <<ADMISSION
+
+[server.admission]
+client_rate = 1000000
+client_burst = 1000000
+pool_capacity = 10000000
+ADMISSION
+    PORT=$((9600 + i)) "$WORK/inner_stub" > "$WORK/inner_$i.log" 2>&1 &
+    RUST_LOG=warn "$SERVER" server -c "$WORK/configs/root_$i.toml" > "$WORK/server_$i.log" 2>&1 &
+done
+sleep 2
+
+echo "# revision $(git -C "$ROOT" rev-parse --short "$REV"), $N transactions, concurrency $C" >&2
+for ((run = 0; run < RUNS; run++)); do
+    "$WORK/client" bench -s 127.0.0.1:9500 -n "$N" --concurrency "$C" -o json
+done
@@ -0,0 +1,181 @@
+//! An in-memory inner provider serving the `MerkleProvider` service of `proto/inner.proto` on
+//! `$PORT`, for benchmarks of the wrapper without a MerkleSquare backend. Roots chain a SHA-256
+//! digest of the sorted writes of each epoch, so that replicas agree, and proofs are empty.
+
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tonic::{transport::Server, Request, Response, Status};
+
+#[allow(dead_code)]
+mod mversegrpc {
+    tonic::include_proto!("mversegrpc");
+}
+
+use mversegrpc::lookup_history_request::LookUpType;
+use mversegrpc::merkle_provider_server::{MerkleProvider, MerkleProviderServer};
+use mversegrpc::transaction_request::TransactionType;
+use mversegrpc::transaction_response::TransactionStatus;
+use mversegrpc::*;
+
+type Value = Option<Vec<u8>>; // none once the key is deleted
+
+#[derive(Debug, Default)]
+struct State {
+    pending: Vec<(Vec<u8>, Value)>, // writes of the open epoch, in order
+    values: HashMap<Vec<u8>, Vec<(u64, Value)>>, // key -> (epoch, value) written
+    heads: Vec<Vec<u8>>,            // the root of each epoch
+}
+
+impl State {
+    fn head(&self) -> Vec<u8> {
+        self.heads.last().cloned().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stub {
+    state: Mutex<State>,
+}
+
+fn proof() -> Option<MerkleProof> {
+    Some(MerkleProof { proof: vec![] })
+}
+
+#[tonic::async_trait]
+impl MerkleProvider for Stub {
+    async fn get_root(
+        &self,
+        request: Request<GetMerkleRootRequest>,
+    ) -> Result<Response<GetMerkleRootResponse>, Status> {
+        let epoch = request.into_inner().epoch.map_or(0, |e| e.epoch);
+        let state = self.state.lock();
+        let head = state
+            .heads
+            .get(epoch as usize)
+            .cloned()
+            .ok_or(Status::not_found(format!(
+                "Epoch {} is not committed",
+                epoch
+            )))?;
+        Ok(Response::new(GetMerkleRootResponse {
+            head,
+            epoch: Some(Epoch { epoch }),
+        }))
+    }
+
+    async fn get_current_root(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<GetMerkleRootResponse>, Status> {
+        let state = self.state.lock();
+        Ok(Response::new(GetMerkleRootResponse {
+            head: state.head(),
+            epoch: Some(Epoch {
+                epoch: state.heads.len().saturating_sub(1) as u64,
+            }),
+        }))
+    }
+
+    async fn trigger_epoch(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<TriggerEpochResponse>, Status> {
+        let mut state = self.state.lock();
+        let epoch = state.heads.len() as u64;
+        let pending = std::mem::take(&mut state.pending);
+        // servers send the same writes in their own order, the root must not depend on it
+        let mut writes: Vec<&(Vec<u8>, Value)> = pending.iter().collect();
+        writes.sort();
+        let mut hasher = Sha256::new();
+        hasher.update(state.head());
+        for (key, value) in writes {
+            hasher.update((key.len() as u64).to_be_bytes());
+            hasher.update(key);
+            hasher.update(value.as_deref().unwrap_or_default());
+        }
+        for (key, value) in pending {
+            state.values.entry(key).or_default().push((epoch, value));
+        }
+        let head = hasher.finalize().to_vec();
+        state.heads.push(head.clone());
+        Ok(Response::new(TriggerEpochResponse {
+            head,
+            new_epoch: Some(Epoch { epoch: epoch + 1 }),
+        }))
+    }
+
+    async fn look_up_latest(
+        &self,
+        request: Request<LookUpLatestRequest>,
+    ) -> Result<Response<LookUpLatestResponse>, Status> {
+        let key = request.into_inner().key;
+        let state = self.state.lock();
+        let value = state
+            .values
+            .get(&key)
+            .and_then(|versions| versions.last())
+            .and_then(|(_, value)| value.clone())
+            .unwrap_or_default();
+        Ok(Response::new(LookUpLatestResponse {
+            value,
+            proof: proof(),
+            head: state.head(),
+        }))
+    }
+
+    async fn transaction(
+        &self,
+        request: Request<TransactionRequest>,
+    ) -> Result<Response<TransactionResponse>, Status> {
+        let req = request.into_inner();
+        let value = match req.transaction_type() {
+            TransactionType::Update => Some(req.value.unwrap_or_default()),
+            TransactionType::Delete => None,
+        };
+        let mut state = self.state.lock();
+        state.pending.push((req.key, value));
+        Ok(Response::new(TransactionResponse {
+            status: TransactionStatus::Success.into(),
+            head: state.head(),
+        }))
+    }
+
+    async fn look_up_history(
+        &self,
+        request: Request<LookupHistoryRequest>,
+    ) -> Result<Response<LookUpHistoryResponse>, Status> {
+        let req = request.into_inner();
+        let state = self.state.lock();
+        let versions = state.values.get(&req.key).map_or(&[][..], |v| v.as_slice());
+        let from = match req.lookup_type() {
+            LookUpType::Last => versions.len().saturating_sub(req.n as usize),
+            LookUpType::Since => versions.partition_point(|(epoch, _)| *epoch < req.n),
+            LookUpType::Complete => 0,
+        };
+        let selected: Vec<Vec<u8>> = versions[from..]
+            .iter()
+            .filter_map(|(_, value)| value.clone())
+            .collect();
+        Ok(Response::new(LookUpHistoryResponse {
+            proof: selected
+                .iter()
+                .map(|_| MerkleProof { proof: vec![] })
+                .collect(),
+            values: selected,
+            head: state.head(),
+        }))
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let port: u16 = std::env::var("PORT")?.parse()?;
+    let addr = ([127, 0, 0, 1], port).into();
+    println!("Inner provider stub listening on {}", addr);
+    Server::builder()
+        .add_service(MerkleProviderServer::new(Stub::default()))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
@@ -12,6 +12,21 @@ pub struct Args {
 pub enum Commands {
     Server(ServerArgs),
     GenPeers(GenPeerArgs),
+    ValidateConfig(ValidateConfigArgs),
+    Monitor(MonitorArgs),
+    Put(PutArgs),
+    Delete(TransactionArgs),
+    Get(KeyArgs),
+    History(HistoryArgs),
+    Root(RootArgs),
+    Info(ClientArgs),
+    Status(ClientArgs),
+    Join(MembershipArgs),
+    Leave(MembershipArgs),
+    Keygen(KeygenArgs),
+    Pubkey(PubkeyArgs),
+    Rotate(RotateArgs),
+    Bench(BenchArgs),
 }
 
 #[derive(Parser, Debug)]
@@ -24,6 +39,197 @@ pub struct ServerArgs {
 pub struct GenPeerArgs {
     #[arg(short, long)]
     pub src: PathBuf,
+    /// directory the configs are written to, with the private keys they hold
     #[arg(short, long)]
-    pub to: Option<PathBuf>,
+    pub to: PathBuf,
+    /// write the private keys to passphrase-encrypted keystores instead of the configs
+    #[arg(short, long)]
+    pub encrypt: bool,
+    /// also write a deployment manifest next to the configs
+    #[arg(short, long, value_enum)]
+    pub manifest: Option<ManifestKind>,
+    /// also issue a CA and certificates binding each server to its id, enabling mutual TLS
+    #[arg(long)]
+    pub tls: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct ValidateConfigArgs {
+    /// a server config, or a directory of configs written by `gen-peers`
+    pub path: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+pub struct KeygenArgs {
+    /// path of the keystore to create
+    #[arg(short, long)]
+    pub out: PathBuf,
+    /// overwrite an existing keystore
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct PubkeyArgs {
+    #[arg(short, long, conflicts_with = "config")]
+    pub keystore: Option<PathBuf>,
+    /// server config whose private key is loaded
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct RotateArgs {
+    #[arg(short, long, required_unless_present = "config")]
+    pub keystore: Option<PathBuf>,
+    /// server config whose public keys are updated, and whose private key is rotated if no
+    /// keystore is given
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct MonitorArgs {
+    #[arg(short, long)]
+    pub config: PathBuf,
+    #[arg(short, long, required = true)]
+    pub servers: Vec<String>,
+    #[arg(long)]
+    pub from_epoch: Option<u64>,
+    /// signers a certificate needs, a quorum of the members of its cluster by default
+    #[arg(long)]
+    pub min_signers: Option<usize>,
+    #[command(flatten)]
+    pub tls: TlsArgs,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ManifestKind {
+    DockerCompose,
+    Systemd,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum OutputFormat {
+    Json,
+    Table,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum KeyFormat {
+    Base64,
+    Binary,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum HistoryMode {
+    Last,
+    Since,
+    Complete,
+}
+
+/// TLS options of clients, a server is reached over TLS when a CA is given
+#[derive(Parser, Debug, Default)]
+pub struct TlsArgs {
+    /// CA certificate of the cluster, in PEM
+    #[arg(long)]
+    pub tls_ca: Option<PathBuf>,
+    /// client certificate, for servers requiring one
+    #[arg(long, requires_all = ["tls_ca", "tls_key"])]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// name to check the server certificate against, instead of the host of the address
+    #[arg(long, requires = "tls_ca")]
+    pub tls_domain: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct ClientArgs {
+    #[arg(short, long, default_value = "127.0.0.1:8000")]
+    pub server: String,
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Table)]
+    pub output: OutputFormat,
+    /// server config providing the public keys used to verify certificates
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub tls: TlsArgs,
+}
+
+#[derive(Parser, Debug)]
+pub struct KeyArgs {
+    #[command(flatten)]
+    pub client: ClientArgs,
+    pub key: String,
+    #[arg(short, long, value_enum, default_value_t = KeyFormat::Base64)]
+    pub key_format: KeyFormat,
+}
+
+#[derive(Parser, Debug)]
+pub struct TransactionArgs {
+    #[command(flatten)]
+    pub key: KeyArgs,
+    /// wait until the transaction is committed
+    #[arg(short, long)]
+    pub wait: bool,
+    /// id making retries of the transaction return its original receipt
+    #[arg(long)]
+    pub idempotency_id: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct BenchArgs {
+    #[command(flatten)]
+    pub client: ClientArgs,
+    /// number of transactions to submit, each to a random key
+    #[arg(short = 'n', long, default_value_t = 10000)]
+    pub transactions: usize,
+    /// number of transactions in flight at once
+    #[arg(long, default_value_t = 64)]
+    pub concurrency: usize,
+    /// length of the random keys, in bytes
+    #[arg(long, default_value_t = 32)]
+    pub key_bytes: usize,
+}
+
+#[derive(Parser, Debug)]
+pub struct PutArgs {
+    #[command(flatten)]
+    pub transaction: TransactionArgs,
+    pub value: String,
+    /// decode the value from base64 instead of using its UTF-8 bytes
+    #[arg(long)]
+    pub value_b64: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct HistoryArgs {
+    #[command(flatten)]
+    pub key: KeyArgs,
+    #[arg(short, long, value_enum, default_value_t = HistoryMode::Last)]
+    pub mode: HistoryMode,
+    /// number of values for `last`, first epoch for `since`
+    #[arg(short, long, default_value_t = 10)]
+    pub n: u64,
+}
+
+#[derive(Parser, Debug)]
+pub struct RootArgs {
+    #[command(flatten)]
+    pub client: ClientArgs,
+    #[arg(short, long)]
+    pub epoch: Option<u64>,
+}
+
+#[derive(Parser, Debug)]
+pub struct MembershipArgs {
+    #[command(flatten)]
+    pub client: ClientArgs,
+    /// config of the server joining or leaving, whose key signs the change
+    #[arg(short, long)]
+    pub member: PathBuf,
+    /// configs of current members approving a join, a quorum of the members must approve
+    #[arg(short, long = "approver")]
+    pub approvers: Vec<PathBuf>,
 }
@@ -0,0 +1,659 @@
+use crate::args::{
+    BenchArgs, ClientArgs, HistoryArgs, HistoryMode, KeyArgs, KeyFormat, MembershipArgs,
+    OutputFormat, PutArgs, RootArgs, TransactionArgs,
+};
+use crate::config::ServersConfig;
+use crate::grpc_handler::inner::mversegrpc::{
+    lookup_history_request::LookUpType, transaction_request::TransactionType, Epoch,
+    GetMerkleRootRequest, LookUpLatestRequest, MerkleProof, TransactionRequest,
+};
+use crate::grpc_handler::outer::mverseouter::membership_change::ChangeType;
+use crate::grpc_handler::outer::mverseouter::run_state::Phase;
+use crate::grpc_handler::outer::mverseouter::{
+    ClientTransactionRequest, ClusterMember, Empty, KeyHistoryRequest, MemberInfo,
+    MembershipChange, RootUpdate, RunState,
+};
+use crate::grpc_handler::outer::{MerkleVerseClient, TransactionResult};
+use crate::monitor::Monitor;
+use crate::server::{approve, propose, ServerId};
+use crate::server::client_endpoint;
+use crate::utils::{b64, binary_to_bytes};
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine as _};
+use bls_signatures::Serialize as _;
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tonic::transport::Channel;
+use tonic::Code;
+
+/// A connection to a MerkleVerse server, optionally able to verify the certificates it returns.
+struct Client {
+    inner: MerkleVerseClient<Channel>,
+    output: OutputFormat,
+    verifier: Option<(String, Monitor)>,
+}
+
+impl Client {
+    async fn connect(args: &ClientArgs) -> Result<Self> {
+        let mut inner =
+            MerkleVerseClient::connect(client_endpoint(&args.server, &args.tls)?).await?;
+        let verifier = match &args.config {
+            Some(path) => {
+                let server_id = inner
+                    .get_server_information(Empty {})
+                    .await?
+                    .into_inner()
+                    .server_id;
+                let mut monitor = Monitor::from_config(&ServersConfig::with_path(path)?, None)?;
+                let announcements = inner.get_key_announcements(Empty {}).await?.into_inner();
+                monitor.add_announcements(announcements.announcements);
+                let membership = inner.get_membership(Empty {}).await?.into_inner();
+                monitor.add_membership(&server_id, membership.records);
+                Some((server_id, monitor))
+            }
+            None => None,
+        };
+        Ok(Self {
+            inner,
+            output: args.output,
+            verifier,
+        })
+    }
+
+    /// checks the certificate of `root` if a config was given
+    fn verify_root(&self, root: &RootUpdate) -> String {
+        match &self.verifier {
+            None => "skipped, no config given".into(),
+            Some((server, monitor)) => match monitor.check_certificate(server, root) {
+                Ok(_) => format!(
+                    "ok, certified by {}",
+                    root.certificate
+                        .as_ref()
+                        .map(|c| c.signers.join(","))
+                        .unwrap_or_default()
+                ),
+                Err(alert) => format!("failed: {}", alert),
+            },
+        }
+    }
+
+    /// Checks that a proof was issued against the certified root of `epoch`, and verifies the
+    /// certificate of that root. The proof itself is in the format of the inner provider, which
+    /// the client does not decode.
+    async fn verify_proof(
+        &mut self,
+        epoch: Option<u64>,
+        head: &[u8],
+        proof: Option<&MerkleProof>,
+    ) -> Result<String> {
+        if proof.is_none_or(|p| p.proof.is_empty()) {
+            return Ok("failed: no proof returned".into());
+        }
+        let Some(epoch) = epoch else {
+            return Ok("failed: the proof names no committed epoch".into());
+        };
+        let root = match self
+            .inner
+            .get_certified_root(GetMerkleRootRequest {
+                epoch: Some(Epoch { epoch }),
+            })
+            .await
+        {
+            Ok(root) => root.into_inner(),
+            Err(status) => return Ok(format!("failed: epoch {}: {}", epoch, status.message())),
+        };
+        if root.head != head {
+            return Ok(format!(
+                "failed: head is not the certified root of epoch {}",
+                epoch
+            ));
+        }
+        Ok(self.verify_root(&root))
+    }
+
+    fn print<T: Serialize>(&self, output: &T) -> Result<()> {
+        let value = serde_json::to_value(output)?;
+        match self.output {
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&value)?),
+            OutputFormat::Table => print_table(&value),
+        }
+        Ok(())
+    }
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => "-".into(),
+        other => other.to_string(),
+    }
+}
+
+fn print_rows(rows: &[Value]) {
+    let columns: Vec<&String> = match rows.first() {
+        Some(Value::Object(first)) => first.keys().collect(),
+        _ => return rows.iter().for_each(|r| println!("{}", scalar(r))),
+    };
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| columns.iter().map(|c| scalar(&row[c.as_str()])).collect())
+        .collect();
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            cells
+                .iter()
+                .map(|r| r[i].len())
+                .chain([c.len()])
+                .max()
+                .unwrap()
+        })
+        .collect();
+    let line = |row: Vec<&str>| {
+        let padded: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(columns.iter().map(|c| c.as_str()).collect());
+    for row in &cells {
+        line(row.iter().map(|c| c.as_str()).collect());
+    }
+}
+
+fn print_table(value: &Value) {
+    match value {
+        Value::Object(fields) => {
+            let width = fields.keys().map(|k| k.len()).max().unwrap_or_default();
+            for (key, field) in fields {
+                match field {
+                    Value::Array(rows) => {
+                        println!("{}:", key);
+                        print_rows(rows);
+                    }
+                    _ => println!("{:width$}  {}", key, scalar(field), width = width),
+                }
+            }
+        }
+        Value::Array(rows) => print_rows(rows),
+        other => println!("{}", scalar(other)),
+    }
+}
+
+fn parse_key(args: &KeyArgs) -> Result<Vec<u8>> {
+    match args.key_format {
+        KeyFormat::Base64 => Ok(general_purpose::STANDARD.decode(&args.key)?),
+        KeyFormat::Binary => binary_to_bytes(&args.key),
+    }
+}
+
+#[derive(Serialize)]
+struct TransactionOutput {
+    key: String,
+    status: &'static str,
+    epoch: u64,
+    replayed: bool,
+    committed: bool,
+}
+
+async fn transaction(
+    args: &TransactionArgs,
+    transaction_type: TransactionType,
+    value: Option<Vec<u8>>,
+) -> Result<()> {
+    let key = parse_key(&args.key)?;
+    let mut client = Client::connect(&args.key.client).await?;
+    let res = client
+        .inner
+        .client_transaction(ClientTransactionRequest {
+            transaction: Some(TransactionRequest {
+                transaction_type: transaction_type.into(),
+                key: key.clone(),
+                value,
+            }),
+            auxiliary: None,
+            wait: args.wait,
+            idempotency_id: args.idempotency_id.clone(),
+        })
+        .await?
+        .into_inner();
+    client.print(&TransactionOutput {
+        key: b64(&key),
+        status: match res.status() {
+            TransactionResult::Ok => "OK",
+            TransactionResult::Duplicate => "DUPLICATE",
+        },
+        epoch: res.epoch,
+        replayed: res.replayed,
+        committed: args.wait,
+    })
+}
+
+pub async fn put(args: PutArgs) -> Result<()> {
+    let value = match args.value_b64 {
+        true => general_purpose::STANDARD.decode(&args.value)?,
+        false => args.value.into_bytes(),
+    };
+    transaction(&args.transaction, TransactionType::Update, Some(value)).await
+}
+
+pub async fn delete(args: TransactionArgs) -> Result<()> {
+    transaction(&args, TransactionType::Delete, None).await
+}
+
+#[derive(Serialize)]
+struct GetOutput {
+    key: String,
+    value: String,
+    epoch: Option<u64>,
+    head: String,
+    proof_size: usize,
+    verification: String,
+}
+
+pub async fn get(args: KeyArgs) -> Result<()> {
+    let key = parse_key(&args)?;
+    let mut client = Client::connect(&args.client).await?;
+    let res = client
+        .inner
+        .look_up_latest(LookUpLatestRequest { key: key.clone() })
+        .await?
+        .into_inner();
+    let epoch = res.epoch.map(|e| e.epoch);
+    let verification = client
+        .verify_proof(epoch, &res.head, res.proof.as_ref())
+        .await?;
+    client.print(&GetOutput {
+        key: b64(&key),
+        value: b64(&res.value),
+        epoch,
+        head: b64(&res.head),
+        proof_size: res.proof.map(|p| p.proof.len()).unwrap_or_default(),
+        verification,
+    })
+}
+
+#[derive(Serialize)]
+struct VersionOutput {
+    epoch: u64,
+    value: String,
+    head: String,
+    proof_size: usize,
+    verification: String,
+}
+
+#[derive(Serialize)]
+struct HistoryOutput {
+    key: String,
+    verification: String,
+    versions: Vec<VersionOutput>,
+}
+
+pub async fn history(args: HistoryArgs) -> Result<()> {
+    let key = parse_key(&args.key)?;
+    let mut client = Client::connect(&args.key.client).await?;
+    let (lookup_type, n) = match args.mode {
+        HistoryMode::Last => (LookUpType::Last, args.n),
+        HistoryMode::Since => (LookUpType::Since, args.n),
+        HistoryMode::Complete => (LookUpType::Complete, 0),
+    };
+    let mut versions = vec![];
+    let mut page_token = vec![];
+    loop {
+        let res = client
+            .inner
+            .look_up_history(KeyHistoryRequest {
+                key: key.clone(),
+                lookup_type: lookup_type.into(),
+                n,
+                page_token,
+                page_size: 0,
+            })
+            .await?
+            .into_inner();
+        let count = res.values.len();
+        if res.proof.len() != count || res.epochs.len() != count || res.heads.len() != count {
+            return Err(anyhow!(
+                "Server returned {} values with {} proofs, {} epochs and {} heads",
+                count,
+                res.proof.len(),
+                res.epochs.len(),
+                res.heads.len()
+            ));
+        }
+        for (((value, proof), epoch), head) in
+            res.values.iter().zip(&res.proof).zip(&res.epochs).zip(&res.heads)
+        {
+            // each proof is checked against the certified root of the epoch it was issued for
+            let verification = client
+                .verify_proof(Some(epoch.epoch), head, Some(proof))
+                .await?;
+            versions.push(VersionOutput {
+                epoch: epoch.epoch,
+                value: b64(value),
+                head: b64(head),
+                proof_size: proof.proof.len(),
+                verification,
+            });
+        }
+        if res.next_page_token.is_empty() {
+            break;
+        }
+        page_token = res.next_page_token;
+    }
+    let verification = match versions.iter().find(|v| v.verification.starts_with("failed")) {
+        Some(v) => format!("failed: epoch {}", v.epoch),
+        None => format!("{} versions checked", versions.len()),
+    };
+    client.print(&HistoryOutput {
+        key: b64(&key),
+        verification,
+        versions,
+    })
+}
+
+#[derive(Serialize)]
+struct RootOutput {
+    epoch: u64,
+    head: String,
+    chain: String,
+    signers: String,
+    verification: String,
+}
+
+pub async fn root(args: RootArgs) -> Result<()> {
+    let mut client = Client::connect(&args.client).await?;
+    let root = client
+        .inner
+        .get_certified_root(GetMerkleRootRequest {
+            epoch: args.epoch.map(|epoch| Epoch { epoch }),
+        })
+        .await?
+        .into_inner();
+    client.print(&RootOutput {
+        epoch: root.epoch.as_ref().map(|e| e.epoch).unwrap_or_default(),
+        head: b64(&root.head),
+        chain: b64(&root.chain),
+        signers: root
+            .certificate
+            .as_ref()
+            .map(|c| c.signers.join(","))
+            .unwrap_or_default(),
+        verification: client.verify_root(&root),
+    })
+}
+
+#[derive(Serialize)]
+struct InfoOutput {
+    server_id: String,
+    server_name: String,
+    pool: String,
+    client_rate: String,
+    pending_per_key: u64,
+    tracked_clients: u64,
+    rejected: String,
+}
+
+pub async fn info(args: ClientArgs) -> Result<()> {
+    let mut client = Client::connect(&args).await?;
+    let res = client
+        .inner
+        .get_server_information(Empty {})
+        .await?
+        .into_inner();
+    let admission = res.admission.unwrap_or_default();
+    client.print(&InfoOutput {
+        server_id: res.server_id,
+        server_name: res.server_name,
+        pool: format!("{}/{}", admission.pool_size, admission.pool_capacity),
+        client_rate: format!(
+            "{}/s, burst {}",
+            admission.client_rate, admission.client_burst
+        ),
+        pending_per_key: admission.pending_per_key,
+        tracked_clients: admission.tracked_clients,
+        rejected: format!(
+            "{} rate limited, {} pool full, {} key limit",
+            admission.rejected_rate_limited,
+            admission.rejected_pool_full,
+            admission.rejected_key_limit
+        ),
+    })
+}
+
+#[derive(Serialize)]
+struct EpochPoolOutput {
+    epoch: u64,
+    pending: u64,
+}
+
+#[derive(Serialize)]
+struct PeerStatusOutput {
+    server_id: String,
+    run_state: String,
+    failures: u32,
+    last_error: String,
+    leaving: bool,
+}
+
+#[derive(Serialize)]
+struct StatusOutput {
+    server_id: String,
+    ready: bool,
+    draining: bool,
+    epoch: u64,
+    run_state: String,
+    since_last_commit_ms: Option<u64>,
+    inner: String,
+    superior: String,
+    parallel: String,
+    pool: Vec<EpochPoolOutput>,
+    peers: Vec<PeerStatusOutput>,
+}
+
+fn run_state(state: Option<RunState>) -> String {
+    match state.unwrap_or_default() {
+        state if state.phase() == Phase::Prepare => format!("prepare {}", state.epoch),
+        _ => "normal".into(),
+    }
+}
+
+fn member_ids(members: &[ClusterMember]) -> String {
+    let ids: Vec<&str> = members.iter().map(|m| m.server_id.as_str()).collect();
+    ids.join(",")
+}
+
+pub async fn status(args: ClientArgs) -> Result<()> {
+    let mut client = Client::connect(&args).await?;
+    let res = client.inner.get_status(Empty {}).await?.into_inner();
+    client.print(&StatusOutput {
+        server_id: res.server_id,
+        ready: res.ready,
+        draining: res.draining,
+        epoch: res.current_epoch,
+        run_state: run_state(res.run_state),
+        since_last_commit_ms: res.since_last_commit_ms,
+        inner: match res.inner_reachable {
+            true => "reachable".into(),
+            false => format!("unreachable: {}", res.inner_error),
+        },
+        superior: member_ids(&res.superior),
+        parallel: member_ids(&res.parallel),
+        pool: res
+            .pool
+            .iter()
+            .map(|p| EpochPoolOutput {
+                epoch: p.epoch,
+                pending: p.pending,
+            })
+            .collect(),
+        peers: res
+            .peers
+            .into_iter()
+            .map(|p| PeerStatusOutput {
+                run_state: run_state(p.run_state),
+                server_id: p.server_id,
+                failures: p.failures,
+                last_error: p.last_error,
+                leaving: p.leaving,
+            })
+            .collect(),
+    })
+}
+
+#[derive(Serialize)]
+struct BenchOutput {
+    transactions: usize,
+    concurrency: usize,
+    elapsed_ms: u128,
+    throughput: String,
+    ok: usize,
+    duplicate: usize,
+    rejected: usize,
+    failed: usize,
+    p50_ms: f64,
+    p99_ms: f64,
+}
+
+/// what one worker of `bench` saw: the latency of every transaction, and how it was answered
+#[derive(Default)]
+struct BenchWorker {
+    latencies: Vec<Duration>,
+    ok: usize,
+    duplicate: usize,
+    rejected: usize,
+    failed: usize,
+}
+
+/// Submits `args.transactions` transactions to random keys, `args.concurrency` at a time, and
+/// reports the rate at which the server accepted them.
+pub async fn bench(args: BenchArgs) -> Result<()> {
+    let client = Client::connect(&args.client).await?;
+    let next = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    let workers: Vec<_> = (0..args.concurrency.max(1))
+        .map(|_| {
+            let (mut inner, next) = (client.inner.clone(), next.clone());
+            let (total, key_bytes) = (args.transactions, args.key_bytes);
+            tokio::spawn(async move {
+                let mut worker = BenchWorker::default();
+                while next.fetch_add(1, Ordering::Relaxed) < total {
+                    let key: Vec<u8> = (0..key_bytes).map(|_| rand::random()).collect();
+                    let sent = Instant::now();
+                    let res = inner
+                        .client_transaction(ClientTransactionRequest {
+                            transaction: Some(TransactionRequest {
+                                transaction_type: TransactionType::Update.into(),
+                                key,
+                                value: Some(vec![1]),
+                            }),
+                            auxiliary: None,
+                            wait: false,
+                            idempotency_id: None,
+                        })
+                        .await;
+                    worker.latencies.push(sent.elapsed());
+                    match res {
+                        Ok(res) if res.get_ref().status() == TransactionResult::Ok => worker.ok += 1,
+                        Ok(_) => worker.duplicate += 1,
+                        Err(status) if status.code() == Code::ResourceExhausted => {
+                            worker.rejected += 1
+                        }
+                        Err(_) => worker.failed += 1,
+                    }
+                }
+                worker
+            })
+        })
+        .collect();
+    let mut total = BenchWorker::default();
+    for worker in futures::future::try_join_all(workers).await? {
+        total.latencies.extend(worker.latencies);
+        total.ok += worker.ok;
+        total.duplicate += worker.duplicate;
+        total.rejected += worker.rejected;
+        total.failed += worker.failed;
+    }
+    let elapsed = start.elapsed();
+    total.latencies.sort();
+    let percentile = |p: usize| {
+        total
+            .latencies
+            .get((total.latencies.len() * p / 100).min(total.latencies.len().saturating_sub(1)))
+            .map_or(0.0, |d| d.as_secs_f64() * 1000.0)
+    };
+    client.print(&BenchOutput {
+        transactions: args.transactions,
+        concurrency: args.concurrency,
+        elapsed_ms: elapsed.as_millis(),
+        throughput: format!("{:.0}/s", total.ok as f64 / elapsed.as_secs_f64()),
+        ok: total.ok,
+        duplicate: total.duplicate,
+        rejected: total.rejected,
+        failed: total.failed,
+        p50_ms: percentile(50),
+        p99_ms: percentile(99),
+    })
+}
+
+#[derive(Serialize)]
+struct MembershipOutput {
+    server_id: String,
+    change: &'static str,
+    version: u64,
+    status: &'static str,
+}
+
+async fn membership_change(args: MembershipArgs, change_type: ChangeType) -> Result<()> {
+    let config = ServersConfig::with_path(&args.member)?;
+    let key = config.server.load_private_key()?;
+    let public_key = key.public_key();
+    let mut client = Client::connect(&args.client).await?;
+    let version = client
+        .inner
+        .get_membership(Empty {})
+        .await?
+        .into_inner()
+        .version;
+    let srv = &config.server.server_config;
+    let change = MembershipChange {
+        change_type: change_type.into(),
+        member: Some(MemberInfo {
+            server_id: srv.id.clone(),
+            connection_string: srv.connection_string.clone(),
+            bls_pub_key: public_key.bls.as_bytes(),
+            dalek_pub_key: public_key.dalek.as_bytes().to_vec(),
+        }),
+        version,
+    };
+    let mut proposal = propose(change.clone(), &key);
+    for path in &args.approvers {
+        let approver = ServersConfig::with_path(path)?.server;
+        proposal.approvals.push(approve(
+            &change,
+            &ServerId(approver.server_config.id.clone()),
+            &approver.load_private_key()?,
+        ));
+    }
+    client.inner.propose_membership(proposal).await?;
+    client.print(&MembershipOutput {
+        server_id: srv.id.clone(),
+        change: change_type.as_str_name(),
+        version,
+        status: "PROPOSED",
+    })
+}
+
+pub async fn join(args: MembershipArgs) -> Result<()> {
+    membership_change(args, ChangeType::Join).await
+}
+
+pub async fn leave(args: MembershipArgs) -> Result<()> {
+    membership_change(args, ChangeType::Leave).await
+}
@@ -1,10 +1,24 @@
+use crate::keystore::{passphrase, write_secret, Keystore, PRIVATE_KEY_ENV};
+use crate::server::{cluster_id, KeyDerivation, PrivateKey, PublicKey};
 use crate::utils::{b64_to_loc, binary_string};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine as _};
 use config::{Config, ConfigBuilder, ConfigError, Environment, File};
 
 use serde::{Deserialize, Serialize, Serializer};
 
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+const REDACTED_FIELDS: [&str; 1] = ["server.private_key"];
+// written by `rotate`, the running server takes the new key on SIGUSR1 and announces it
+const ROTATION_FIELDS: [&str; 4] = [
+    "server.private_key",
+    "server.bls_pub_key",
+    "server.dalek_pub_key",
+    "server.keystore",
+];
 
 #[derive(Debug, Deserialize, Serialize, Default, Clone)]
 pub struct ServerConfig {
@@ -17,6 +31,129 @@ pub struct ServerConfig {
     pub dalek_pub_key: String,
 }
 
+/// Timeouts of the channels to peers and the inner provider, and retries of broadcasts to peers,
+/// in milliseconds.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    pub connect_timeout_ms: u64,
+    pub request_timeout_ms: u64,
+    pub health_interval_ms: u64, // also the HTTP/2 keep-alive interval
+    pub backoff_initial_ms: u64, // delay before retrying a backend after a failed health check
+    pub backoff_max_ms: u64,
+    pub broadcast_deadline_ms: u64, // time a peer has to acknowledge a broadcast, retries included
+    pub broadcast_retries: u32,
+    pub retry_backoff_ms: u64, // delay before the first retry, doubled on each retry and jittered
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_ms: 1000,
+            request_timeout_ms: 5000,
+            health_interval_ms: 5000,
+            backoff_initial_ms: 500,
+            backoff_max_ms: 30000,
+            broadcast_deadline_ms: 3000,
+            broadcast_retries: 2,
+            retry_backoff_ms: 100,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Limits on the client transactions admitted into the transaction pool.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct AdmissionConfig {
+    pub client_rate: u32,  // transactions per second refilled in the bucket of each client
+    pub client_burst: u32, // size of the bucket of each client
+    pub pool_capacity: usize, // transactions pending per epoch, from clients and peers alike
+    pub pending_per_key: usize, // pending writes to the same key per epoch
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            client_rate: 100,
+            client_burst: 200,
+            pool_capacity: 10000,
+            pending_per_key: 8,
+        }
+    }
+}
+
+impl AdmissionConfig {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Lifetime of the transactions of the pool.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct PoolConfig {
+    // committed epochs whose transactions are kept for subscriptions, and to answer resubmissions
+    // without an idempotency id as duplicates
+    #[serde(alias = "duplicate_window")]
+    pub retained_epochs: usize,
+    pub idempotency_window_ms: u64, // how long the receipts of idempotency ids are remembered
+    // epochs after the one it was first pooled for that a transaction is pooled again for, when
+    // the cluster commits past it, before it is dropped
+    pub transaction_ttl_epochs: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            retained_epochs: 64,
+            idempotency_window_ms: 600000,
+            transaction_ttl_epochs: 16,
+        }
+    }
+}
+
+impl PoolConfig {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// How a shutdown drains the server.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    pub drain_timeout_ms: u64, // time given to the in-flight epoch, the pool handoff and the leave notice
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout_ms: 10000,
+        }
+    }
+}
+
+impl ShutdownConfig {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// PEM files of the CA of the cluster and of the certificate of this server, enabling mutual TLS
+/// on the outer listener and towards peers.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub ca: PathBuf,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct LocalServerConfig {
     #[serde(flatten)]
@@ -24,11 +161,30 @@ pub struct LocalServerConfig {
     pub outer_port: u16,
     pub outer_addr: String,
     pub inner_port: u16,
-    pub private_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<String>, // base64 private key, in clear
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keystore: Option<PathBuf>, // passphrase-encrypted private key file, relative to the config
     pub epoch_interval: u32, // epoch interval in miliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<String>, // tracing filter, used when RUST_LOG is not set
+    #[serde(default, skip_serializing_if = "ConnectionConfig::is_default")]
+    pub connections: ConnectionConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    #[serde(default, skip_serializing_if = "AdmissionConfig::is_default")]
+    pub admission: AdmissionConfig,
+    #[serde(default, skip_serializing_if = "PoolConfig::is_default")]
+    pub pool: PoolConfig,
+    #[serde(default, skip_serializing_if = "ShutdownConfig::is_default")]
+    pub shutdown: ShutdownConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_dir: Option<PathBuf>, // state kept across restarts, `<id>.data` by default
+    #[serde(skip)]
+    pub base_dir: PathBuf, // directory of the config file, relative paths are resolved against it
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ServersConfig {
     pub server: LocalServerConfig,
     pub peers: Option<Vec<ServerConfig>>,
@@ -47,16 +203,160 @@ impl ServersConfig {
             .add_source(Environment::with_prefix("MERKLEVERSE"))
             .build()?;
 
-        s.try_deserialize()
+        let mut config: Self = s.try_deserialize()?;
+        if let Some(dir) = path.as_ref().parent() {
+            config.server.base_dir = dir.to_path_buf();
+        }
+        Ok(config)
     }
 
+    /// writes this config to `path`, readable by its owner only if it holds a private key
     pub fn to_path<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         let res = toml::to_string(&self)?;
-        std::fs::write(path, res)?;
+        match self.server.private_key {
+            Some(_) => write_secret(path.as_ref(), res.as_bytes())?,
+            None => std::fs::write(path, res)?,
+        }
         Ok(())
     }
 }
 
+/// A field that differs between two configs, named like `server.epoch_interval` or
+/// `peers.<id>.connection_string`. `None` stands for a field or peer that is absent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigChange {
+    pub field: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl ConfigChange {
+    /// whether the change can be applied to a running server, without a restart or a
+    /// membership change agreed on by its cluster
+    pub fn is_live(&self) -> bool {
+        match self.field.as_str() {
+            "server.epoch_interval" | "server.log_level" => true,
+            field => {
+                field.starts_with("peers.")
+                    && field.ends_with(".connection_string")
+                    && self.old.is_some()
+                    && self.new.is_some()
+            }
+        }
+    }
+
+    /// whether the field is owned by key rotation, which a reload leaves to the key announcement
+    pub fn is_rotation(&self) -> bool {
+        ROTATION_FIELDS.contains(&self.field.as_str())
+    }
+}
+
+impl Display for ConfigChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let show = |value: &Option<String>| match value {
+            None => "(none)".to_string(),
+            Some(_) if REDACTED_FIELDS.contains(&self.field.as_str()) => "(redacted)".to_string(),
+            Some(value) => format!("{:?}", value),
+        };
+        write!(f, "{}: {} -> {}", self.field, show(&self.old), show(&self.new))
+    }
+}
+
+impl ServersConfig {
+    /// every set field of this config, peers keyed by their id
+    fn fields(&self) -> Result<BTreeMap<String, String>> {
+        fn flatten(prefix: &str, value: serde_json::Value, fields: &mut BTreeMap<String, String>) {
+            if let serde_json::Value::Object(map) = value {
+                for (key, value) in map {
+                    let value = match value {
+                        serde_json::Value::Null => continue,
+                        serde_json::Value::String(s) => s,
+                        other => other.to_string(),
+                    };
+                    fields.insert(format!("{}.{}", prefix, key), value);
+                }
+            }
+        }
+        let mut fields = BTreeMap::new();
+        flatten("server", serde_json::to_value(&self.server)?, &mut fields);
+        for peer in self.peers.iter().flatten() {
+            let prefix = format!("peers.{}", peer.id);
+            flatten(&prefix, serde_json::to_value(peer)?, &mut fields);
+        }
+        Ok(fields)
+    }
+
+    /// the fields that differ from this config in `other`
+    pub fn diff(&self, other: &Self) -> Result<Vec<ConfigChange>> {
+        let (mut old, mut new) = (self.fields()?, other.fields()?);
+        let names: BTreeSet<String> = old.keys().chain(new.keys()).cloned().collect();
+        Ok(names
+            .into_iter()
+            .filter_map(|field| {
+                let change = ConfigChange {
+                    old: old.remove(&field),
+                    new: new.remove(&field),
+                    field,
+                };
+                (change.old != change.new).then_some(change)
+            })
+            .collect())
+    }
+}
+
+impl LocalServerConfig {
+    /// `path` as given in the config, relative to the directory of the config file
+    pub fn resolve(&self, path: &Path) -> PathBuf {
+        self.base_dir.join(path)
+    }
+
+    /// the TLS files of this server, resolved against the directory of the config
+    pub fn tls_files(&self) -> Option<TlsConfig> {
+        self.tls.as_ref().map(|tls| TlsConfig {
+            ca: self.resolve(&tls.ca),
+            cert: self.resolve(&tls.cert),
+            key: self.resolve(&tls.key),
+        })
+    }
+
+    pub fn data_dir(&self) -> PathBuf {
+        match &self.data_dir {
+            Some(dir) => self.resolve(dir),
+            None => self.resolve(Path::new(&format!("{}.data", self.server_config.id))),
+        }
+    }
+
+    /// Loads the private key of this server from the environment, the config itself, or the
+    /// keystore it points to, in that order.
+    pub fn load_private_key(&self) -> Result<PrivateKey> {
+        let key = self.read_private_key()?;
+        if key.derivation() == KeyDerivation::Legacy {
+            tracing::warn!(
+                "Server {} uses a legacy private key, run `rotate` to migrate it",
+                self.server_config.id
+            );
+        }
+        Ok(key)
+    }
+
+    fn read_private_key(&self) -> Result<PrivateKey> {
+        if let Ok(key) = std::env::var(PRIVATE_KEY_ENV) {
+            return PrivateKey::try_from(general_purpose::STANDARD.decode(key.trim())?);
+        }
+        if let Some(key) = &self.private_key {
+            return PrivateKey::try_from(general_purpose::STANDARD.decode(key)?);
+        }
+        match &self.keystore {
+            Some(path) => Keystore::read(self.resolve(path))?.decrypt(&passphrase(false)?),
+            None => Err(anyhow!(
+                "Server {} has neither a private key nor a keystore, and {} is not set",
+                self.server_config.id,
+                PRIVATE_KEY_ENV
+            )),
+        }
+    }
+}
+
 fn prefix_bin(prefix: &Option<String>, length: &Option<u32>) -> Result<String> {
     match (prefix, length) {
         (Some(pref), Some(len)) => {
@@ -71,6 +371,17 @@ impl ServerConfig {
     pub fn prefix_bin(&self) -> Result<String> {
         prefix_bin(&self.prefix, &self.prefix_length)
     }
+
+    pub fn cluster_id(&self) -> Result<String> {
+        Ok(cluster_id(&self.prefix_bin()?, self.length))
+    }
+
+    pub fn public_key(&self) -> Result<PublicKey> {
+        PublicKey::new(
+            &general_purpose::STANDARD.decode(&self.bls_pub_key)?,
+            &general_purpose::STANDARD.decode(&self.dalek_pub_key)?,
+        )
+    }
 }
 
 #[cfg(test)]
@@ -95,4 +406,67 @@ mod tests {
         println!("{:#?}", target_srv);
         Ok(())
     }
+
+    #[test]
+    fn tst_config_diff() -> Result<()> {
+        let peer = ServerConfig {
+            connection_string: "127.0.0.1:8001".into(),
+            id: "srv_1".into(),
+            length: 4,
+            ..Default::default()
+        };
+        let current = ServersConfig {
+            server: LocalServerConfig {
+                server_config: ServerConfig {
+                    id: "srv_0".into(),
+                    ..peer.clone()
+                },
+                outer_port: 8000,
+                outer_addr: "127.0.0.1:8000".into(),
+                inner_port: 9000,
+                private_key: Some("a2V5".into()),
+                keystore: None,
+                epoch_interval: 1000,
+                log_level: None,
+                connections: Default::default(),
+                tls: None,
+                admission: Default::default(),
+                pool: Default::default(),
+                shutdown: Default::default(),
+                data_dir: None,
+                base_dir: Default::default(),
+            },
+            peers: Some(vec![peer]),
+        };
+        assert!(current.diff(&current)?.is_empty());
+
+        let mut live = current.clone();
+        live.server.epoch_interval = 500;
+        live.server.log_level = Some("debug".into());
+        live.peers.as_mut().unwrap()[0].connection_string = "10.0.0.1:8001".into();
+        let changes = current.diff(&live)?;
+        assert_eq!(changes.len(), 3);
+        assert!(changes.iter().all(ConfigChange::is_live));
+
+        let mut rejected = live.clone();
+        rejected.server.server_config.length = 5;
+        rejected.server.private_key = Some("b3RoZXI=".into());
+        rejected.peers = None;
+        let changes = current.diff(&rejected)?;
+        let fields: Vec<&str> = changes
+            .iter()
+            .filter(|c| !c.is_live())
+            .map(|c| c.field.as_str())
+            .collect();
+        assert!(fields.contains(&"server.length"));
+        assert!(fields.contains(&"peers.srv_1.connection_string"));
+        let key_change = changes
+            .iter()
+            .find(|c| c.field == "server.private_key")
+            .unwrap();
+        assert!(!key_change.to_string().contains("b3RoZXI="));
+        assert!(key_change.is_rotation());
+        assert!(!changes.iter().any(|c| c.field == "server.length" && c.is_rotation()));
+        Ok(())
+    }
 }
@@ -1,12 +1,16 @@
 use crate::grpc_handler::inner::mversegrpc;
 use crate::grpc_handler::outer::mverseouter::{
     ClientTransactionRequest, Empty, PeerCommitRequest, PeerPrepareRequest, PeerTransactionRequest,
+    EquivocationReport, GossipMessage, KeyAnnouncement, KeyAnnouncements, KeyHistoryRequest,
+    KeyHistoryResponse, KeyLookUpResponse, KeyUpdate, MembershipHistory, MembershipProposal,
+    PeerLeavingRequest, RootChain, RootChainRequest, RootUpdate, ServerIdentity, ServerStatus, WatchKeysRequest, WatchRootsRequest,
 };
 use crate::server;
+use crate::server::HistoryQuery;
 use anyhow::{anyhow, Result};
+use mversegrpc::lookup_history_request::LookUpType;
 pub use mversegrpc::{
-    GetMerkleRootRequest, GetMerkleRootResponse, LookUpHistoryResponse, LookUpLatestRequest,
-    LookUpLatestResponse, LookupHistoryRequest, TransactionRequest,
+    GetMerkleRootRequest, GetMerkleRootResponse, LookUpLatestRequest, TransactionRequest,
 };
 pub use mverseouter::{
     merkle_verse_client::MerkleVerseClient,
@@ -14,6 +18,7 @@ pub use mverseouter::{
     transaction_response::TransactionResult,
     ServerInformationResponse, TransactionResponse,
 };
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{IntoRequest, Request, Response, Status};
 use tracing::instrument;
 
@@ -21,10 +26,21 @@ pub mod mverseouter {
     tonic::include_proto!("mverseouter");
 }
 
+const MAX_HISTORY_PAGE: u32 = 256; // maximum number of values returned by a single history lookup
+
 fn err_transform(e: anyhow::Error) -> Status {
     Status::internal(e.to_string())
 }
 
+fn auth_err_transform(e: anyhow::Error) -> Status {
+    Status::permission_denied(e.to_string())
+}
+
+/// the server id a peer request claims to come from, if any
+fn claimed_id(identity: &Option<ServerIdentity>) -> Option<&str> {
+    identity.as_ref().map(|id| id.server_id.as_str())
+}
+
 #[tonic::async_trait]
 impl MerkleVerse for server::MerkleVerseServer {
     #[instrument]
@@ -35,22 +51,68 @@ impl MerkleVerse for server::MerkleVerseServer {
         Ok(Response::new(ServerInformationResponse {
             server_name: "Outer Merkle Verse Server".into(),
             server_id: self.id.0.clone(),
+            admission: Some(self.admission_state()),
         }))
     }
 
     #[instrument]
     async fn look_up_history(
         &self,
-        request: Request<LookupHistoryRequest>,
-    ) -> Result<Response<LookUpHistoryResponse>, Status> {
-        let mut inn_client = self.get_inner_client().await?;
-        let inn_req: mversegrpc::LookupHistoryRequest = request.into_inner();
-        let res: LookUpHistoryResponse = inn_client
-            .look_up_history(inn_req.into_request())
-            .await?
-            .into_inner();
-
-        Ok(Response::new(res))
+        request: Request<KeyHistoryRequest>,
+    ) -> Result<Response<KeyHistoryResponse>, Status> {
+        let inn_req = request.into_inner();
+        let n = inn_req.n;
+        let query = match inn_req.lookup_type() {
+            LookUpType::Last => {
+                if n == 0 || n > MAX_HISTORY_PAGE as u64 {
+                    return Err(Status::invalid_argument(format!(
+                        "n must be between 1 and {} for Last lookups",
+                        MAX_HISTORY_PAGE
+                    )));
+                }
+                HistoryQuery::Last(n as usize)
+            }
+            LookUpType::Since => {
+                if n > self.current_epoch() {
+                    return Err(Status::invalid_argument(format!(
+                        "Epoch {} has not been reached yet",
+                        n
+                    )));
+                }
+                HistoryQuery::Since(n)
+            }
+            LookUpType::Complete => {
+                if n != 0 {
+                    return Err(Status::invalid_argument(
+                        "n is not used by Complete lookups, use page_size instead",
+                    ));
+                }
+                if inn_req.page_size > MAX_HISTORY_PAGE {
+                    return Err(Status::invalid_argument(format!(
+                        "page_size must not exceed {}",
+                        MAX_HISTORY_PAGE
+                    )));
+                }
+                let after = match inn_req.page_token.len() {
+                    0 => None,
+                    8 => Some(u64::from_be_bytes(inn_req.page_token[..].try_into().unwrap())),
+                    _ => return Err(Status::invalid_argument("Malformed page_token")),
+                };
+                HistoryQuery::Complete {
+                    after,
+                    limit: match inn_req.page_size {
+                        0 => MAX_HISTORY_PAGE as usize,
+                        size => size as usize,
+                    },
+                }
+            }
+        };
+        match self.key_history(&inn_req.key, query) {
+            Some(res) => Ok(Response::new(res)),
+            None => Err(Status::out_of_range(
+                "The history asked for is not retained by this server",
+            )),
+        }
     }
 
     #[instrument]
@@ -58,7 +120,9 @@ impl MerkleVerse for server::MerkleVerseServer {
         &self,
         _request: Request<Empty>,
     ) -> Result<Response<GetMerkleRootResponse>, Status> {
-        let mut inn_client = self.get_inner_client().await?;
+        let mut inn_client = self
+            .get_inner_client()
+            .map_err(|e| Status::unavailable(e.to_string()))?;
         let inn_req = mversegrpc::Empty {};
         let res: GetMerkleRootResponse = inn_client
             .get_current_root(inn_req.into_request())
@@ -72,7 +136,9 @@ impl MerkleVerse for server::MerkleVerseServer {
         &self,
         request: Request<GetMerkleRootRequest>,
     ) -> Result<Response<GetMerkleRootResponse>, Status> {
-        let mut inn_client = self.get_inner_client().await?;
+        let mut inn_client = self
+            .get_inner_client()
+            .map_err(|e| Status::unavailable(e.to_string()))?;
         let inn_req: GetMerkleRootRequest = request.into_inner();
         let res: GetMerkleRootResponse = inn_client
             .get_root(inn_req.into_request())
@@ -85,14 +151,24 @@ impl MerkleVerse for server::MerkleVerseServer {
     async fn look_up_latest(
         &self,
         request: Request<LookUpLatestRequest>,
-    ) -> Result<Response<LookUpLatestResponse>, Status> {
-        let mut inn_client = self.get_inner_client().await?;
+    ) -> Result<Response<KeyLookUpResponse>, Status> {
+        let mut inn_client = self
+            .get_inner_client()
+            .map_err(|e| Status::unavailable(e.to_string()))?;
         let inn_req: LookUpLatestRequest = request.into_inner();
         let res = inn_client
             .look_up_latest(inn_req.into_request())
             .await?
             .into_inner();
-        Ok(Response::new(res))
+        // names the epoch the proof was issued for, so that clients check it against its certificate
+        Ok(Response::new(KeyLookUpResponse {
+            epoch: self
+                .committed_epoch_of(&res.head)
+                .map(|epoch| mversegrpc::Epoch { epoch }),
+            value: res.value,
+            proof: res.proof,
+            head: res.head,
+        }))
     }
 
     #[instrument]
@@ -100,18 +176,34 @@ impl MerkleVerse for server::MerkleVerseServer {
         &self,
         request: Request<ClientTransactionRequest>,
     ) -> Result<Response<TransactionResponse>, Status> {
+        // clients are rate limited by address
+        let client = request
+            .remote_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_default();
+        if self.is_draining() {
+            return Err(Status::unavailable("The server is shutting down"));
+        }
         let inn_req = request.into_inner();
         let wait = inn_req.wait;
         let res = self
-            .receive_client_transaction(inn_req, wait)
+            .receive_client_transaction(&client, inn_req, wait)
             .await
-            .map_err(err_transform)
-            .map(|res| TransactionResponse {
-                status: match res {
-                    None => TransactionResult::Ok,
-                    Some(_) => TransactionResult::Duplicate,
+            .map_err(|e| match e.downcast_ref::<server::AdmissionError>() {
+                Some(rejection) => {
+                    tracing::debug!("Rejected a transaction of {}: {:?}", client, rejection.reason);
+                    Status::resource_exhausted(e.to_string())
+                }
+                None => err_transform(e),
+            })
+            .map(|receipt| TransactionResponse {
+                status: match receipt.duplicate {
+                    false => TransactionResult::Ok,
+                    true => TransactionResult::Duplicate,
                 }
                 .into(),
+                epoch: receipt.epoch,
+                replayed: receipt.replayed,
             })?;
         Ok(Response::new(res))
     }
@@ -121,17 +213,25 @@ impl MerkleVerse for server::MerkleVerseServer {
         &self,
         request: Request<PeerTransactionRequest>,
     ) -> Result<Response<TransactionResponse>, Status> {
+        self.authenticate_peer(&request, &request.get_ref().server_id)
+            .map_err(auth_err_transform)?;
         let inn_req = request.into_inner();
+        let epoch = inn_req.epoch.as_ref().map_or(0, |e| e.epoch);
         let res = self
             .receive_peer_transaction(inn_req)
             .await
-            .map_err(err_transform)
+            .map_err(|e| match e.downcast_ref::<server::AdmissionError>() {
+                Some(_) => Status::resource_exhausted(e.to_string()),
+                None => err_transform(e),
+            })
             .map(|res| TransactionResponse {
                 status: match res {
                     None => TransactionResult::Ok,
                     Some(_) => TransactionResult::Duplicate,
                 }
                 .into(),
+                epoch,
+                replayed: false,
             })?;
         Ok(Response::new(res))
     }
@@ -141,6 +241,11 @@ impl MerkleVerse for server::MerkleVerseServer {
         &self,
         request: Request<PeerPrepareRequest>,
     ) -> Result<Response<Empty>, Status> {
+        let sender = self
+            .authenticate_rpc(request.get_ref())
+            .map_err(auth_err_transform)?;
+        self.authenticate_peer(&request, &sender.0)
+            .map_err(auth_err_transform)?;
         let inn_req = request.into_inner();
         self.receive_prepare(
             inn_req
@@ -148,12 +253,8 @@ impl MerkleVerse for server::MerkleVerseServer {
                 .ok_or(anyhow!("An epoch number must be provided!"))
                 .map_err(err_transform)?
                 .epoch,
-            inn_req
-                .peer_identity
-                .ok_or(anyhow!("A peer identity must be provided!"))
-                .map_err(err_transform)?
-                .server_id
-                .into(),
+            sender,
+            inn_req.membership,
         )
         .await
         .map_err(err_transform)?;
@@ -165,13 +266,28 @@ impl MerkleVerse for server::MerkleVerseServer {
         &self,
         request: Request<PeerCommitRequest>,
     ) -> Result<Response<Empty>, Status> {
+        let sender = self
+            .authenticate_rpc(request.get_ref())
+            .map_err(auth_err_transform)?;
+        self.authenticate_peer(&request, &sender.0)
+            .map_err(auth_err_transform)?;
         let inn_req = request.into_inner();
+        if !inn_req.membership_signature.is_empty() {
+            if let Err(e) = self.receive_membership_signature(
+                inn_req.membership_version,
+                sender.clone(),
+                &inn_req.membership_signature,
+            ) {
+                tracing::warn!("Rejected membership signature of {}: {}", sender.0, e);
+            }
+        }
         self.receive_signatures(
             inn_req
                 .epoch
                 .ok_or(anyhow!("An epoch number must be provided!"))
                 .map_err(err_transform)?
                 .epoch,
+            sender,
             &inn_req.head,
             &inn_req.signature,
         )
@@ -179,4 +295,166 @@ impl MerkleVerse for server::MerkleVerseServer {
         .map_err(err_transform)?;
         Ok(Response::new(Empty {}))
     }
+
+    #[instrument]
+    async fn get_certified_root(
+        &self,
+        request: Request<GetMerkleRootRequest>,
+    ) -> Result<Response<RootUpdate>, Status> {
+        let epoch = request.into_inner().epoch.map(|e| e.epoch);
+        let root = self
+            .certified_root(epoch)
+            .ok_or(Status::not_found(match epoch {
+                Some(epoch) => format!("Epoch {} is not committed or no longer retained", epoch),
+                None => "No epoch has been committed yet".into(),
+            }))?;
+        Ok(Response::new(root))
+    }
+
+    type WatchRootsStream = ReceiverStream<Result<RootUpdate, Status>>;
+
+    #[instrument]
+    async fn watch_roots(
+        &self,
+        request: Request<WatchRootsRequest>,
+    ) -> Result<Response<Self::WatchRootsStream>, Status> {
+        Ok(Response::new(
+            self.subscribe_roots(request.into_inner().from_epoch),
+        ))
+    }
+
+    type WatchKeysStream = ReceiverStream<Result<KeyUpdate, Status>>;
+
+    #[instrument]
+    async fn watch_keys(
+        &self,
+        request: Request<WatchKeysRequest>,
+    ) -> Result<Response<Self::WatchKeysStream>, Status> {
+        let inn_req = request.into_inner();
+        if inn_req.keys.is_empty() && inn_req.prefixes.is_empty() {
+            return Err(Status::invalid_argument(
+                "At least one key or prefix must be provided!",
+            ));
+        }
+        let prefixes = inn_req
+            .prefixes
+            .into_iter()
+            .map(|p| (p.prefix, p.length))
+            .collect();
+        Ok(Response::new(self.subscribe_keys(inn_req.keys, prefixes)))
+    }
+
+    #[instrument]
+    async fn get_root_chain(
+        &self,
+        request: Request<RootChainRequest>,
+    ) -> Result<Response<RootChain>, Status> {
+        let inn_req = request.into_inner();
+        if inn_req.from_epoch > inn_req.to_epoch {
+            return Err(Status::invalid_argument(
+                "from_epoch must not be greater than to_epoch",
+            ));
+        }
+        let chain = self
+            .root_chain(inn_req.from_epoch, inn_req.to_epoch)
+            .ok_or(Status::out_of_range(format!(
+                "Epochs {} to {} are not committed or no longer retained",
+                inn_req.from_epoch, inn_req.to_epoch
+            )))?;
+        Ok(Response::new(chain))
+    }
+
+    #[instrument]
+    async fn gossip(
+        &self,
+        request: Request<GossipMessage>,
+    ) -> Result<Response<GossipMessage>, Status> {
+        self.authenticate_claimed(&request, claimed_id(&request.get_ref().peer_identity))
+            .map_err(auth_err_transform)?;
+        let observations = self
+            .receive_gossip(request.into_inner().observations)
+            .map_err(err_transform)?;
+        Ok(Response::new(GossipMessage {
+            peer_identity: Some(ServerIdentity {
+                server_id: self.id.0.clone(),
+            }),
+            observations,
+        }))
+    }
+
+    #[instrument]
+    async fn get_equivocations(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<EquivocationReport>, Status> {
+        Ok(Response::new(self.equivocations()))
+    }
+
+    #[instrument]
+    async fn announce_key(
+        &self,
+        request: Request<KeyAnnouncement>,
+    ) -> Result<Response<Empty>, Status> {
+        self.authenticate_peer(&request, &request.get_ref().server_id)
+            .map_err(auth_err_transform)?;
+        self.receive_key_announcement(request.into_inner())
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument]
+    async fn get_key_announcements(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<KeyAnnouncements>, Status> {
+        Ok(Response::new(self.key_announcements()))
+    }
+
+    #[instrument]
+    async fn propose_membership(
+        &self,
+        request: Request<MembershipProposal>,
+    ) -> Result<Response<Empty>, Status> {
+        // proposals relayed by a member name it, others are sent with the certificate of their
+        // subject
+        let proposal = request.get_ref();
+        let subject = proposal
+            .change
+            .as_ref()
+            .and_then(|change| change.member.as_ref())
+            .map(|member| member.server_id.as_str());
+        self.authenticate_claimed(&request, claimed_id(&proposal.peer_identity).or(subject))
+            .map_err(auth_err_transform)?;
+        self.receive_membership_proposal(request.into_inner())
+            .await
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument]
+    async fn get_membership(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<MembershipHistory>, Status> {
+        Ok(Response::new(self.membership_history()))
+    }
+
+    #[instrument]
+    async fn peer_leaving(
+        &self,
+        request: Request<PeerLeavingRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let sender = self
+            .authenticate_rpc(request.get_ref())
+            .map_err(auth_err_transform)?;
+        self.authenticate_peer(&request, &sender.0)
+            .map_err(auth_err_transform)?;
+        self.receive_leaving(sender).map_err(err_transform)?;
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument]
+    async fn get_status(&self, _request: Request<Empty>) -> Result<Response<ServerStatus>, Status> {
+        Ok(Response::new(self.status().await))
+    }
 }
@@ -0,0 +1,314 @@
+use crate::args::{KeygenArgs, PubkeyArgs, RotateArgs};
+use crate::config::ServersConfig;
+use crate::server::{PrivateKey, PublicKey};
+use crate::utils::b64;
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use bls_signatures::Serialize as _;
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+pub const PRIVATE_KEY_ENV: &str = "MERKLEVERSE_PRIVATE_KEY"; // base64 private key, overrides the config
+pub const PASSPHRASE_ENV: &str = "MERKLEVERSE_KEYSTORE_PASSPHRASE"; // passphrase used instead of prompting
+
+const KEYSTORE_VERSION: u32 = 1;
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+    pub salt: String,
+}
+
+/// A private key encrypted with a passphrase, stored apart from the server config. The public keys
+/// are kept in clear so they can be read without the passphrase, and are authenticated by the
+/// encryption.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Keystore {
+    pub version: u32,
+    pub bls_pub_key: String,
+    pub dalek_pub_key: String,
+    pub kdf: KdfParams,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+fn derive_key(passphrase: &str, kdf: &KdfParams) -> Result<chacha20poly1305::Key> {
+    let params = scrypt::Params::new(kdf.log_n, kdf.r, kdf.p, 32)
+        .map_err(|e| anyhow!("Invalid keystore parameters: {}", e))?;
+    let salt = general_purpose::STANDARD.decode(&kdf.salt)?;
+    let mut key = chacha20poly1305::Key::default();
+    scrypt::scrypt(passphrase.as_bytes(), &salt, &params, &mut key)
+        .map_err(|e| anyhow!("Failed to derive keystore key: {}", e))?;
+    Ok(key)
+}
+
+impl Keystore {
+    pub fn encrypt(key: &PrivateKey, passphrase: &str) -> Result<Self> {
+        Self::seal(key, passphrase, SCRYPT_LOG_N)
+    }
+
+    fn seal(key: &PrivateKey, passphrase: &str, log_n: u8) -> Result<Self> {
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let public_key = key.public_key();
+        let mut keystore = Self {
+            version: KEYSTORE_VERSION,
+            bls_pub_key: b64(&public_key.bls.as_bytes()),
+            dalek_pub_key: b64(public_key.dalek.as_bytes()),
+            kdf: KdfParams {
+                log_n,
+                r: SCRYPT_R,
+                p: SCRYPT_P,
+                salt: b64(&salt),
+            },
+            nonce: b64(&nonce),
+            ciphertext: String::new(),
+        };
+        let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &keystore.kdf)?);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: key.as_bytes(),
+                    aad: keystore.associated_data().as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to encrypt private key"))?;
+        keystore.ciphertext = b64(&ciphertext);
+        Ok(keystore)
+    }
+
+    /// the clear fields bound to the ciphertext
+    fn associated_data(&self) -> String {
+        format!(
+            "{}:{}:{}",
+            self.version, self.bls_pub_key, self.dalek_pub_key
+        )
+    }
+
+    pub fn decrypt(&self, passphrase: &str) -> Result<PrivateKey> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(anyhow!("Unsupported keystore version {}", self.version));
+        }
+        let nonce: [u8; 12] = general_purpose::STANDARD
+            .decode(&self.nonce)?
+            .try_into()
+            .map_err(|_| anyhow!("Keystore nonce has an invalid length"))?;
+        let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &self.kdf)?);
+        let raw = cipher
+            .decrypt(
+                &nonce.into(),
+                Payload {
+                    msg: &general_purpose::STANDARD.decode(&self.ciphertext)?,
+                    aad: self.associated_data().as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Wrong passphrase or corrupted keystore"))?;
+        let key = PrivateKey::try_from(raw)?;
+        if key.public_key() != self.public_key()? {
+            return Err(anyhow!("Keystore public keys do not match its private key"));
+        }
+        Ok(key)
+    }
+
+    pub fn public_key(&self) -> Result<PublicKey> {
+        PublicKey::new(
+            &general_purpose::STANDARD.decode(&self.bls_pub_key)?,
+            &general_purpose::STANDARD.decode(&self.dalek_pub_key)?,
+        )
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Failed to read keystore {}", path.as_ref().display()))?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        write_secret(path.as_ref(), toml::to_string(self)?.as_bytes())
+    }
+}
+
+/// Writes a file holding a secret, readable and writable by its owner only, also when it
+/// replaces a file with wider permissions.
+pub fn write_secret(path: &Path, content: &[u8]) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    file.write_all(content)?;
+    Ok(())
+}
+
+/// Reads the keystore passphrase from the environment, or prompts for it on the terminal without
+/// echoing it.
+pub fn passphrase(confirm: bool) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let prompt = |msg: &str| -> Result<String> {
+        rpassword::prompt_password(format!("{}: ", msg))
+            .with_context(|| format!("Failed to read the passphrase, or set {}", PASSPHRASE_ENV))
+    };
+    let passphrase = prompt("Keystore passphrase")?;
+    if confirm && prompt("Repeat passphrase")? != passphrase {
+        return Err(anyhow!("Passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+fn print_public_key(public_key: &PublicKey) {
+    println!("bls_pub_key = \"{}\"", b64(&public_key.bls.as_bytes()));
+    println!("dalek_pub_key = \"{}\"", b64(public_key.dalek.as_bytes()));
+}
+
+pub fn keygen(args: KeygenArgs) -> Result<()> {
+    if args.out.exists() && !args.force {
+        return Err(anyhow!(
+            "{} already exists, pass --force to overwrite it",
+            args.out.display()
+        ));
+    }
+    let key = PrivateKey::generate();
+    Keystore::encrypt(&key, &passphrase(true)?)?.write(&args.out)?;
+    eprintln!("Wrote keystore {}", args.out.display());
+    print_public_key(&key.public_key());
+    Ok(())
+}
+
+pub fn pubkey(args: PubkeyArgs) -> Result<()> {
+    let public_key = match (args.keystore, args.config) {
+        (Some(path), None) => Keystore::read(path)?.public_key()?,
+        (None, Some(path)) => ServersConfig::with_path(path)?
+            .server
+            .load_private_key()?
+            .public_key(),
+        _ => return Err(anyhow!("Specify exactly one of --keystore and --config")),
+    };
+    print_public_key(&public_key);
+    Ok(())
+}
+
+/// Copies `path` next to itself with an `.old` extension, numbered after the backups kept by
+/// earlier rotations so that none of them is overwritten.
+fn backup(path: &Path) -> Result<PathBuf> {
+    let backup = (0..)
+        .map(|n| {
+            let mut backup = path.to_path_buf().into_os_string();
+            backup.push(".old");
+            if n > 0 {
+                backup.push(format!(".{}", n));
+            }
+            PathBuf::from(backup)
+        })
+        .find(|backup| !backup.exists())
+        .unwrap();
+    std::fs::copy(path, &backup)?;
+    Ok(backup)
+}
+
+/// Replaces the key of a keystore, or the key written in a server config, with a fresh one derived
+/// with the current scheme. The previous file is kept next to it, and the public keys of the server
+/// config are updated if one is given.
+pub fn rotate(args: RotateArgs) -> Result<()> {
+    let mut config = match &args.config {
+        Some(path) => Some((ServersConfig::with_path(path)?, path)),
+        None => None,
+    };
+    let key = PrivateKey::generate();
+
+    match &args.keystore {
+        Some(path) => {
+            let passphrase = passphrase(false)?;
+            Keystore::read(path)?.decrypt(&passphrase)?;
+            let keystore = Keystore::encrypt(&key, &passphrase)?;
+            let backup = backup(path)?;
+            keystore.write(path)?;
+            eprintln!(
+                "Rotated keystore {}, previous key kept in {}",
+                path.display(),
+                backup.display()
+            );
+        }
+        None => {
+            let (config, path) = config
+                .as_mut()
+                .ok_or(anyhow!("Specify a keystore or a server config to rotate"))?;
+            if config.server.private_key.is_none() {
+                return Err(anyhow!(
+                    "{} does not contain a private key, specify its keystore",
+                    path.display()
+                ));
+            }
+            config.server.private_key = Some(b64(key.as_bytes()));
+            eprintln!(
+                "Rotated the private key of {}, previous config kept in {}",
+                path.display(),
+                backup(path)?.display()
+            );
+        }
+    }
+
+    let public_key = key.public_key();
+    if let Some((mut config, path)) = config {
+        config.server.server_config.bls_pub_key = b64(&public_key.bls.as_bytes());
+        config.server.server_config.dalek_pub_key = b64(public_key.dalek.as_bytes());
+        config.to_path(path)?;
+        eprintln!("Updated the public keys in {}", path.display());
+    }
+    eprintln!("Send SIGUSR1 to the running server to announce the new key to its peers");
+    print_public_key(&public_key);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tst_backup() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("mverse-keystore-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("srv.keystore");
+        write_secret(&path, b"first")?;
+        assert_eq!(std::fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        assert_eq!(backup(&path)?, dir.join("srv.keystore.old"));
+        write_secret(&path, b"second")?;
+        assert_eq!(backup(&path)?, dir.join("srv.keystore.old.1"));
+        assert_eq!(std::fs::read(dir.join("srv.keystore.old"))?, b"first");
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn tst_keystore() -> Result<()> {
+        let key = PrivateKey::from([7u8; 32]);
+        let keystore = Keystore::seal(&key, "correct horse", 4)?;
+        assert_eq!(keystore.decrypt("correct horse")?, key);
+        assert!(keystore.decrypt("wrong").is_err());
+
+        let parsed: Keystore = toml::from_str(&toml::to_string(&keystore)?)?;
+        assert_eq!(parsed.public_key()?, key.public_key());
+
+        let mut swapped = parsed.clone();
+        swapped.bls_pub_key = b64(&PrivateKey::from([8u8; 32]).public_key().bls.as_bytes());
+        assert!(swapped.decrypt("correct horse").is_err());
+        Ok(())
+    }
+}
@@ -1,48 +1,99 @@
 use std::fs;
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 use ::config::ConfigBuilder;
-use crate::config::ServersConfig;
+use crate::config::{ConfigChange, ServersConfig, TlsConfig};
 use crate::grpc_handler::outer::{MerkleVerseServer};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::Parser;
 use futures::{FutureExt, TryFutureExt};
 use tonic::codegen::Body;
 
 use args::{Args, Commands, ServerArgs};
+use tokio::signal::unix::{signal, SignalKind};
 use tonic::transport::Server;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
 use tonic_reflection::pb::FILE_DESCRIPTOR_SET;
 use tower_http::trace::TraceLayer;
 use tower::{BoxError, Service, ServiceExt, steer::Steer};
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::registry::Data;
 use tracing_subscriber::util::SubscriberInitExt;
-use crate::args::GenPeerArgs;
+use crate::args::{GenPeerArgs, MonitorArgs};
 use crate::metaconfig::MetaConfig;
+use notify::{RecursiveMode, Watcher};
+
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(200); // wait for editors to finish writing
+const CLOSE_GRACE: Duration = Duration::from_secs(2); // time open requests get once the server drained
+
+/// replaces the tracing filter of the running process
+type LogFilterHandle = Arc<dyn Fn(EnvFilter) -> Result<()> + Send + Sync>;
 
 mod args;
 mod bridge;
+mod client;
 mod config;
 mod grpc_handler;
+mod keystore;
 mod metaconfig;
+mod monitor;
 mod server;
 mod utils;
+mod validate;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    initialize_tracing()?;
+    let log_filter = initialize_tracing()?;
     let args = Args::parse();
     match args.command {
-        Commands::Server(s) => srv(s).await?,
-        Commands::GenPeers(g) => gen_configs(g)?
+        Commands::Server(s) => srv(s, log_filter).await?,
+        Commands::GenPeers(g) => gen_configs(g)?,
+        Commands::ValidateConfig(v) => validate::validate_config(v)?,
+        Commands::Monitor(m) => monitor(m).await?,
+        Commands::Put(p) => client::put(p).await?,
+        Commands::Delete(d) => client::delete(d).await?,
+        Commands::Get(g) => client::get(g).await?,
+        Commands::History(h) => client::history(h).await?,
+        Commands::Root(r) => client::root(r).await?,
+        Commands::Info(i) => client::info(i).await?,
+        Commands::Status(s) => client::status(s).await?,
+        Commands::Bench(b) => client::bench(b).await?,
+        Commands::Join(j) => client::join(j).await?,
+        Commands::Leave(l) => client::leave(l).await?,
+        Commands::Keygen(k) => keystore::keygen(k)?,
+        Commands::Pubkey(p) => keystore::pubkey(p)?,
+        Commands::Rotate(r) => keystore::rotate(r)?,
     }
     Ok(())
 }
 
-async fn srv(args: ServerArgs) -> Result<()> {
-    let cfig = ServersConfig::with_path(args.config.as_path())?;
-    let server = server::MerkleVerseServer::from_cluster_config(cfig).await?;
-    let conn = server.connection_string.clone();
+async fn srv(args: ServerArgs, log_filter: LogFilterHandle) -> Result<()> {
+    let cfig = validate::check_file(&args.config)?;
+    if let (Some(level), Err(_)) = (&cfig.server.log_level, std::env::var("RUST_LOG")) {
+        log_filter(EnvFilter::try_new(level)?)?;
+    }
+    let server = server::MerkleVerseServer::from_cluster_config(cfig.clone()).await?;
+    // peers reach the server at its connection string, which need not be an address of this host
+    let listen: SocketAddr = cfig.server.outer_addr.parse()?;
+    let drain_timeout = Duration::from_millis(cfig.server.shutdown.drain_timeout_ms);
+
+    let server_key = server.clone();
+    let config_path = args.config.clone();
+    tokio::spawn(async move {
+        if let Err(e) = watch_key_rotation(server_key, config_path).await {
+            tracing::error!("Stopped watching for key rotations: {}", e);
+        }
+    });
+
+    let server_reload = server.clone();
+    tokio::spawn(async move {
+        if let Err(e) = watch_config(server_reload, args.config, cfig, log_filter).await {
+            tracing::error!("Stopped watching the config: {}", e);
+        }
+    });
 
     let server_cl = server.clone();
     let routine_loop = tokio::spawn(async move {
@@ -55,43 +106,249 @@ async fn srv(args: ServerArgs) -> Result<()> {
         }
     });
 
-    tracing::info!("Server starting at {}", conn);
+    // the signals are caught before serving, so that none of them kills the node mid-epoch
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let server_drain = server.clone();
+    let mut drain = tokio::spawn(async move {
+        let name = tokio::select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = interrupt.recv() => "SIGINT",
+        };
+        tracing::info!("Received {}, shutting down", name);
+        server_drain.drain(drain_timeout).await;
+        let _ = stop_tx.send(());
+        tokio::time::sleep(CLOSE_GRACE).await;
+    });
+
+    let (health, health_service) = tonic_health::server::health_reporter();
+    tokio::spawn(report_health(server.clone(), health));
+
+    tracing::info!(
+        "Server starting at {}, reached by peers at {}",
+        listen,
+        server.connection_string
+    );
 
-    let _grpc_service = Server::builder()
+    let mut builder = Server::builder();
+    if let Some(tls) = server.tls_server_config() {
+        tracing::info!("Serving over TLS, peer RPCs require a certificate naming the peer");
+        builder = builder.tls_config(tls)?;
+    }
+    let grpc_service = builder
         .trace_fn(|_| tracing::info_span!("MerkleVerse Server"))
+        .add_service(health_service)
         .add_service(MerkleVerseServer::new(server))
-        .serve(conn.parse()?)
-        .await?;
+        .serve_with_shutdown(listen, stop_rx.map(drop));
 
-    tokio::try_join!(routine_loop)?;
+    // streams such as root subscriptions stay open until their client leaves, they are given
+    // the close grace once the server drained
+    tokio::select! {
+        res = grpc_service => res?,
+        _ = &mut drain => tracing::warn!("Closing the connections still open after the drain"),
+    }
+    routine_loop.abort();
+    tracing::info!("Server stopped");
     Ok(())
 }
 
+/// Reports the server, and its outer service, as serving to the gRPC health service while the
+/// server is ready.
+async fn report_health(server: server::MerkleVerseServer, mut health: HealthReporter) {
+    type Outer = MerkleVerseServer<server::MerkleVerseServer>;
+    let mut ready = server.ready_receiver();
+    loop {
+        if *ready.borrow_and_update() {
+            health.set_service_status("", ServingStatus::Serving).await;
+            health.set_serving::<Outer>().await;
+        } else {
+            health.set_service_status("", ServingStatus::NotServing).await;
+            health.set_not_serving::<Outer>().await;
+        }
+        if ready.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Announces the key found in `config` every time SIGUSR1 is received, e.g. after `rotate`.
+async fn watch_key_rotation(server: server::MerkleVerseServer, config: PathBuf) -> Result<()> {
+    let mut signals = signal(SignalKind::user_defined1())?;
+    while signals.recv().await.is_some() {
+        let res = async {
+            let key = ServersConfig::with_path(&config)?.server.load_private_key()?;
+            server.rotate_key(key).await
+        }
+        .await;
+        match res {
+            Ok(epoch) => tracing::info!("Announced a new key, active from epoch {}", epoch),
+            Err(e) => tracing::error!("Failed to rotate the key: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Reloads `path` when it changes on disk or SIGHUP is received, and applies the settings that
+/// may change live. Reloads touching anything else are rejected as a whole.
+async fn watch_config(
+    server: server::MerkleVerseServer,
+    path: PathBuf,
+    mut current: ServersConfig,
+    log_filter: LogFilterHandle,
+) -> Result<()> {
+    let (tx, mut changes) = tokio::sync::mpsc::channel(16);
+    let file_name = path.file_name().map(|name| name.to_os_string());
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res {
+            let ours = event.paths.iter().any(|p| p.file_name() == file_name.as_deref());
+            if ours && (event.kind.is_create() || event.kind.is_modify()) {
+                let _ = tx.try_send(());
+            }
+        }
+    })?;
+    // editors often replace the file, so watch its directory rather than the file itself
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    let mut hangups = signal(SignalKind::hangup())?;
+
+    loop {
+        tokio::select! {
+            Some(_) = changes.recv() => {}
+            Some(_) = hangups.recv() => {}
+            else => return Ok(()),
+        }
+        tokio::time::sleep(RELOAD_DEBOUNCE).await;
+        while changes.try_recv().is_ok() {}
+        match reload_config(&server, &path, &current, &log_filter) {
+            Ok(Some(config)) => current = config,
+            Ok(None) => {}
+            Err(e) => tracing::error!("Rejected the reloaded config {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Validates the config at `path` and applies its changes over `current`, returns the new config
+/// if any changed. The key fields written by `rotate` are left to the key announcement, they are
+/// taken over without being applied.
+fn reload_config(
+    server: &server::MerkleVerseServer,
+    path: &Path,
+    current: &ServersConfig,
+    log_filter: &LogFilterHandle,
+) -> Result<Option<ServersConfig>> {
+    let config = validate::check_file(path)?;
+    let (rotated, changes): (Vec<ConfigChange>, Vec<ConfigChange>) = current
+        .diff(&config)?
+        .into_iter()
+        .partition(ConfigChange::is_rotation);
+    for change in &rotated {
+        tracing::info!("Key rotation changed {}, send SIGUSR1 to announce it", change.field);
+    }
+    if changes.is_empty() {
+        return Ok((!rotated.is_empty()).then_some(config));
+    }
+    let rejected: Vec<&ConfigChange> = changes.iter().filter(|c| !c.is_live()).collect();
+    if !rejected.is_empty() {
+        let diff: Vec<String> = rejected.iter().map(|c| format!("  {}", c)).collect();
+        return Err(anyhow!(
+            "nothing was applied, these changes need a restart or a membership change:\n{}",
+            diff.join("\n")
+        ));
+    }
+    if current.server.log_level != config.server.log_level {
+        log_filter(match &config.server.log_level {
+            Some(level) => EnvFilter::try_new(level)?,
+            None => EnvFilter::from_default_env(),
+        })?;
+    }
+    server.apply_config(&config);
+    for change in &changes {
+        tracing::info!("Applied config change {}", change);
+    }
+    Ok(Some(config))
+}
+
+async fn monitor(args: MonitorArgs) -> Result<()> {
+    let cfig = ServersConfig::with_path(args.config.as_path())?;
+    monitor::run(cfig, args.servers, args.from_epoch, args.min_signers, &args.tls).await
+}
+
 fn gen_configs(args: GenPeerArgs) -> Result<()> {
     let config = MetaConfig::with_path(args.src)?;
-    let srvs = config.to_serv_configs()?;
-    match args.to {
-        None => {
-            for srv in srvs {
-                println!("{}\n", srv.to_string())
-            }
+    let mut srvs = config.generate(args.manifest)?;
+    let path = args.to;
+    let passphrase = match args.encrypt {
+        true => Some(keystore::passphrase(true)?),
+        false => None,
+    };
+    // files are referenced where the manifest mounts them once deployed, or else by their name,
+    // which resolves next to the config
+    let deployed = |file: &str| match args.manifest {
+        Some(_) => PathBuf::from(config.deployed_path(file)),
+        None => PathBuf::from(file),
+    };
+    let authority = match args.tls {
+        true => Some(server::Authority::new()?),
+        false => None,
+    };
+    if let Some(authority) = &authority {
+        let ca = authority.pem()?;
+        fs::write(path.join("ca.pem"), ca.cert)?;
+        keystore::write_secret(&path.join("ca.key"), ca.key.as_bytes())?;
+        let client = authority.issue_client()?;
+        fs::write(path.join("client.pem"), client.cert)?;
+        keystore::write_secret(&path.join("client.key"), client.key.as_bytes())?;
+        eprintln!(
+            "Wrote the CA to {}, keep ca.key out of deployments",
+            path.join("ca.pem").display()
+        );
+    }
+    for srv in srvs.iter_mut() {
+        let srv = &mut srv.config;
+        let id = srv.server.server_config.id.clone();
+        if let Some(authority) = &authority {
+            let issued =
+                authority.issue_server(&id, &srv.server.server_config.connection_string)?;
+            let (cert_file, key_file) = (format!("{}.pem", id), format!("{}.key", id));
+            fs::write(path.join(&cert_file), issued.cert)?;
+            keystore::write_secret(&path.join(&key_file), issued.key.as_bytes())?;
+            srv.server.tls = Some(TlsConfig {
+                ca: deployed("ca.pem"),
+                cert: deployed(&cert_file),
+                key: deployed(&key_file),
+            });
         }
-        Some(path) => {
-            for srv in srvs{
-                fs::write(path.join(format!("{}.toml", srv.server.server_config.id)), srv.to_string())?;
-            }
+        if let Some(passphrase) = &passphrase {
+            let key = srv.server.load_private_key()?;
+            let keystore_file = format!("{}.keystore", id);
+            let keystore_path = path.join(&keystore_file);
+            keystore::Keystore::encrypt(&key, passphrase)?.write(&keystore_path)?;
+            srv.server.private_key = None;
+            srv.server.keystore = Some(deployed(&keystore_file));
+        }
+        srv.to_path(path.join(format!("{}.toml", id)))?;
+    }
+    if let Some(kind) = args.manifest {
+        for (file, content) in config.manifest(kind, &srvs) {
+            fs::write(path.join(&file), content)?;
+            eprintln!("Wrote {}", path.join(&file).display());
         }
     }
     Ok(())
 }
 
-fn initialize_tracing() -> Result<()>{
-    tracing_subscriber::fmt::fmt()
+fn initialize_tracing() -> Result<LogFilterHandle>{
+    let builder = tracing_subscriber::fmt::fmt()
         .with_line_number(true)
         .with_file(true)
         .with_thread_ids(true)
         .with_env_filter(EnvFilter::from_default_env())
-        .finish()
-        .init();
-    Ok(())
+        .with_filter_reloading();
+    let handle = builder.reload_handle();
+    builder.finish().init();
+    Ok(Arc::new(move |filter| Ok(handle.reload(filter)?)))
 }
\ No newline at end of file
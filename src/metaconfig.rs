@@ -1,29 +1,208 @@
+use crate::args::ManifestKind;
 use crate::config::{LocalServerConfig, ServerConfig, ServersConfig};
 use crate::server::{PrivateKey};
-use crate::utils::b64;
-use anyhow::{Context, Result};
+use crate::utils::{b64, binary_to_bytes};
+use anyhow::{anyhow, Result};
 use bls_signatures::Serialize as _;
 use config::{Config, ConfigError, Environment, File};
-use rand::{RngCore};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::path::Path;
-use tracing_log::LogTracer;
 
-const INNER_BEGIN: i32 = 6000;
-const OUTER_BEGIN: i32 = 8000;
+const INNER_BEGIN: u16 = 6000;
+const OUTER_BEGIN: u16 = 8000;
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_ADDRESS: &str = "{host}:{port}";
+const COMPOSE_ADDRESS: &str = "{id}:{port}"; // containers reach each other by service name
+const DEFAULT_EPOCH_INTERVAL: u32 = 1000;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct PeerGroupConfig {
+    name: Option<String>, // servers are named `<name>_<i>`, and other groups refer to it as parent
+    parent: Option<String>, // the group this group's prefix hangs off
     prefix: Option<String>,
     prefix_length: Option<u32>,
     length: u32,
     count: u32,
-    epoch_interval: u32,
+    epoch_interval: Option<u32>,
+    #[serde(default)]
+    hosts: Vec<String>, // servers are spread over the hosts round-robin
+    address: Option<String>, // connection string template, see `render`
+    outer_port: Option<u16>, // first outer port used on each host
+    inner_port: Option<u16>, // first inner port used on each host
+}
+
+/// A complete tree of clusters: `depth` levels where every cluster above the last level splits
+/// the key space of its prefix into `fan_out` child clusters of `replicas` servers each.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TreeShape {
+    depth: u32,
+    fan_out: u32, // a power of two
+    replicas: u32,
+    key_length: Option<u32>, // length of the leaf clusters, by default one more level of fan-out
+    #[serde(default)]
+    epoch_intervals: Vec<u32>, // per level, from the root
+}
+
+impl TreeShape {
+    /// The peer groups of the tree, root first. The groups are named `root` and `p<bits>` after
+    /// their prefix, so that the prefixes of a level partition the keys of the level above.
+    fn groups(&self) -> Result<Vec<PeerGroupConfig>> {
+        if self.fan_out < 2 || !self.fan_out.is_power_of_two() {
+            return Err(anyhow!("fan_out must be a power of two, got {}", self.fan_out));
+        }
+        if self.depth == 0 || self.replicas == 0 {
+            return Err(anyhow!("depth and replicas must be positive"));
+        }
+        let bits = self.fan_out.trailing_zeros();
+        let leaf_prefix = (self.depth - 1) * bits;
+        let key_length = self.key_length.unwrap_or(self.depth * bits);
+        if key_length <= leaf_prefix {
+            return Err(anyhow!(
+                "key_length must exceed the {} bits of the leaf prefixes",
+                leaf_prefix
+            ));
+        }
+
+        let mut groups = vec![];
+        let mut level: Vec<String> = vec![String::new()];
+        for depth in 0..self.depth {
+            let length = match depth + 1 == self.depth {
+                true => key_length,
+                false => (depth + 1) * bits,
+            };
+            let mut next = vec![];
+            for prefix in &level {
+                let name = |prefix: &str| match prefix.is_empty() {
+                    true => "root".to_string(),
+                    false => format!("p{}", prefix),
+                };
+                let parent = prefix
+                    .len()
+                    .checked_sub(bits as usize)
+                    .map(|len| name(&prefix[..len]));
+                groups.push(PeerGroupConfig {
+                    name: Some(name(prefix)),
+                    parent,
+                    prefix: match prefix.is_empty() {
+                        true => None,
+                        false => Some(b64(&binary_to_bytes(prefix)?)),
+                    },
+                    prefix_length: Some(prefix.len() as u32).filter(|len| *len > 0),
+                    length,
+                    count: self.replicas,
+                    epoch_interval: self.epoch_intervals.get(depth as usize).copied(),
+                    ..Default::default()
+                });
+                for child in 0..self.fan_out {
+                    next.push(format!("{}{:0width$b}", prefix, child, width = bits as usize));
+                }
+            }
+            level = next;
+        }
+        Ok(groups)
+    }
+}
+
+/// which servers are listed as peers of each other
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Peering {
+    #[default]
+    All, // every server knows every other server
+    Tree, // servers know their own group, their parent group and their child groups
+}
+
+/// where generated manifests expect the binary, its image and the configs on the target hosts
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DeployConfig {
+    image: String,
+    binary: String,
+    config_dir: String,
+    inner_image: String, // image of the inner provider every server runs beside it
+    inner_binary: String, // binary of the inner provider, which listens on $PORT
+}
+
+impl Default for DeployConfig {
+    fn default() -> Self {
+        Self {
+            image: "merkleverse:latest".into(),
+            binary: "/usr/local/bin/MerkleVerseWrapper".into(),
+            config_dir: "/etc/merkleverse".into(),
+            inner_image: "merklesquare:latest".into(),
+            inner_binary: "/usr/local/bin/mverserver".into(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct MetaConfig {
-    peer_groups: Vec<PeerGroupConfig>,
+    #[serde(default)]
+    peer_groups: Vec<PeerGroupConfig>, // generated after the groups of `tree`, if any
+    tree: Option<TreeShape>,
+    #[serde(default)]
+    peering: Peering,
+    #[serde(default)]
+    hosts: Vec<String>, // default hosts of the groups
+    address: Option<String>, // default connection string template of the groups
+    epoch_interval: Option<u32>, // default epoch interval of the groups
+    #[serde(default)]
+    deploy: DeployConfig,
+}
+
+/// A server generated from a peer group, with where it runs.
+pub struct GeneratedServer {
+    pub config: ServersConfig,
+    pub host: String,
+    pub depends_on: Vec<String>, // ids of the servers of the parent group
+}
+
+/// replaces the `{name}` placeholders of `template` by their value in `vars`
+fn render(template: &str, vars: &[(&str, String)]) -> Result<String> {
+    let mut res = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        res.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or(anyhow!("Unclosed placeholder in template {:?}", template))?;
+        let name = &rest[start + 1..start + end];
+        let (_, value) = vars.iter().find(|(var, _)| *var == name).ok_or(anyhow!(
+            "Unknown placeholder {{{}}} in template {:?}, expected one of {}",
+            name,
+            template,
+            vars.iter()
+                .map(|(var, _)| format!("{{{}}}", var))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))?;
+        res.push_str(value);
+        rest = &rest[start + end + 1..];
+    }
+    res.push_str(rest);
+    Ok(res)
+}
+
+/// whether the host of the connection string `addr` is this machine
+fn is_loopback(addr: &str) -> bool {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// the address a server on `host` listens on, every interface unless `host` is an address
+fn listen_addr(host: &str, port: u16, compose: bool) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(ip) if !compose => std::net::SocketAddr::new(ip, port).to_string(),
+        _ => format!("0.0.0.0:{}", port),
+    }
+}
+
+/// quotes `value` for YAML, JSON strings being valid YAML scalars
+fn quote(value: &str) -> String {
+    serde_json::to_string(value).unwrap()
 }
 
 impl MetaConfig {
@@ -36,63 +215,316 @@ impl MetaConfig {
         s.try_deserialize()
     }
 
-    pub fn to_serv_configs(&self) -> Result<Vec<ServersConfig>> {
-        let mut serv_configs = Vec::new();
+    /// the groups of the tree shape followed by the explicit groups
+    fn groups(&self) -> Result<Vec<PeerGroupConfig>> {
+        let mut groups = match &self.tree {
+            Some(tree) => tree.groups()?,
+            None => vec![],
+        };
+        groups.extend(self.peer_groups.iter().cloned());
+        Ok(groups)
+    }
+
+    /// checks that every group hangs off an earlier group it extends, returns the parent of each
+    fn parents(groups: &[PeerGroupConfig]) -> Result<Vec<Option<usize>>> {
+        let mut names: HashMap<&str, usize> = HashMap::new();
+        let mut parents = vec![];
+        for (i, group) in groups.iter().enumerate() {
+            let label = group.name.clone().unwrap_or(format!("#{}", i));
+            let parent = match &group.parent {
+                None => None,
+                Some(parent) => {
+                    let p = *names.get(parent.as_str()).ok_or(anyhow!(
+                        "Group {} has parent {}, which is not a group defined before it",
+                        label,
+                        parent
+                    ))?;
+                    let (pg, g) = (&groups[p], group);
+                    let bin = |group: &PeerGroupConfig| {
+                        ServerConfig {
+                            prefix: group.prefix.clone(),
+                            prefix_length: group.prefix_length,
+                            ..Default::default()
+                        }
+                        .prefix_bin()
+                    };
+                    if g.prefix_length != Some(pg.length) || !bin(g)?.starts_with(&bin(pg)?) {
+                        return Err(anyhow!(
+                            "Group {} must have a prefix of length {} extending the prefix of its parent {}",
+                            label,
+                            pg.length,
+                            parent
+                        ));
+                    }
+                    Some(p)
+                }
+            };
+            if let Some(name) = &group.name {
+                if names.insert(name, i).is_some() {
+                    return Err(anyhow!("Group name {} is used twice", name));
+                }
+            }
+            parents.push(parent);
+        }
+        Ok(parents)
+    }
+
+    /// Generates the config of every server of every group, to be deployed with `manifest`.
+    /// Servers sharing a host get consecutive ports from the port bases of their group, groups
+    /// whose port ranges overlap on a host are rejected.
+    pub fn generate(&self, manifest: Option<ManifestKind>) -> Result<Vec<GeneratedServer>> {
+        let groups = self.groups()?;
+        let parents = Self::parents(&groups)?;
+        let compose = matches!(manifest, Some(ManifestKind::DockerCompose));
+        let mut servers: Vec<(usize, String, LocalServerConfig)> = Vec::new();
+        let mut next_ports: HashMap<(String, u16), u16> = HashMap::new();
+        let mut used_ports: HashSet<(String, u16)> = HashSet::new();
         let mut s_cnt = 0;
-        for peer_group in &self.peer_groups {
-            for _ in 0..peer_group.count {
-                let port = OUTER_BEGIN + s_cnt;
-                let conn_st = format!("127.0.0.1:{}", port);
-                let mut rand_buf = [0u8; 32];
-                rand::thread_rng().fill_bytes(&mut rand_buf);
-                let priv_key = PrivateKey::from(rand_buf);
+        for (g, peer_group) in groups.iter().enumerate() {
+            let hosts = match (&peer_group.hosts, &self.hosts) {
+                (hosts, _) if !hosts.is_empty() => hosts.clone(),
+                (_, hosts) if !hosts.is_empty() => hosts.clone(),
+                _ => vec![DEFAULT_HOST.to_string()],
+            };
+            let template = peer_group
+                .address
+                .as_deref()
+                .or(self.address.as_deref())
+                .unwrap_or(if compose { COMPOSE_ADDRESS } else { DEFAULT_ADDRESS });
+            for i in 0..peer_group.count {
+                let host = hosts[i as usize % hosts.len()].clone();
+                let mut port = |base: u16| -> Result<u16> {
+                    let next = next_ports.entry((host.clone(), base)).or_insert(base);
+                    let port = *next;
+                    *next = next.checked_add(1).ok_or(anyhow!("Ran out of ports on {}", host))?;
+                    if !used_ports.insert((host.clone(), port)) {
+                        return Err(anyhow!(
+                            "Port {} on {} is assigned twice, the port ranges of the groups overlap",
+                            port,
+                            host
+                        ));
+                    }
+                    Ok(port)
+                };
+                let outer_port = port(peer_group.outer_port.unwrap_or(OUTER_BEGIN))?;
+                let inner_port = port(peer_group.inner_port.unwrap_or(INNER_BEGIN))?;
+                let id = match &peer_group.name {
+                    Some(name) => format!("{}_{}", name, i),
+                    None => format!("srv_{}", s_cnt),
+                };
+                let conn_st = render(
+                    template,
+                    &[
+                        ("id", id.clone()),
+                        ("group", peer_group.name.clone().unwrap_or(g.to_string())),
+                        ("index", i.to_string()),
+                        ("host", host.clone()),
+                        ("port", outer_port.to_string()),
+                    ],
+                )?;
+                if compose && is_loopback(&conn_st) {
+                    return Err(anyhow!(
+                        "Server {} would be reached at {}, which is the container itself, \
+                         use an address such as \"{}\"",
+                        id,
+                        conn_st,
+                        COMPOSE_ADDRESS
+                    ));
+                }
+                let priv_key = PrivateKey::generate();
                 let pub_key = priv_key.public_key();
                 let serv_config = ServerConfig {
                     prefix: peer_group.prefix.clone(),
                     prefix_length: peer_group.prefix_length,
                     length: peer_group.length,
-                    id: format!("srv_{}", s_cnt),
+                    id,
                     connection_string: conn_st.clone(),
                     bls_pub_key: b64(&pub_key.bls.as_bytes()),
                     dalek_pub_key: b64(pub_key.dalek.as_bytes()),
                 };
                 let local_srv_config = LocalServerConfig {
                     server_config: serv_config,
-                    epoch_interval: peer_group.epoch_interval,
-                    private_key: b64(priv_key.as_bytes()),
-                    inner_port: (INNER_BEGIN + s_cnt) as u16,
-                    outer_port: (OUTER_BEGIN + s_cnt) as u16,
-                    outer_addr: conn_st,
+                    epoch_interval: peer_group
+                        .epoch_interval
+                        .or(self.epoch_interval)
+                        .unwrap_or(DEFAULT_EPOCH_INTERVAL),
+                    private_key: Some(b64(priv_key.as_bytes())),
+                    keystore: None,
+                    log_level: None,
+                    connections: Default::default(),
+                    tls: None,
+                    admission: Default::default(),
+                    pool: Default::default(),
+                    shutdown: Default::default(),
+                    data_dir: None,
+                    base_dir: Default::default(),
+                    inner_port,
+                    outer_port,
+                    outer_addr: listen_addr(&host, outer_port, compose),
                 };
-                serv_configs.push(local_srv_config);
+                servers.push((g, host, local_srv_config));
                 s_cnt += 1;
             }
         }
+
+        let related = |a: usize, b: usize| match self.peering {
+            Peering::All => true,
+            Peering::Tree => a == b || parents[a] == Some(b) || parents[b] == Some(a),
+        };
         let mut final_conf = Vec::new();
-        for i in 0..serv_configs.len() {
+        for (i, (g, host, server)) in servers.iter().enumerate() {
             let mut peers = Vec::new();
-            for j in 0..serv_configs.len() {
-                if j == i {
+            let mut depends_on = Vec::new();
+            for (j, (other_g, _, other)) in servers.iter().enumerate() {
+                if parents[*g] == Some(*other_g) {
+                    depends_on.push(other.server_config.id.clone());
+                }
+                if j == i || !related(*g, *other_g) {
                     continue;
                 }
-                peers.push(serv_configs[j].server_config.clone());
+                peers.push(other.server_config.clone());
             }
-            let cur_conf = ServersConfig {
-                server: serv_configs[i].clone(),
-                peers: Some(peers),
-            };
-            final_conf.push(cur_conf);
+            final_conf.push(GeneratedServer {
+                config: ServersConfig {
+                    server: server.clone(),
+                    peers: Some(peers),
+                },
+                host: host.clone(),
+                depends_on,
+            });
         }
         Ok(final_conf)
     }
+
+    /// where a server finds `file` once deployed
+    pub fn deployed_path(&self, file: &str) -> String {
+        format!("{}/{}", self.deploy.config_dir.trim_end_matches('/'), file)
+    }
+
+    /// Renders the deployment manifest of `servers` as (file name, content) pairs, next to which
+    /// the configs written by `gen-peers` are expected.
+    pub fn manifest(
+        &self,
+        kind: ManifestKind,
+        servers: &[GeneratedServer],
+    ) -> Vec<(String, String)> {
+        match kind {
+            ManifestKind::DockerCompose => vec![("docker-compose.yml".into(), self.compose(servers))],
+            ManifestKind::Systemd => self.systemd_units(servers),
+        }
+    }
+
+    fn compose(&self, servers: &[GeneratedServer]) -> String {
+        let mut res = String::from(
+            "# generated by gen-peers, servers reach each other by service name\nservices:\n",
+        );
+        for srv in servers {
+            let local = &srv.config.server;
+            let id = &local.server_config.id;
+            let config_file = format!("{}.toml", id);
+            res += &format!("  {}:\n", id);
+            res += &format!("    image: {}\n", quote(&self.deploy.image));
+            res += &format!("    hostname: {}\n", quote(id));
+            res += &format!(
+                "    command: [\"server\", \"--config\", {}]\n",
+                quote(&self.deployed_path(&config_file))
+            );
+            res += "    volumes:\n";
+            let mut files = vec![config_file];
+            if local.keystore.is_some() {
+                files.push(format!("{}.keystore", id));
+            }
+            if local.tls.is_some() {
+                files.extend(["ca.pem".into(), format!("{}.pem", id), format!("{}.key", id)]);
+            }
+            for file in files {
+                let mount = format!("./{}:{}:ro", file, self.deployed_path(&file));
+                res += &format!("      - {}\n", quote(&mount));
+            }
+            res += "    expose:\n";
+            res += &format!("      - {}\n", quote(&local.outer_port.to_string()));
+            if !srv.depends_on.is_empty() {
+                res += "    depends_on:\n";
+                for dep in &srv.depends_on {
+                    res += &format!("      - {}\n", quote(dep));
+                }
+            }
+            // the inner provider shares the network of its server, which reaches it on loopback
+            res += &format!("  {}_inner:\n", id);
+            res += &format!("    image: {}\n", quote(&self.deploy.inner_image));
+            res += &format!("    network_mode: {}\n", quote(&format!("service:{}", id)));
+            res += "    environment:\n";
+            res += &format!("      PORT: {}\n", quote(&local.inner_port.to_string()));
+        }
+        res
+    }
+
+    fn systemd_units(&self, servers: &[GeneratedServer]) -> Vec<(String, String)> {
+        let unit_name = |id: &str| format!("merkleverse-{}.service", id);
+        let inner_name = |id: &str| format!("merkleverse-inner-{}.service", id);
+        let mut units = vec![];
+        let mut list = String::from("# host unit config\n");
+        for srv in servers {
+            let id = &srv.config.server.server_config.id;
+            let config_file = self.deployed_path(&format!("{}.toml", id));
+            list += &format!("{} {} {}\n", srv.host, unit_name(id), config_file);
+            list += &format!("{} {} -\n", srv.host, inner_name(id));
+            let inner = format!(
+                "# generated by gen-peers, install on {host}\n\
+                 [Unit]\n\
+                 Description=Inner provider of MerkleVerse server {id}\n\
+                 Wants=network-online.target\n\
+                 After=network-online.target\n\
+                 \n\
+                 [Service]\n\
+                 Environment=PORT={port}\n\
+                 ExecStart={binary}\n\
+                 Restart=on-failure\n\
+                 \n\
+                 [Install]\n\
+                 WantedBy=multi-user.target\n",
+                host = srv.host,
+                id = id,
+                port = srv.config.server.inner_port,
+                binary = self.deploy.inner_binary,
+            );
+            units.push((inner_name(id), inner));
+            let unit = format!(
+                "# generated by gen-peers, install on {host}\n\
+                 [Unit]\n\
+                 Description=MerkleVerse server {id}\n\
+                 Wants=network-online.target {inner}\n\
+                 After=network-online.target {inner}\n\
+                 \n\
+                 [Service]\n\
+                 EnvironmentFile=-{env}\n\
+                 ExecStart={binary} server --config {config}\n\
+                 ExecReload=/bin/kill -HUP $MAINPID\n\
+                 Restart=on-failure\n\
+                 \n\
+                 [Install]\n\
+                 WantedBy=multi-user.target\n",
+                host = srv.host,
+                id = id,
+                env = self.deployed_path(&format!("{}.env", id)),
+                inner = inner_name(id),
+                binary = self.deploy.binary,
+                config = config_file,
+            );
+            units.push((unit_name(id), unit));
+        }
+        units.push(("units.txt".into(), list));
+        units
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::server::MerkleVerseServer;
-    use anyhow::{anyhow, Result};
+    use anyhow::{anyhow, Context, Result};
     use std::env;
+    use tracing_log::LogTracer;
 
     #[tokio::test]
     async fn test_config() -> Result<()> {
@@ -105,8 +537,158 @@ mod tests {
 
         let config = MetaConfig::with_path(path)?;
         println!("Number of Clusters: {:#?}", config.peer_groups.len());
-        let target_srv = config.to_serv_configs()?;
+        let target_srv = config.generate(None)?;
         println!("Target Servers length: {:#?}", target_srv.len());
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn tst_hierarchy() -> Result<()> {
+        let config: MetaConfig = toml::from_str(
+            r#"
+            peering = "tree"
+            hosts = ["10.0.0.1", "10.0.0.2"]
+            epoch_interval = 3000
+
+            [[peer_groups]]
+            name = "root"
+            length = 2
+            count = 2
+
+            [[peer_groups]]
+            name = "edge"
+            parent = "root"
+            prefix = "AQ=="
+            prefix_length = 2
+            length = 6
+            count = 3
+            epoch_interval = 500
+            hosts = ["edge.internal"]
+            address = "{id}.{host}:{port}"
+            outer_port = 9000
+            "#,
+        )?;
+        let servers = config.generate(None)?;
+        let ids: Vec<&str> = servers
+            .iter()
+            .map(|s| s.config.server.server_config.id.as_str())
+            .collect();
+        assert_eq!(ids, ["root_0", "root_1", "edge_0", "edge_1", "edge_2"]);
+
+        let root = &servers[1].config.server;
+        assert_eq!(servers[1].host, "10.0.0.2");
+        assert_eq!(root.server_config.connection_string, "10.0.0.2:8000");
+        assert_eq!(root.outer_addr, "10.0.0.2:8000");
+        assert_eq!(root.epoch_interval, 3000);
+        let edge = &servers[4].config.server;
+        assert_eq!(edge.server_config.connection_string, "edge_2.edge.internal:9002");
+        assert_eq!(edge.outer_addr, "0.0.0.0:9002");
+        assert_eq!((edge.inner_port, edge.epoch_interval), (6002, 500));
+        assert_eq!(servers[4].depends_on, ["root_0", "root_1"]);
+        assert_eq!(servers[4].config.peers.as_ref().unwrap().len(), 4);
+
+        let compose = config.manifest(ManifestKind::DockerCompose, &servers);
+        assert!(compose[0].1.contains("- \"9002\""));
+        assert!(compose[0].1.contains("  edge_2_inner:\n"));
+        assert!(compose[0].1.contains("network_mode: \"service:edge_2\"\n"));
+        assert!(compose[0].1.contains("PORT: \"6002\"\n"));
+        let composed = config.generate(Some(ManifestKind::DockerCompose))?;
+        assert_eq!(composed[0].config.server.server_config.connection_string, "root_0:8000");
+        assert_eq!(composed[0].config.server.outer_addr, "0.0.0.0:8000");
+        let local: MetaConfig =
+            toml::from_str("address = \"{host}:{port}\"\n[[peer_groups]]\nlength = 2\ncount = 2")?;
+        assert!(local.generate(Some(ManifestKind::DockerCompose)).is_err());
+        let units = config.manifest(ManifestKind::Systemd, &servers);
+        assert_eq!(units.len(), 2 * servers.len() + 1);
+        assert!(units[1].1.contains("After=network-online.target merkleverse-inner-root_0"));
+
+        let orphan: MetaConfig = toml::from_str(
+            r#"
+            [[peer_groups]]
+            parent = "root"
+            prefix = "AQ=="
+            prefix_length = 2
+            length = 6
+            count = 1
+            "#,
+        )?;
+        assert!(orphan.generate(None).is_err());
+
+        let overlapping: MetaConfig = toml::from_str(
+            r#"
+            [[peer_groups]]
+            length = 2
+            count = 2
+
+            [[peer_groups]]
+            length = 2
+            count = 1
+            outer_port = 8001
+            "#,
+        )?;
+        assert!(overlapping.generate(None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tst_tree_partition() -> Result<()> {
+        let config: MetaConfig = toml::from_str(
+            r#"
+            [tree]
+            depth = 2
+            fan_out = 4
+            replicas = 3
+            key_length = 6
+            epoch_intervals = [3000, 1000]
+            "#,
+        )?;
+        let groups = config.groups()?;
+        let shape: Vec<_> = groups
+            .iter()
+            .map(|g| {
+                (
+                    g.prefix.as_deref(),
+                    g.prefix_length,
+                    g.length,
+                    g.parent.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            shape,
+            [
+                (None, None, 2, None),
+                (Some("AA=="), Some(2), 6, Some("root")),
+                (Some("AQ=="), Some(2), 6, Some("root")),
+                (Some("Ag=="), Some(2), 6, Some("root")),
+                (Some("Aw=="), Some(2), 6, Some("root")),
+            ]
+        );
+        let servers = config.generate(None)?;
+        assert_eq!(servers.len(), 15);
+        assert_eq!(servers[3].config.server.server_config.id, "p00_0");
+        assert_eq!(servers[3].config.server.epoch_interval, 1000);
+
+        // every key falls under exactly one leaf cluster
+        let deep: MetaConfig = toml::from_str("tree = { depth = 3, fan_out = 2, replicas = 1 }")?;
+        let groups = deep.groups()?;
+        let leaves: Vec<String> = groups
+            .iter()
+            .filter(|g| g.length == 3)
+            .map(|g| {
+                ServerConfig {
+                    prefix: g.prefix.clone(),
+                    prefix_length: g.prefix_length,
+                    ..Default::default()
+                }
+                .prefix_bin()
+            })
+            .collect::<Result<_>>()?;
+        assert_eq!(leaves, ["00", "01", "10", "11"]);
+        assert_eq!(groups.len(), 7);
+
+        let odd: MetaConfig = toml::from_str("tree = { depth = 2, fan_out = 3, replicas = 1 }")?;
+        assert!(odd.generate(None).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,580 @@
+use crate::config::ServersConfig;
+use crate::grpc_handler::outer::mverseouter::{
+    Empty, KeyAnnouncement, MembershipRecord, RootChainRequest, RootUpdate, WatchRootsRequest,
+};
+use crate::grpc_handler::outer::MerkleVerseClient;
+use crate::server::{
+    member_key, quorum, record_digest, verify_announcement, verify_multisig, verify_root_chain,
+    PublicKey,
+};
+use crate::args::TlsArgs;
+use crate::server::client_endpoint;
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use tonic::transport::{Channel, Endpoint};
+
+const MONITOR_RETENTION: usize = 1024; // number of epochs remembered per cluster for fork detection
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Alert {
+    MissingCertificate {
+        server: String,
+        epoch: u64,
+        signers: usize,
+    },
+    InvalidCertificate {
+        server: String,
+        epoch: u64,
+        reason: String,
+    },
+    BrokenChain {
+        server: String,
+        from_epoch: u64,
+        to_epoch: u64,
+        reason: String,
+    },
+    Fork {
+        cluster: String,
+        epoch: u64,
+        servers: (String, String),
+    },
+}
+
+impl Display for Alert {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Alert::MissingCertificate {
+                server,
+                epoch,
+                signers,
+            } => write!(
+                f,
+                "{} published epoch {} with {} signers, fewer than required",
+                server, epoch, signers
+            ),
+            Alert::InvalidCertificate {
+                server,
+                epoch,
+                reason,
+            } => write!(
+                f,
+                "{} published an invalid certificate for epoch {}: {}",
+                server, epoch, reason
+            ),
+            Alert::BrokenChain {
+                server,
+                from_epoch,
+                to_epoch,
+                reason,
+            } => write!(
+                f,
+                "{} failed to prove the root chain of epoch {} extends epoch {}: {}",
+                server, to_epoch, from_epoch, reason
+            ),
+            Alert::Fork {
+                cluster,
+                epoch,
+                servers,
+            } => write!(
+                f,
+                "Fork in cluster {} at epoch {}: {} and {} certified different roots",
+                cluster, epoch, servers.0, servers.1
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Member {
+    cluster: String,
+    public_key: PublicKey,
+    rotations: BTreeMap<u64, PublicKey>, // keys announced since, by activation epoch
+    since: u64,                           // first epoch the server signs for its cluster
+    until: Option<u64>,                   // epoch from which on the server left its cluster
+}
+
+impl Member {
+    fn new(cluster: String, public_key: PublicKey, since: u64) -> Self {
+        Self {
+            cluster,
+            public_key,
+            rotations: BTreeMap::new(),
+            since,
+            until: None,
+        }
+    }
+
+    fn active_at(&self, epoch: u64) -> bool {
+        self.since <= epoch && self.until.is_none_or(|until| epoch < until)
+    }
+
+    fn key_at(&self, epoch: u64) -> &PublicKey {
+        self.rotations
+            .range(..=epoch)
+            .next_back()
+            .map(|(_, key)| key)
+            .unwrap_or(&self.public_key)
+    }
+
+    fn rotate(&mut self, ann: &KeyAnnouncement) -> Result<bool> {
+        if self.rotations.contains_key(&ann.activation_epoch) {
+            return Ok(false);
+        }
+        let previous = match self.rotations.iter().next_back() {
+            Some((epoch, _)) if *epoch > ann.activation_epoch => {
+                return Err(anyhow!("Announcement precedes a known rotation"))
+            }
+            Some((_, key)) => key,
+            None => &self.public_key,
+        };
+        let key = verify_announcement(ann, previous)?;
+        self.rotations.insert(ann.activation_epoch, key);
+        Ok(true)
+    }
+}
+
+/// Checks the certified roots published by the followed servers against the keys of a cluster
+/// configuration, and remembers them to detect forks between servers of the same cluster.
+#[derive(Debug)]
+pub struct Monitor {
+    members: HashMap<String, Member>,
+    min_signers: Option<usize>, // a quorum of the members of the cluster if not set
+    seen: HashMap<String, BTreeMap<u64, (String, Vec<u8>)>>, // cluster -> epoch -> (server, root)
+    versions: HashMap<String, u64>, // cluster -> latest verified membership version
+}
+
+impl Monitor {
+    pub fn from_config(config: &ServersConfig, min_signers: Option<usize>) -> Result<Self> {
+        let mut members = HashMap::new();
+        let servers =
+            std::iter::once(&config.server.server_config).chain(config.peers.iter().flatten());
+        for srv in servers {
+            members.insert(
+                srv.id.clone(),
+                Member::new(srv.cluster_id()?, srv.public_key()?, 0),
+            );
+        }
+        Ok(Self {
+            members,
+            min_signers,
+            seen: HashMap::new(),
+            versions: HashMap::new(),
+        })
+    }
+
+    /// the number of signers a certificate of `cluster` for `epoch` needs
+    fn required_signers(&self, cluster: &str, epoch: u64) -> usize {
+        match self.min_signers {
+            Some(min_signers) => min_signers.max(1),
+            None => quorum(
+                self.members
+                    .values()
+                    .filter(|m| m.cluster == cluster && m.active_at(epoch))
+                    .count(),
+            ),
+        }
+    }
+
+    pub fn cluster_of(&self, server: &str) -> Result<String> {
+        self.members
+            .get(server)
+            .map(|m| m.cluster.clone())
+            .ok_or(anyhow!(
+                "Server {} is not part of the configuration",
+                server
+            ))
+    }
+
+    /// Records the key rotations announced by the servers of the configuration, ignoring the
+    /// announcements that do not verify. Returns the number of new rotations.
+    pub fn add_announcements(&mut self, mut announcements: Vec<KeyAnnouncement>) -> usize {
+        announcements.sort_by_key(|ann| ann.activation_epoch);
+        let mut added = 0;
+        for ann in announcements {
+            let Some(member) = self.members.get_mut(&ann.server_id) else {
+                continue;
+            };
+            match member.rotate(&ann) {
+                Ok(true) => added += 1,
+                Ok(false) => {}
+                Err(e) => tracing::warn!(
+                    "Ignoring key announcement of {} for epoch {}: {}",
+                    ann.server_id,
+                    ann.activation_epoch,
+                    e
+                ),
+            }
+        }
+        added
+    }
+
+    /// the keys `signers` used at `epoch`, provided they were all members of `cluster` then
+    fn signer_keys(
+        &self,
+        cluster: &str,
+        epoch: u64,
+        signers: &[String],
+    ) -> Result<Vec<bls_signatures::PublicKey>, String> {
+        let unique: HashSet<&String> = signers.iter().collect();
+        if unique.len() != signers.len() {
+            return Err("a signer is listed more than once".into());
+        }
+        signers
+            .iter()
+            .map(|signer| match self.members.get(signer) {
+                Some(member) if member.cluster == cluster && member.active_at(epoch) => {
+                    Ok(member.key_at(epoch).bls)
+                }
+                _ => Err(format!(
+                    "{} is not a member of cluster {} at epoch {}",
+                    signer, cluster, epoch
+                )),
+            })
+            .collect()
+    }
+
+    /// Verifies the membership records of the cluster of `server` in order, each signed by the
+    /// members before it, and applies the joins and leaves they record. Returns the number of
+    /// records applied.
+    pub fn add_membership(&mut self, server: &str, mut records: Vec<MembershipRecord>) -> usize {
+        let Ok(cluster) = self.cluster_of(server) else {
+            return 0;
+        };
+        records.sort_by_key(|r| r.version);
+        let mut applied = 0;
+        for record in records {
+            let known = self.versions.get(&cluster).copied().unwrap_or_default();
+            if record.version <= known {
+                continue;
+            }
+            if let Err(e) = self.apply_record(&cluster, known, &record) {
+                tracing::warn!(
+                    "Ignoring membership version {} of cluster {}: {}",
+                    record.version,
+                    cluster,
+                    e
+                );
+                break;
+            }
+            applied += 1;
+        }
+        applied
+    }
+
+    fn apply_record(&mut self, cluster: &str, known: u64, record: &MembershipRecord) -> Result<()> {
+        if record.version != known + 1 || record.epoch == 0 {
+            return Err(anyhow!("membership version {} is missing", known + 1));
+        }
+        let required = self.required_signers(cluster, record.epoch - 1);
+        let cert = record
+            .certificate
+            .as_ref()
+            .filter(|c| c.signers.len() >= required)
+            .ok_or(anyhow!("not enough signers"))?;
+        let keys = self
+            .signer_keys(cluster, record.epoch - 1, &cert.signers)
+            .map_err(|e| anyhow!(e))?;
+        let digest = record_digest(record.version, record.epoch, &record.members);
+        if !verify_multisig(cert, &digest, &keys) {
+            return Err(anyhow!("certificate signatures do not verify"));
+        }
+
+        for member in self.members.values_mut() {
+            if member.cluster == cluster && member.active_at(record.epoch) {
+                member.until = Some(record.epoch);
+            }
+        }
+        for info in &record.members {
+            match self.members.get_mut(&info.server_id) {
+                Some(member) if member.cluster == cluster && member.until == Some(record.epoch) => {
+                    member.until = None
+                }
+                _ => {
+                    self.members.insert(
+                        info.server_id.clone(),
+                        Member::new(cluster.into(), member_key(info)?, record.epoch),
+                    );
+                }
+            }
+        }
+        self.versions.insert(cluster.into(), record.version);
+        Ok(())
+    }
+
+    /// verifies the certificate of `update`, published by `server`
+    pub fn check_certificate(&self, server: &str, update: &RootUpdate) -> Result<(), Alert> {
+        let epoch = update.epoch.as_ref().map(|e| e.epoch).unwrap_or_default();
+        let invalid = |reason: String| Alert::InvalidCertificate {
+            server: server.into(),
+            epoch,
+            reason,
+        };
+        let cluster = self
+            .cluster_of(server)
+            .map_err(|e| invalid(e.to_string()))?;
+        let cert = match &update.certificate {
+            Some(cert) if cert.signers.len() >= self.required_signers(&cluster, epoch) => cert,
+            cert => {
+                return Err(Alert::MissingCertificate {
+                    server: server.into(),
+                    epoch,
+                    signers: cert.as_ref().map(|c| c.signers.len()).unwrap_or_default(),
+                })
+            }
+        };
+        let keys = self
+            .signer_keys(&cluster, epoch, &cert.signers)
+            .map_err(invalid)?;
+        if !verify_multisig(cert, &update.chain, &keys) {
+            return Err(invalid("certificate signatures do not verify".into()));
+        }
+        Ok(())
+    }
+
+    /// records a certified root, reporting a fork if another server of the same cluster
+    /// certified a different root for the same epoch
+    pub fn observe(&mut self, server: &str, epoch: u64, root: &[u8]) -> Result<(), Alert> {
+        let cluster = self.cluster_of(server).unwrap_or_default();
+        let seen = self.seen.entry(cluster.clone()).or_default();
+        match seen.get(&epoch) {
+            Some((other, known)) if known.as_slice() != root => Err(Alert::Fork {
+                cluster,
+                epoch,
+                servers: (other.clone(), server.into()),
+            }),
+            Some(_) => Ok(()),
+            None => {
+                seen.insert(epoch, (server.into(), root.to_vec()));
+                while seen.len() > MONITOR_RETENTION {
+                    seen.pop_first();
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn raise(alert: Alert) {
+    tracing::error!("ALERT: {}", alert);
+}
+
+/// fetches the key rotations and membership changes known to `server`
+async fn refresh(
+    monitor: &Mutex<Monitor>,
+    client: &mut MerkleVerseClient<Channel>,
+    server: &str,
+) -> Result<()> {
+    let announcements = client.get_key_announcements(Empty {}).await?.into_inner();
+    let membership = client.get_membership(Empty {}).await?.into_inner();
+    let mut monitor = monitor.lock().unwrap();
+    monitor.add_announcements(announcements.announcements);
+    monitor.add_membership(server, membership.records);
+    Ok(())
+}
+
+/// Follows the committed roots of the server at `addr`, checking every certificate and that the
+/// root chain of each certified epoch extends that of the previous one.
+async fn follow(monitor: Arc<Mutex<Monitor>>, endpoint: Endpoint, from_epoch: Option<u64>) -> Result<()> {
+    let addr = endpoint.uri().to_string();
+    let mut client = MerkleVerseClient::connect(endpoint).await?;
+    let server = client
+        .get_server_information(Empty {})
+        .await?
+        .into_inner()
+        .server_id;
+    monitor.lock().unwrap().cluster_of(&server)?;
+    tracing::info!("Following server {} at {}", server, addr);
+    refresh(&monitor, &mut client, &server).await?;
+
+    let mut stream = client
+        .watch_roots(WatchRootsRequest { from_epoch })
+        .await?
+        .into_inner();
+    let mut last_certified: Option<(u64, Vec<u8>)> = None;
+    while let Some(update) = stream.message().await? {
+        let epoch = update
+            .epoch
+            .as_ref()
+            .ok_or(anyhow!("Server {} sent a root without an epoch", server))?
+            .epoch;
+        tracing::info!("Server {} committed epoch {}", server, epoch);
+
+        let invalid = matches!(
+            monitor.lock().unwrap().check_certificate(&server, &update),
+            Err(Alert::InvalidCertificate { .. })
+        );
+        if invalid {
+            // the certificate may be signed with a key or by a member that is new since the last fetch
+            refresh(&monitor, &mut client, &server).await?;
+        }
+        let certified = {
+            let mut monitor = monitor.lock().unwrap();
+            match monitor.check_certificate(&server, &update) {
+                Ok(_) => {
+                    if let Err(alert) = monitor.observe(&server, epoch, &update.head) {
+                        raise(alert);
+                    }
+                    true
+                }
+                Err(alert) => {
+                    raise(alert);
+                    false
+                }
+            }
+        };
+        if !certified {
+            continue;
+        }
+
+        if let Some((from_epoch, from_chain)) = &last_certified {
+            let chain = client
+                .get_root_chain(RootChainRequest {
+                    from_epoch: *from_epoch,
+                    to_epoch: epoch,
+                })
+                .await;
+            let res = match chain {
+                Ok(chain) => verify_root_chain(
+                    *from_epoch,
+                    from_chain,
+                    epoch,
+                    &update.chain,
+                    &chain.into_inner().roots,
+                ),
+                Err(e) => Err(anyhow!(e)),
+            };
+            if let Err(e) = res {
+                raise(Alert::BrokenChain {
+                    server: server.clone(),
+                    from_epoch: *from_epoch,
+                    to_epoch: epoch,
+                    reason: e.to_string(),
+                });
+            }
+        }
+        last_certified = Some((epoch, update.chain));
+    }
+    Err(anyhow!("Server {} closed the root stream", server))
+}
+
+pub async fn run(
+    config: ServersConfig,
+    servers: Vec<String>,
+    from_epoch: Option<u64>,
+    min_signers: Option<usize>,
+    tls: &TlsArgs,
+) -> Result<()> {
+    let monitor = Arc::new(Mutex::new(Monitor::from_config(&config, min_signers)?));
+    let endpoints = servers
+        .iter()
+        .map(|addr| client_endpoint(addr, tls))
+        .collect::<Result<Vec<_>>>()?;
+    let tasks = endpoints.into_iter().zip(servers).map(|(endpoint, addr)| {
+        let monitor = monitor.clone();
+        tokio::spawn(async move {
+            if let Err(e) = follow(monitor, endpoint, from_epoch).await {
+                tracing::error!("Stopped following {}: {}", addr, e);
+            }
+        })
+    });
+    futures::future::join_all(tasks).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc_handler::inner::mversegrpc::Epoch;
+    use crate::grpc_handler::outer::mverseouter::EpochCertificate;
+    use bls_signatures::{Serialize, Signature};
+    use crate::server::{announce, PrivateKey, ServerId};
+
+    fn monitor(keys: &[PrivateKey]) -> Monitor {
+        let members = keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| {
+                (
+                    format!("srv_{}", i),
+                    Member::new("/2".into(), k.public_key(), 0),
+                )
+            })
+            .collect();
+        Monitor {
+            members,
+            min_signers: None,
+            seen: HashMap::new(),
+            versions: HashMap::new(),
+        }
+    }
+
+    fn update(keys: &[PrivateKey], signers: &[usize], chain: &[u8]) -> RootUpdate {
+        let sigs: Vec<Signature> = signers.iter().map(|i| keys[*i].bls.sign(chain)).collect();
+        RootUpdate {
+            epoch: Some(Epoch { epoch: 3 }),
+            head: vec![],
+            chain: chain.to_vec(),
+            certificate: Some(EpochCertificate {
+                aggregate_signature: bls_signatures::aggregate(&sigs).unwrap().as_bytes(),
+                signers: signers.iter().map(|i| format!("srv_{}", i)).collect(),
+                signatures: sigs.iter().map(|sig| sig.as_bytes()).collect(),
+            }),
+        }
+    }
+
+    #[test]
+    fn tst_check_certificate() {
+        let keys: Vec<PrivateKey> = (1..=3u8).map(|i| PrivateKey::from([i; 32])).collect();
+        let monitor = monitor(&keys);
+        assert!(monitor
+            .check_certificate("srv_0", &update(&keys, &[0, 1], b"chain"))
+            .is_ok());
+        assert!(matches!(
+            monitor.check_certificate("srv_0", &update(&keys, &[0], b"chain")),
+            Err(Alert::MissingCertificate { .. })
+        ));
+        let mut forged = update(&keys, &[0, 1], b"chain");
+        forged.chain = b"other".to_vec();
+        assert!(matches!(
+            monitor.check_certificate("srv_0", &forged),
+            Err(Alert::InvalidCertificate { .. })
+        ));
+    }
+
+    #[test]
+    fn tst_rotated_certificate() {
+        let keys: Vec<PrivateKey> = (1..=3u8).map(|i| PrivateKey::from([i; 32])).collect();
+        let mut monitor = monitor(&keys[..2]);
+        let ann = announce(&ServerId("srv_1".into()), &keys[1], &keys[2], 3);
+        assert_eq!(monitor.add_announcements(vec![ann.clone(), ann]), 1);
+
+        let sign = |signers: [&PrivateKey; 2], epoch: u64| {
+            let mut update = update(&[signers[0].clone(), signers[1].clone()], &[0, 1], b"c");
+            update.epoch = Some(Epoch { epoch });
+            update
+        };
+        assert!(monitor
+            .check_certificate("srv_0", &sign([&keys[0], &keys[2]], 3))
+            .is_ok());
+        assert!(monitor
+            .check_certificate("srv_0", &sign([&keys[0], &keys[1]], 2))
+            .is_ok());
+        assert!(monitor
+            .check_certificate("srv_0", &sign([&keys[0], &keys[1]], 3))
+            .is_err());
+    }
+
+    #[test]
+    fn tst_fork() {
+        let keys: Vec<PrivateKey> = (1..=2u8).map(|i| PrivateKey::from([i; 32])).collect();
+        let mut monitor = monitor(&keys);
+        assert!(monitor.observe("srv_0", 1, b"a").is_ok());
+        assert!(monitor.observe("srv_1", 1, b"a").is_ok());
+        assert!(matches!(
+            monitor.observe("srv_1", 1, b"b"),
+            Err(Alert::Fork { .. })
+        ));
+    }
+}
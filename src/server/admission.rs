@@ -0,0 +1,235 @@
+use crate::config::AdmissionConfig;
+use crate::grpc_handler::outer::mverseouter::AdmissionState;
+use crate::server::pool::{shard_of, EpochCounts, SHARDS};
+use crate::server::transactions::{Transaction, TransactionPool};
+use crate::server::MerkleVerseServer;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::time::Instant;
+
+const MAX_IDLE_BUCKETS: usize = 4096; // full buckets kept before they are dropped
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rejection {
+    RateLimited = 0,
+    PoolFull = 1,
+    KeyLimit = 2,
+}
+
+/// Why a client or peer transaction was not admitted, reported as `RESOURCE_EXHAUSTED`.
+#[derive(Debug)]
+pub struct AdmissionError {
+    pub reason: Rejection,
+    message: String,
+}
+
+impl Display for AdmissionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for AdmissionError {}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Rate limits of clients and limits on the pending transactions of the pool. The buckets of
+/// clients are sharded, so that clients are only serialized with the clients of their shard.
+#[derive(Debug)]
+pub struct Admission {
+    config: AdmissionConfig,
+    buckets: Vec<Mutex<HashMap<String, TokenBucket>>>,
+    rejected: [AtomicU64; 3], // by rejection
+}
+
+impl Admission {
+    pub fn new(config: AdmissionConfig) -> Self {
+        Self {
+            config,
+            buckets: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            rejected: Default::default(),
+        }
+    }
+
+    /// refills the bucket of `client` and takes a token from it, if there is one
+    fn take_token(&self, client: &str, now: Instant) -> bool {
+        let (rate, burst) = (
+            self.config.client_rate as f64,
+            self.config.client_burst as f64,
+        );
+        let mut buckets = self.buckets[shard_of(client)].lock();
+        if buckets.len() >= MAX_IDLE_BUCKETS / SHARDS {
+            // buckets refilled to the burst are the same as new ones
+            buckets.retain(|_, bucket| {
+                bucket.tokens + (now - bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = buckets
+            .entry(client.to_string())
+            .or_insert(TokenBucket {
+                tokens: burst,
+                updated: now,
+            });
+        bucket.tokens = (bucket.tokens + (now - bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    fn reject(&self, reason: Rejection, message: String) -> AdmissionError {
+        self.rejected[reason as usize].fetch_add(1, Ordering::Relaxed);
+        AdmissionError { reason, message }
+    }
+
+    /// Admits `transaction` of `client` into the pool of `epoch`, `pool` being the shard of its
+    /// key and `counts` the pending transactions of all shards, and returns whether it reserved a
+    /// slot of the pool of `epoch` for it. Duplicates only cost a token, as the pool ignores them,
+    /// see `TransactionPool::duplicate_of`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn admit(
+        &self,
+        client: &str,
+        pool: &TransactionPool,
+        counts: &EpochCounts,
+        epoch: u64,
+        transaction: &Transaction,
+        idempotent: bool,
+        now: Instant,
+    ) -> Result<bool, AdmissionError> {
+        if !self.take_token(client, now) {
+            return Err(self.reject(
+                Rejection::RateLimited,
+                format!(
+                    "Client {} exceeds {} transactions per second",
+                    client, self.config.client_rate
+                ),
+            ));
+        }
+        if pool.duplicate_of(epoch, transaction, idempotent).is_some() {
+            return Ok(false);
+        }
+        let (key, _) = transaction.operation.to_raw();
+        if pool.pending_writes(epoch, &key) >= self.config.pending_per_key {
+            return Err(self.reject(
+                Rejection::KeyLimit,
+                format!(
+                    "The key already has {} writes pending for epoch {}",
+                    self.config.pending_per_key, epoch
+                ),
+            ));
+        }
+        self.reserve(counts, epoch)?;
+        Ok(true)
+    }
+
+    /// Reserves a slot of the pool of `epoch`, which peer transactions take without a token.
+    pub fn reserve(&self, counts: &EpochCounts, epoch: u64) -> Result<(), AdmissionError> {
+        if !counts.reserve(epoch, self.config.pool_capacity) {
+            return Err(self.reject(
+                Rejection::PoolFull,
+                format!(
+                    "The transaction pool of epoch {} is full ({} transactions)",
+                    epoch, self.config.pool_capacity
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn state(&self, pool_size: usize) -> AdmissionState {
+        let rejected = |reason: Rejection| self.rejected[reason as usize].load(Ordering::Relaxed);
+        AdmissionState {
+            pool_size: pool_size as u64,
+            pool_capacity: self.config.pool_capacity as u64,
+            pending_per_key: self.config.pending_per_key as u64,
+            client_rate: self.config.client_rate,
+            client_burst: self.config.client_burst,
+            tracked_clients: self.buckets.iter().map(|b| b.lock().len() as u64).sum(),
+            rejected_rate_limited: rejected(Rejection::RateLimited),
+            rejected_pool_full: rejected(Rejection::PoolFull),
+            rejected_key_limit: rejected(Rejection::KeyLimit),
+        }
+    }
+}
+
+impl MerkleVerseServer {
+    pub fn admission_state(&self) -> AdmissionState {
+        self.pool.admission_state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc_handler::inner::mversegrpc::transaction_request::TransactionType;
+    use crate::grpc_handler::outer::mverseouter::ClientTransactionRequest;
+    use crate::grpc_handler::outer::TransactionRequest;
+    use anyhow::Result;
+    use std::time::Duration;
+
+    fn request(key: u8, value: u8) -> ClientTransactionRequest {
+        ClientTransactionRequest {
+            transaction: Some(TransactionRequest {
+                key: vec![key],
+                value: Some(vec![value]),
+                transaction_type: TransactionType::Update.into(),
+            }),
+            auxiliary: None,
+            wait: false,
+            idempotency_id: None,
+        }
+    }
+
+    #[test]
+    fn tst_admission() -> Result<()> {
+        let admission = Admission::new(AdmissionConfig {
+            client_rate: 10,
+            client_burst: 2,
+            pool_capacity: 3,
+            pending_per_key: 2,
+        });
+        let mut pool = TransactionPool::new();
+        let counts = EpochCounts::default();
+        let now = Instant::now();
+        let mut submit = |admission: &Admission, client: &str, req, now| {
+            let transaction = Transaction::from_client(&req).unwrap();
+            admission.admit(client, &pool, &counts, 0, &transaction, false, now)?;
+            pool.insert_client(0, client, &req).unwrap();
+            Ok::<_, AdmissionError>(())
+        };
+
+        // the bucket of a client holds two tokens, refilled at ten per second
+        submit(&admission, "a", request(1, 1), now)?;
+        submit(&admission, "a", request(1, 2), now)?;
+        let err = submit(&admission, "a", request(2, 1), now).unwrap_err();
+        assert_eq!(err.reason, Rejection::RateLimited);
+        let later = now + Duration::from_millis(100);
+        let err = submit(&admission, "a", request(1, 3), later).unwrap_err();
+        assert_eq!(err.reason, Rejection::KeyLimit);
+
+        submit(&admission, "b", request(2, 1), now)?;
+        let err = submit(&admission, "b", request(3, 1), now).unwrap_err();
+        assert_eq!(err.reason, Rejection::PoolFull);
+
+        let state = admission.state(3);
+        assert_eq!(state.tracked_clients, 2);
+        assert_eq!(
+            (
+                state.rejected_rate_limited,
+                state.rejected_key_limit,
+                state.rejected_pool_full
+            ),
+            (1, 1, 1)
+        );
+        Ok(())
+    }
+}
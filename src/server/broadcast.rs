@@ -0,0 +1,149 @@
+use crate::config::ConnectionConfig;
+use crate::grpc_handler::outer::MerkleVerseClient;
+use crate::server::{MerkleVerseServer, PeerServer, ServerId};
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+use tonic::transport::Channel;
+use tonic::{Code, Status};
+
+/// status codes worth retrying, any other failure is final
+const RETRYABLE: [Code; 4] = [
+    Code::Unavailable,
+    Code::DeadlineExceeded,
+    Code::ResourceExhausted,
+    Code::Aborted,
+];
+
+/// How a broadcast to peers went.
+#[derive(Debug, Default)]
+pub struct BroadcastOutcome {
+    pub reached: Vec<ServerId>,
+    pub failed: Vec<(ServerId, String)>,
+}
+
+impl BroadcastOutcome {
+    /// whether this server and the peers reached form a majority of the servers broadcast to
+    /// and this one
+    pub fn has_quorum(&self) -> bool {
+        let size = self.reached.len() + self.failed.len() + 1;
+        (self.reached.len() + 1) * 2 > size
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "reached {} of {} peers",
+            self.reached.len(),
+            self.reached.len() + self.failed.len()
+        )
+    }
+}
+
+/// the delay before retry number `retry`, starting at 1, jittered by up to half of it
+fn retry_delay(config: &ConnectionConfig, retry: u32) -> Duration {
+    let base = config
+        .retry_backoff_ms
+        .saturating_mul(1 << retry.saturating_sub(1).min(16));
+    let jittered = rand::thread_rng().gen_range(base / 2..=base + base / 2);
+    Duration::from_millis(jittered)
+}
+
+impl MerkleVerseServer {
+    /// Sends the request made by `call` to each of `peers` concurrently. Every peer has the
+    /// broadcast deadline to answer, and transient failures are retried with jittered backoff
+    /// within it. Results are recorded in the peer states.
+    pub(super) async fn broadcast<F, Fut, T>(
+        &self,
+        method: &str,
+        peers: impl IntoIterator<Item = PeerServer>,
+        call: F,
+    ) -> BroadcastOutcome
+    where
+        F: Fn(MerkleVerseClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let call = &call;
+        let sends = peers.into_iter().map(|srv| async move {
+            let res = self.send_with_retries(&srv, call).await;
+            (srv.id, res)
+        });
+        let results = futures::future::join_all(sends).await;
+
+        let mut outcome = BroadcastOutcome::default();
+        let mut serv_state = self.state.lock();
+        for (id, res) in results {
+            serv_state.record_broadcast(&id, res.as_ref().err());
+            match res {
+                Ok(()) => outcome.reached.push(id),
+                Err(e) => {
+                    tracing::warn!("Failed to send {} to {}: {}", method, id.0, e);
+                    outcome.failed.push((id, e));
+                }
+            }
+        }
+        outcome
+    }
+
+    async fn send_with_retries<F, Fut, T>(&self, srv: &PeerServer, call: &F) -> Result<(), String>
+    where
+        F: Fn(MerkleVerseClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let config = self.connections.config();
+        let deadline = Instant::now() + Duration::from_millis(config.broadcast_deadline_ms);
+        // a peer that announced its shutdown gets a single attempt, until it answers again
+        let max_retries = match self.state.lock().is_leaving(&srv.id) {
+            true => 0,
+            false => config.broadcast_retries,
+        };
+        let mut retries = 0;
+        loop {
+            let error = match self.peer_client(srv) {
+                Err(e) => e.to_string(),
+                Ok(client) => match tokio::time::timeout_at(deadline, call(client)).await {
+                    Ok(Ok(_)) => return Ok(()),
+                    Ok(Err(status)) if !RETRYABLE.contains(&status.code()) => {
+                        return Err(status.message().to_string())
+                    }
+                    Ok(Err(status)) => status.message().to_string(),
+                    Err(_) => format!("no answer within {}ms", config.broadcast_deadline_ms),
+                },
+            };
+            retries += 1;
+            let delay = retry_delay(config, retries);
+            if retries > max_retries || Instant::now() + delay >= deadline {
+                return Err(format!("{} after {} attempt(s)", error, retries));
+            }
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tst_broadcast_policy() {
+        let config = ConnectionConfig {
+            retry_backoff_ms: 100,
+            ..Default::default()
+        };
+        for retry in 1..4 {
+            let base = 100 << (retry - 1);
+            let delay = retry_delay(&config, retry).as_millis() as u64;
+            assert!((base / 2..=base + base / 2).contains(&delay));
+        }
+
+        let id = |i: usize| ServerId(format!("s{}", i));
+        let outcome = |reached: usize, failed: usize| BroadcastOutcome {
+            reached: (0..reached).map(id).collect(),
+            failed: (0..failed).map(|i| (id(i), "down".into())).collect(),
+        };
+        assert!(outcome(0, 0).has_quorum());
+        assert!(outcome(1, 1).has_quorum());
+        assert!(!outcome(1, 2).has_quorum());
+        assert!(!outcome(0, 1).has_quorum());
+    }
+}
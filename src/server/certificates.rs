@@ -0,0 +1,227 @@
+use crate::server::history::{EpochHistory, EpochRecord, KeyHistory, KeyVersion};
+use crate::server::ServerId;
+use anyhow::{anyhow, Result};
+use arc_swap::ArcSwap;
+use bls_signatures::{aggregate, Signature};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::sync::watch::{channel, Receiver, Sender};
+
+/// the number of signers a certificate of a cluster of `members` servers needs, a majority
+pub fn quorum(members: usize) -> usize {
+    members / 2 + 1
+}
+
+/// The signatures over the chain digest of an epoch, and the root the chain ends with.
+#[derive(Debug, Clone)]
+pub struct MultiSig {
+    pub root: Vec<u8>,
+    pub chain: Vec<u8>,
+    pub aggregate: Signature,
+    pub signatures: HashMap<ServerId, Signature>,
+}
+
+/// What this server committed: the epoch it is filling, the latest root of the inner provider,
+/// and the retained roots, certificates and key versions. Each part is shared between views, and
+/// only copied when it changes.
+#[derive(Debug, Default, Clone)]
+pub struct CommittedView {
+    pub current_epoch: u64,
+    pub current_root: Vec<u8>,
+    pub history: Arc<EpochHistory>,
+    pub key_history: Arc<KeyHistory>,
+    multi_sigs: Arc<BTreeMap<u64, MultiSig>>,
+}
+
+impl CommittedView {
+    pub fn multi_sig(&self, epoch: u64) -> Option<&MultiSig> {
+        self.multi_sigs.get(&epoch)
+    }
+
+    /// the certificate of `record`, if its signatures are over the chain of the record
+    pub fn certificate_of(&self, record: &EpochRecord) -> Option<&MultiSig> {
+        self.multi_sig(record.epoch)
+            .filter(|multi_sig| multi_sig.root == record.root && multi_sig.chain == record.chain)
+    }
+
+    /// the number of servers that signed the certificate of `epoch`
+    pub fn signers(&self, epoch: u64) -> usize {
+        self.multi_sig(epoch).map_or(0, |m| m.signatures.len())
+    }
+}
+
+/// The committed view, read without locking. Writers are serialized and each publishes a new
+/// view, so readers always see a consistent one.
+#[derive(Debug)]
+pub struct CertificateStore {
+    view: ArcSwap<CommittedView>,
+    writer: Mutex<()>,
+    commits: (Sender<u64>, Receiver<u64>), // the epoch filled after each commit
+    changes: (Sender<u64>, Receiver<u64>), // the number of views published
+}
+
+impl Default for CertificateStore {
+    fn default() -> Self {
+        Self {
+            view: ArcSwap::from_pointee(CommittedView::default()),
+            writer: Mutex::new(()),
+            commits: channel(0),
+            changes: channel(0),
+        }
+    }
+}
+
+impl CertificateStore {
+    pub fn view(&self) -> Arc<CommittedView> {
+        self.view.load_full()
+    }
+
+    pub fn current_epoch(&self) -> u64 {
+        self.view.load().current_epoch
+    }
+
+    fn update<R>(&self, f: impl FnOnce(&mut CommittedView) -> R) -> R {
+        let _writer = self.writer.lock();
+        let mut view = CommittedView::clone(&self.view.load());
+        let res = f(&mut view);
+        self.view.store(Arc::new(view));
+        self.changes.0.send_modify(|n| *n += 1);
+        res
+    }
+
+    /// Resumes after `record`, the last epoch committed before a restart. Its chain digest is
+    /// the one the next epoch extends.
+    pub fn restore(&self, record: EpochRecord) {
+        let next = self.update(|view| {
+            view.current_epoch = record.epoch + 1;
+            view.current_root = record.root.clone();
+            Arc::make_mut(&mut view.history).insert(record);
+            view.current_epoch
+        });
+        self.commits.0.send_replace(next);
+    }
+
+    /// records the root the inner provider returned for the current epoch
+    pub fn set_root(&self, root: Vec<u8>) {
+        self.update(|view| view.current_root = root);
+    }
+
+    pub fn record_versions(&self, epoch: u64, versions: Vec<(Vec<u8>, KeyVersion)>) {
+        self.update(|view| Arc::make_mut(&mut view.key_history).record_epoch(epoch, versions));
+    }
+
+    /// notes that the key versions written in `epoch` are missing from the key history
+    pub fn skip_versions(&self, epoch: u64) {
+        self.update(|view| Arc::make_mut(&mut view.key_history).skip_epoch(epoch));
+    }
+
+    /// Adds the signature of `signer` to the certificate of `epoch`, unless it signed already. A
+    /// signature over another root or chain than the one the certificate collects is an error.
+    pub fn add_signature(
+        &self,
+        epoch: u64,
+        root: Vec<u8>,
+        chain: Vec<u8>,
+        signer: ServerId,
+        sig: Signature,
+    ) -> Result<()> {
+        self.update(|view| {
+            let multi_sigs = Arc::make_mut(&mut view.multi_sigs);
+            match multi_sigs.get_mut(&epoch) {
+                Some(multi_sig) => {
+                    if multi_sig.root != root || multi_sig.chain != chain {
+                        return Err(anyhow!(
+                            "Signature of server {} is over another chain than epoch {}",
+                            signer.0,
+                            epoch
+                        ));
+                    }
+                    if multi_sig.signatures.contains_key(&signer) {
+                        return Ok(());
+                    }
+                    multi_sig.signatures.insert(signer, sig);
+                    multi_sig.aggregate = aggregate([multi_sig.aggregate, sig].as_slice())?;
+                }
+                None => {
+                    let mut multi_sig = MultiSig {
+                        root,
+                        chain,
+                        aggregate: sig,
+                        signatures: HashMap::new(),
+                    };
+                    multi_sig.signatures.insert(signer, sig);
+                    multi_sigs.insert(epoch, multi_sig);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Commits the current root as the current epoch, forgets what is no longer retained, and
+    /// moves on to the next epoch. Returns the committed epoch.
+    pub fn commit(&self) -> u64 {
+        let (committed, next) = self.update(|view| {
+            let committed = view.current_epoch;
+            let history = Arc::make_mut(&mut view.history);
+            history.push(committed, view.current_root.clone());
+            if let Some(oldest) = history.oldest() {
+                Arc::make_mut(&mut view.key_history).purge_before(oldest);
+                if view.multi_sigs.keys().next().is_some_and(|e| *e < oldest) {
+                    Arc::make_mut(&mut view.multi_sigs).retain(|e, _| *e >= oldest);
+                }
+            }
+            view.current_epoch += 1;
+            (committed, view.current_epoch)
+        });
+        self.commits.0.send_replace(next);
+        committed
+    }
+
+    /// a receiver that is notified with the next epoch number every time an epoch is committed
+    pub fn commit_receiver(&self) -> Receiver<u64> {
+        self.commits.1.clone()
+    }
+
+    /// a receiver that is notified every time a new view is published, such as when a
+    /// certificate gains a signature
+    pub fn change_receiver(&self) -> Receiver<u64> {
+        self.changes.1.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::PrivateKey;
+
+    #[test]
+    fn tst_certificate_store() -> Result<()> {
+        let store = CertificateStore::default();
+        let before = store.view();
+        store.set_root(vec![1]);
+        let key = PrivateKey::generate();
+        let chain = store.view().history.next_chain(0, &[1]);
+        let sig = key.bls.sign(&chain);
+        store.add_signature(0, vec![1], chain.clone(), ServerId("s1".into()), sig)?;
+        store.add_signature(0, vec![1], chain, ServerId("s1".into()), sig)?;
+        assert!(store
+            .add_signature(0, vec![2], vec![2], ServerId("s2".into()), sig)
+            .is_err());
+        assert_eq!(store.commit(), 0);
+
+        // views taken earlier are not affected by later writes
+        assert_eq!(
+            (before.current_epoch, before.history.latest().is_none()),
+            (0, true)
+        );
+        let view = store.view();
+        assert_eq!(view.current_epoch, 1);
+        assert_eq!(view.history.latest().map(|r| r.root.clone()), Some(vec![1]));
+        assert_eq!(view.signers(0), 1);
+        assert!(view.certificate_of(view.history.latest().unwrap()).is_some());
+        assert_eq!((quorum(1), quorum(2), quorum(3), quorum(4)), (1, 2, 2, 3));
+        assert_eq!(*store.commit_receiver().borrow(), 1);
+        Ok(())
+    }
+}
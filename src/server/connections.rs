@@ -0,0 +1,241 @@
+use crate::config::ConnectionConfig;
+use crate::grpc_handler::inner::{mversegrpc, MerkleProviderClient};
+use crate::grpc_handler::outer::mverseouter::Empty;
+use crate::grpc_handler::outer::MerkleVerseClient;
+use crate::server::{MerkleVerseServer, PeerServer, Tls};
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use parking_lot::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Status;
+
+/// which service a channel talks to, and so how its health is probed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Peer,
+    Inner,
+}
+
+#[derive(Debug)]
+struct Connection {
+    backend: Backend,
+    peer: Option<String>, // the id of the peer, which its certificate must name
+    channel: Channel,
+    failures: u32,
+    retry_at: Option<Instant>, // calls fail fast until then
+}
+
+/// Lazily connected channels to the peers and the inner provider, shared by every clone of the
+/// server. Channels are probed periodically, and an unreachable backend is backed off
+/// exponentially instead of being dialed on every call.
+#[derive(Debug)]
+pub struct Connections {
+    config: ConnectionConfig,
+    tls: Option<Tls>, // used towards peers, the inner provider is local
+    channels: Mutex<HashMap<String, Connection>>,
+}
+
+impl Connections {
+    pub fn new(config: ConnectionConfig, tls: Option<Tls>) -> Self {
+        Self {
+            config,
+            tls,
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn tls(&self) -> Option<&Tls> {
+        self.tls.as_ref()
+    }
+
+    /// a channel to `url`, over TLS when the backend is the peer `peer` and TLS is configured
+    fn connect(&self, url: &str, peer: Option<&str>) -> Result<Channel> {
+        let ms = Duration::from_millis;
+        let endpoint = match (&self.tls, peer) {
+            (Some(tls), Some(id)) => {
+                Endpoint::from_shared(url.replacen("http://", "https://", 1))?
+                    .tls_config(tls.peer_config(id))?
+            }
+            _ => Endpoint::from_shared(url.to_string())?,
+        };
+        Ok(endpoint
+            .connect_timeout(ms(self.config.connect_timeout_ms))
+            .timeout(ms(self.config.request_timeout_ms))
+            .tcp_nodelay(true)
+            .http2_keep_alive_interval(ms(self.config.health_interval_ms))
+            .keep_alive_while_idle(true)
+            .connect_lazy())
+    }
+
+    /// the channel to `url`, unless the backend is backing off after failed health checks
+    fn channel(&self, url: &str, backend: Backend, peer: Option<&str>) -> Result<Channel> {
+        let mut channels = self.channels.lock();
+        if let Some(conn) = channels.get(url) {
+            return match conn.retry_at {
+                Some(retry_at) if retry_at > Instant::now() => Err(anyhow!(
+                    "{} is unreachable, retrying in {}ms",
+                    url,
+                    (retry_at - Instant::now()).as_millis()
+                )),
+                _ => Ok(conn.channel.clone()),
+            };
+        }
+        let channel = self.connect(url, peer)?;
+        channels.insert(
+            url.to_string(),
+            Connection {
+                backend,
+                peer: peer.map(String::from),
+                channel: channel.clone(),
+                failures: 0,
+                retry_at: None,
+            },
+        );
+        Ok(channel)
+    }
+
+    pub fn peer(&self, url: &str, id: &str) -> Result<MerkleVerseClient<Channel>> {
+        Ok(MerkleVerseClient::new(self.channel(
+            url,
+            Backend::Peer,
+            Some(id),
+        )?))
+    }
+
+    pub fn inner(&self, url: &str) -> Result<MerkleProviderClient<Channel>> {
+        Ok(MerkleProviderClient::new(
+            self.channel(url, Backend::Inner, None)?,
+        ))
+    }
+
+    /// the delay before retrying a backend that failed `failures` health checks in a row
+    fn backoff(&self, failures: u32) -> Duration {
+        let factor = 1u64 << failures.saturating_sub(1).min(16);
+        Duration::from_millis(
+            self.config
+                .backoff_initial_ms
+                .saturating_mul(factor)
+                .min(self.config.backoff_max_ms),
+        )
+    }
+
+    /// records the outcome of a health check of `url`
+    fn record(&self, url: &str, healthy: bool) {
+        let mut channels = self.channels.lock();
+        let Some(conn) = channels.get_mut(url) else {
+            return;
+        };
+        if healthy {
+            if conn.failures > 0 {
+                tracing::info!("{} is reachable again", url);
+            }
+            conn.failures = 0;
+            conn.retry_at = None;
+            return;
+        }
+        conn.failures += 1;
+        let delay = self.backoff(conn.failures);
+        tracing::warn!(
+            "{} failed {} health check(s), backing off for {}ms",
+            url,
+            conn.failures,
+            delay.as_millis()
+        );
+        conn.retry_at = Some(Instant::now() + delay);
+        // start over with a fresh channel rather than one stuck on a dead connection
+        if let Ok(channel) = self.connect(url, conn.peer.as_deref()) {
+            conn.channel = channel;
+        }
+    }
+
+    async fn probe(channel: Channel, backend: Backend) -> Result<(), Status> {
+        match backend {
+            Backend::Peer => {
+                MerkleVerseClient::new(channel)
+                    .get_server_information(Empty {})
+                    .await?;
+            }
+            Backend::Inner => {
+                MerkleProviderClient::new(channel)
+                    .get_current_root(mversegrpc::Empty {})
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Probes every channel in use once its backoff, if any, has expired.
+    pub async fn health_check(&self) {
+        let due: Vec<(String, Channel, Backend)> = {
+            let now = Instant::now();
+            self.channels
+                .lock()
+                .iter()
+                .filter(|(_, conn)| conn.retry_at.is_none_or(|at| at <= now))
+                .map(|(url, conn)| (url.clone(), conn.channel.clone(), conn.backend))
+                .collect()
+        };
+        let probes = due.into_iter().map(|(url, channel, backend)| async move {
+            let healthy = Self::probe(channel, backend).await.is_ok();
+            (url, healthy)
+        });
+        for (url, healthy) in futures::future::join_all(probes).await {
+            self.record(&url, healthy);
+        }
+    }
+
+    pub fn config(&self) -> &ConnectionConfig {
+        &self.config
+    }
+
+    pub fn health_interval(&self) -> Duration {
+        Duration::from_millis(self.config.health_interval_ms)
+    }
+}
+
+impl MerkleVerseServer {
+    /// a client of `srv` over its pooled channel
+    pub(super) fn peer_client(&self, srv: &PeerServer) -> Result<MerkleVerseClient<Channel>> {
+        self.connections.peer(&srv.connection_string, &srv.id.0)
+    }
+
+    pub async fn health_loop(&self) -> Result<()> {
+        // periodically checks the pooled channels, so that calls to unreachable backends fail fast
+        loop {
+            tokio::time::sleep(self.connections.health_interval()).await;
+            self.connections.health_check().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn tst_backoff() -> Result<()> {
+        let connections = Connections::new(
+            ConnectionConfig {
+                connect_timeout_ms: 100,
+                backoff_initial_ms: 100,
+                backoff_max_ms: 1000,
+                ..Default::default()
+            },
+            None,
+        );
+        assert_eq!(connections.backoff(1), Duration::from_millis(100));
+        assert_eq!(connections.backoff(3), Duration::from_millis(400));
+        assert_eq!(connections.backoff(40), Duration::from_millis(1000));
+
+        // nothing listens on port 1, the health check fails and calls are refused until retry
+        let url = "http://127.0.0.1:1";
+        connections.peer(url, "s1")?;
+        connections.health_check().await;
+        assert!(connections.peer(url, "s1").is_err());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(connections.peer(url, "s1").is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,293 @@
+use crate::grpc_handler::inner::mversegrpc::Epoch;
+use crate::grpc_handler::outer::mverseouter::{
+    EpochCertificate, Equivocation, EquivocationReport, GossipMessage, ObservedRoot, ServerIdentity,
+};
+use crate::server::certificates::quorum;
+use crate::server::validation::verify_multisig;
+use crate::server::{MerkleVerseServer, PeerServer, ServerId};
+use anyhow::{anyhow, Result};
+use rand::seq::SliceRandom;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+const GOSSIP_INTERVAL: u64 = 2000; // gossip round interval in milliseconds
+const GOSSIP_FANOUT: usize = 2; // number of servers contacted every gossip round
+const GOSSIP_RETENTION: usize = 64; // number of epochs remembered per cluster
+const GOSSIP_RECENT: usize = 8; // number of latest epochs per cluster sent every gossip round
+
+/// identifies a cluster by the binary prefix and length its servers share
+pub fn cluster_id(prefix_bin: &str, length: u32) -> String {
+    format!("{}/{}", prefix_bin, length)
+}
+
+fn observed_epoch(obs: &ObservedRoot) -> u64 {
+    obs.epoch.as_ref().map(|e| e.epoch).unwrap_or_default()
+}
+
+/// Certified roots observed from every known cluster, and the equivocations found among them.
+#[derive(Debug, Default)]
+pub struct ObservationStore {
+    observations: HashMap<String, BTreeMap<u64, ObservedRoot>>,
+    equivocations: Vec<Equivocation>,
+}
+
+impl ObservationStore {
+    /// Records a verified observation. If the same cluster certified a different root for the
+    /// same epoch before, the pair is stored and returned as a proof of misbehaviour.
+    pub fn observe(&mut self, obs: ObservedRoot) -> Option<Equivocation> {
+        let epoch = observed_epoch(&obs);
+        let cluster = self.observations.entry(obs.cluster.clone()).or_default();
+        match cluster.get(&epoch) {
+            Some(known) if known.head != obs.head => {
+                let reported = self.equivocations.iter().any(|e| {
+                    matches!((&e.first, &e.second), (Some(first), Some(second))
+                        if first.cluster == obs.cluster
+                            && observed_epoch(first) == epoch
+                            && first.head == known.head
+                            && second.head == obs.head)
+                });
+                if reported {
+                    return None;
+                }
+                let equivocation = Equivocation {
+                    first: Some(known.clone()),
+                    second: Some(obs),
+                };
+                self.equivocations.push(equivocation.clone());
+                Some(equivocation)
+            }
+            Some(_) => None,
+            None => {
+                cluster.insert(epoch, obs);
+                while cluster.len() > GOSSIP_RETENTION {
+                    cluster.pop_first();
+                }
+                None
+            }
+        }
+    }
+
+    /// the latest observations of every cluster
+    pub fn recent(&self) -> Vec<ObservedRoot> {
+        self.observations
+            .values()
+            .flat_map(|cluster| cluster.values().rev().take(GOSSIP_RECENT).cloned())
+            .collect()
+    }
+
+    pub fn equivocations(&self) -> &[Equivocation] {
+        &self.equivocations
+    }
+}
+
+impl MerkleVerseServer {
+    /// the BLS keys the servers in `cluster` signed `epoch` with, as far as this server knows them
+    fn cluster_keys(
+        &self,
+        cluster: &str,
+        epoch: u64,
+    ) -> Result<HashMap<ServerId, bls_signatures::PublicKey>> {
+        let mut members = vec![];
+        if cluster_id(&self.prefix.to_binstring()?, self.length) == cluster {
+            members.push(self.id.clone());
+        }
+        if let Some(peers) = &self.gossip_peers() {
+            for srv in peers.servers.values() {
+                if cluster_id(&srv.prefix.to_binstring()?, srv.length) == cluster {
+                    members.push(srv.id.clone());
+                }
+            }
+        }
+        Ok(members
+            .into_iter()
+            .filter_map(|id| Some((id.clone(), self.public_key_at(&id, epoch)?.bls)))
+            .collect())
+    }
+
+    /// checks that the certificate of an observation was signed by a quorum of the members of its
+    /// cluster
+    fn verify_observation(&self, obs: &ObservedRoot) -> Result<()> {
+        let cert = obs
+            .certificate
+            .as_ref()
+            .ok_or(anyhow!("Observation carries no certificate"))?;
+        let keys = self.cluster_keys(&obs.cluster, observed_epoch(obs))?;
+        let signers: HashSet<&String> = cert.signers.iter().collect();
+        if signers.len() != cert.signers.len() {
+            return Err(anyhow!("Certificate lists a signer more than once"));
+        }
+        if signers.len() < quorum(keys.len()) {
+            return Err(anyhow!(
+                "Certificate has {} signers, cluster {} needs {}",
+                signers.len(),
+                obs.cluster,
+                quorum(keys.len())
+            ));
+        }
+        let signer_keys = cert
+            .signers
+            .iter()
+            .map(|s| {
+                keys.get(&ServerId(s.clone())).copied().ok_or(anyhow!(
+                    "Server {} is not a known member of cluster {}",
+                    s,
+                    obs.cluster
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if !verify_multisig(cert, &obs.chain, &signer_keys) {
+            return Err(anyhow!("Certificate signature is invalid"));
+        }
+        Ok(())
+    }
+
+    /// records the roots this server has certified itself
+    fn observe_own(&self) -> Result<()> {
+        let cluster = cluster_id(&self.prefix.to_binstring()?, self.length);
+        let view = self.certificates.view();
+        let own: Vec<ObservedRoot> = view
+            .history
+            .since(0)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .take(GOSSIP_RECENT)
+            .filter_map(|record| {
+                let multi_sig = view.certificate_of(record)?;
+                Some(ObservedRoot {
+                    cluster: cluster.clone(),
+                    epoch: Some(Epoch {
+                        epoch: record.epoch,
+                    }),
+                    head: record.root.clone(),
+                    chain: record.chain.clone(),
+                    certificate: Some(EpochCertificate::from(multi_sig)),
+                })
+            })
+            .collect();
+        let mut serv_state = self.state.lock();
+        for obs in own {
+            self.record_observation(serv_state.observations_mut(), obs);
+        }
+        Ok(())
+    }
+
+    fn record_observation(&self, store: &mut ObservationStore, obs: ObservedRoot) {
+        if let Some(equivocation) = store.observe(obs) {
+            if let Some(second) = equivocation.second {
+                tracing::error!(
+                    "Equivocation detected: cluster {} certified conflicting roots for epoch {}",
+                    second.cluster,
+                    observed_epoch(&second)
+                );
+            }
+        }
+    }
+
+    /// Verifies and records the observations gossiped by another server, and answers with the
+    /// latest observations of this server.
+    pub fn receive_gossip(&self, observations: Vec<ObservedRoot>) -> Result<Vec<ObservedRoot>> {
+        self.observe_own()?;
+        let verified: Vec<ObservedRoot> = observations
+            .into_iter()
+            .filter(|obs| obs.epoch.is_some())
+            .filter(|obs| match self.verify_observation(obs) {
+                Ok(_) => true,
+                Err(e) => {
+                    tracing::debug!("Ignoring gossiped root of cluster {}: {}", obs.cluster, e);
+                    false
+                }
+            })
+            .collect();
+        let mut serv_state = self.state.lock();
+        for obs in verified {
+            self.record_observation(serv_state.observations_mut(), obs);
+        }
+        Ok(serv_state.observations().recent())
+    }
+
+    async fn gossip_round(&self) -> Result<()> {
+        let Some(peers) = &self.gossip_peers() else {
+            return Ok(());
+        };
+        let targets: Vec<PeerServer> = {
+            let servers: Vec<&PeerServer> = peers.servers.values().collect();
+            servers
+                .choose_multiple(&mut rand::thread_rng(), GOSSIP_FANOUT)
+                .map(|srv| (*srv).clone())
+                .collect()
+        };
+        let observations = self.receive_gossip(vec![])?;
+        for srv in targets {
+            let res = async {
+                let mut client = self.peer_client(&srv)?;
+                let res = client
+                    .gossip(GossipMessage {
+                        peer_identity: Some(ServerIdentity {
+                            server_id: self.id.0.clone(),
+                        }),
+                        observations: observations.clone(),
+                    })
+                    .await?
+                    .into_inner();
+                self.receive_gossip(res.observations)
+            }
+            .await;
+            if let Err(e) = res {
+                tracing::warn!("Failed to gossip with {}: {}", srv.id.0, e);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn gossip_loop(&self) -> Result<()> {
+        // periodically exchanges certified roots with a few random servers to detect equivocation.
+        // a failed round is retried on the next one, it must not stop the other routines
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_millis(GOSSIP_INTERVAL)).await;
+            if let Err(e) = self.gossip_round().await {
+                tracing::warn!("Gossip round failed: {}", e);
+            }
+        }
+    }
+
+    pub fn equivocations(&self) -> EquivocationReport {
+        EquivocationReport {
+            equivocations: self
+                .state
+                .lock()
+                .observations()
+                .equivocations()
+                .to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observation(epoch: u64, head: u8) -> ObservedRoot {
+        ObservedRoot {
+            cluster: "01/6".into(),
+            epoch: Some(Epoch { epoch }),
+            head: vec![head],
+            chain: vec![],
+            certificate: None,
+        }
+    }
+
+    #[test]
+    fn tst_equivocation() {
+        let mut store = ObservationStore::default();
+        assert!(store.observe(observation(1, 1)).is_none());
+        assert!(store.observe(observation(1, 1)).is_none());
+        assert!(store.observe(observation(2, 2)).is_none());
+        let mut rechained = observation(2, 2);
+        rechained.chain = vec![9];
+        assert!(store.observe(rechained).is_none());
+        assert!(store.observe(observation(1, 3)).is_some());
+        assert!(store.observe(observation(1, 3)).is_none());
+        assert_eq!(store.equivocations().len(), 1);
+        assert_eq!(store.recent().len(), 2);
+    }
+}
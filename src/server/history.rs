@@ -0,0 +1,263 @@
+use crate::grpc_handler::inner::mversegrpc::MerkleProof;
+use crate::server::root_chain::{chain_digest, GENESIS_CHAIN};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+const HISTORY_RETENTION: usize = 1024; // number of committed epochs kept in memory
+
+#[derive(Debug, Clone)]
+pub struct EpochRecord {
+    pub epoch: u64,
+    pub root: Vec<u8>,
+    pub chain: Vec<u8>,
+}
+
+/// Bounded record of the most recently committed epochs and their roots.
+#[derive(Debug, Clone)]
+pub struct EpochHistory {
+    records: BTreeMap<u64, EpochRecord>,
+    retention: usize,
+}
+
+impl Default for EpochHistory {
+    fn default() -> Self {
+        Self::new(HISTORY_RETENTION)
+    }
+}
+
+impl EpochHistory {
+    pub fn new(retention: usize) -> Self {
+        Self {
+            records: BTreeMap::new(),
+            retention,
+        }
+    }
+
+    /// The chain digest `epoch` would have if it was committed with `root`. It continues from
+    /// the latest record, restored from the data directory after a restart, and only links roots.
+    pub fn next_chain(&self, epoch: u64, root: &[u8]) -> Vec<u8> {
+        let previous = match self.latest() {
+            Some(record) => record.chain.as_slice(),
+            None => GENESIS_CHAIN.as_slice(),
+        };
+        chain_digest(previous, epoch, root)
+    }
+
+    pub fn push(&mut self, epoch: u64, root: Vec<u8>) {
+        let chain = self.next_chain(epoch, &root);
+        self.insert(EpochRecord { epoch, root, chain });
+    }
+
+    /// adds a record whose chain digest is already known, such as one restored after a restart
+    pub fn insert(&mut self, record: EpochRecord) {
+        self.records.insert(record.epoch, record);
+        while self.records.len() > self.retention {
+            self.records.pop_first();
+        }
+    }
+
+    pub fn get(&self, epoch: u64) -> Option<&EpochRecord> {
+        self.records.get(&epoch)
+    }
+
+    pub fn latest(&self) -> Option<&EpochRecord> {
+        self.records.values().next_back()
+    }
+
+    /// the latest retained epoch committed with `root`
+    pub fn epoch_of(&self, root: &[u8]) -> Option<u64> {
+        self.records
+            .values()
+            .rev()
+            .find(|record| record.root == root)
+            .map(|record| record.epoch)
+    }
+
+    /// the oldest epoch that is still retained
+    pub fn oldest(&self) -> Option<u64> {
+        self.records.keys().next().copied()
+    }
+
+    /// all retained records with an epoch number of at least `epoch`, in order
+    pub fn since(&self, epoch: u64) -> impl Iterator<Item = &EpochRecord> {
+        self.records.range(epoch..).map(|(_, r)| r)
+    }
+}
+
+/// A value a key held as of a committed epoch, together with its inclusion proof.
+#[derive(Debug, Clone)]
+pub struct KeyVersion {
+    pub epoch: u64,
+    pub value: Option<Vec<u8>>, // None if the key was deleted in this epoch
+    pub proof: Option<MerkleProof>,
+    pub head: Vec<u8>,
+}
+
+/// Per-key record of the values committed within the retained epochs, ordered by epoch. The
+/// versions of each key are shared between copies until they change.
+#[derive(Debug, Default, Clone)]
+pub struct KeyHistory {
+    versions: HashMap<Vec<u8>, Arc<Vec<KeyVersion>>>,
+    complete_since: Option<u64>, // no version committed in or after this epoch is missing
+    purged_before: u64,          // no version committed before this epoch is kept
+}
+
+impl KeyHistory {
+    /// Records the versions written by the commit of `epoch`. The history is complete from the
+    /// first epoch recorded after startup.
+    pub fn record_epoch(&mut self, epoch: u64, versions: Vec<(Vec<u8>, KeyVersion)>) {
+        self.complete_since.get_or_insert(epoch);
+        for (key, version) in versions {
+            self.record(key, version);
+        }
+    }
+
+    /// Notes that the versions written by the commit of `epoch` could not be recorded, so the
+    /// history is only complete from the next epoch on.
+    pub fn skip_epoch(&mut self, epoch: u64) {
+        self.complete_since = Some(epoch + 1);
+    }
+
+    /// whether every version of a key committed in `epoch` or later is retained
+    pub fn covers(&self, epoch: u64) -> bool {
+        self.complete_since.is_some_and(|since| since <= epoch) && self.purged_before <= epoch
+    }
+
+    /// whether `versions`, as returned by [`Self::last`] for `n`, are the actual last `n`
+    pub fn covers_last(&self, versions: &[KeyVersion], n: usize) -> bool {
+        match versions.first() {
+            Some(first) if versions.len() == n => {
+                self.complete_since.is_some_and(|since| since <= first.epoch)
+            }
+            _ => self.covers(0),
+        }
+    }
+
+    pub fn record(&mut self, key: Vec<u8>, version: KeyVersion) {
+        let versions = Arc::make_mut(self.versions.entry(key).or_default());
+        match versions.last_mut() {
+            Some(last) if last.epoch == version.epoch => *last = version,
+            _ => versions.push(version),
+        }
+    }
+
+    fn versions(&self, key: &[u8]) -> &[KeyVersion] {
+        self.versions
+            .get(key)
+            .map(|versions| versions.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn at(&self, key: &[u8], epoch: u64) -> Option<&KeyVersion> {
+        let versions = self.versions(key);
+        versions
+            .binary_search_by_key(&epoch, |v| v.epoch)
+            .ok()
+            .map(|i| &versions[i])
+    }
+
+    /// the last `n` versions of `key`
+    pub fn last(&self, key: &[u8], n: usize) -> &[KeyVersion] {
+        let versions = self.versions(key);
+        &versions[versions.len().saturating_sub(n)..]
+    }
+
+    /// all versions of `key` committed in `epoch` or later
+    pub fn since(&self, key: &[u8], epoch: u64) -> &[KeyVersion] {
+        let versions = self.versions(key);
+        &versions[versions.partition_point(|v| v.epoch < epoch)..]
+    }
+
+    /// up to `limit` versions of `key` committed after the epoch `after`, and whether more remain
+    pub fn page(&self, key: &[u8], after: Option<u64>, limit: usize) -> (&[KeyVersion], bool) {
+        let versions = match after {
+            Some(epoch) => self.since(key, epoch + 1),
+            None => self.versions(key),
+        };
+        if versions.len() > limit {
+            (&versions[..limit], true)
+        } else {
+            (versions, false)
+        }
+    }
+
+    /// Forgets every version committed before `epoch`, and the keys not written since, so that
+    /// the history is bounded by the retained epochs rather than by every key ever written.
+    pub fn purge_before(&mut self, epoch: u64) {
+        self.purged_before = self.purged_before.max(epoch);
+        self.versions.retain(|_, versions| {
+            let cut = versions.partition_point(|v| v.epoch < epoch);
+            if cut > 0 {
+                Arc::make_mut(versions).drain(..cut);
+            }
+            !versions.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tst_retention() {
+        let mut history = EpochHistory::new(3);
+        for epoch in 0..5 {
+            history.push(epoch, vec![epoch as u8]);
+        }
+        assert_eq!(history.oldest(), Some(2));
+        assert_eq!(history.epoch_of(&[3]), Some(3));
+        assert_eq!(history.epoch_of(&[1]), None);
+        let epochs: Vec<u64> = history.since(3).map(|r| r.epoch).collect();
+        assert_eq!(epochs, vec![3, 4]);
+    }
+
+    fn version(epoch: u64) -> KeyVersion {
+        KeyVersion {
+            epoch,
+            value: Some(vec![epoch as u8]),
+            proof: None,
+            head: vec![],
+        }
+    }
+
+    #[test]
+    fn tst_key_history() {
+        let key = vec![1u8];
+        let mut history = KeyHistory::default();
+        assert!(!history.covers(0));
+        for epoch in [1, 3, 4, 7] {
+            history.record_epoch(epoch, vec![(key.clone(), version(epoch))]);
+        }
+        let epochs = |vs: &[KeyVersion]| vs.iter().map(|v| v.epoch).collect::<Vec<_>>();
+        assert!(history.covers(1) && !history.covers(0));
+        assert!(history.covers_last(history.last(&key, 4), 4));
+        assert!(!history.covers_last(history.last(&key, 5), 5));
+        assert_eq!(epochs(history.last(&key, 2)), vec![4, 7]);
+        assert_eq!(epochs(history.last(&key, 10)), vec![1, 3, 4, 7]);
+        assert_eq!(epochs(history.since(&key, 3)), vec![3, 4, 7]);
+        assert!(history.at(&key, 2).is_none());
+
+        let (page, more) = history.page(&key, None, 2);
+        assert_eq!((epochs(page), more), (vec![1, 3], true));
+        let (page, more) = history.page(&key, Some(3), 2);
+        assert_eq!((epochs(page), more), (vec![4, 7], false));
+
+        // keys not written within the retained epochs are forgotten along with their versions
+        history.record_epoch(8, vec![(vec![2u8], version(8))]);
+        history.purge_before(8);
+        assert!(history.last(&key, 10).is_empty());
+        assert!(!history.covers_last(history.last(&key, 1), 1));
+        assert_eq!(history.versions.len(), 1);
+        history.purge_before(10);
+        assert!(history.versions.is_empty());
+        assert!(!history.covers(9) && history.covers(10));
+        history.record_epoch(11, vec![(key.clone(), version(11))]);
+        assert!(history.covers_last(history.last(&key, 1), 1));
+
+        // a commit whose versions were lost breaks the history up to it
+        history.skip_epoch(12);
+        assert!(!history.covers_last(history.last(&key, 1), 1));
+        assert!(history.covers(13));
+    }
+}
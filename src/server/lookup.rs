@@ -0,0 +1,97 @@
+use crate::grpc_handler::inner::mversegrpc::transaction_request::TransactionType;
+use crate::grpc_handler::inner::mversegrpc::{Epoch, LookUpLatestRequest, TransactionRequest};
+use crate::grpc_handler::inner::MerkleProviderClient;
+use crate::grpc_handler::outer::mverseouter::KeyHistoryResponse;
+use crate::server::history::KeyVersion;
+use crate::server::MerkleVerseServer;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use tonic::transport::Channel;
+
+#[derive(Debug, Clone, Copy)]
+pub enum HistoryQuery {
+    Last(usize),
+    Since(u64),
+    Complete { after: Option<u64>, limit: usize },
+}
+
+impl MerkleVerseServer {
+    /// Looks up the value and inclusion proof of every key written by `transactions`, which were just
+    /// committed to the inner provider as `epoch`, and records them in the key history.
+    pub(super) async fn record_key_versions(
+        &self,
+        inner_client: &mut MerkleProviderClient<Channel>,
+        epoch: u64,
+        transactions: &[TransactionRequest],
+    ) -> Result<()> {
+        let keys: BTreeMap<Vec<u8>, bool> = transactions
+            .iter()
+            .map(|t| {
+                (
+                    t.key.clone(),
+                    t.transaction_type() == TransactionType::Delete,
+                )
+            })
+            .collect();
+        let mut versions = vec![];
+        for (key, deleted) in keys {
+            let res = inner_client
+                .look_up_latest(LookUpLatestRequest { key: key.clone() })
+                .await?
+                .into_inner();
+            let version = KeyVersion {
+                epoch,
+                value: if deleted { None } else { Some(res.value) },
+                proof: res.proof,
+                head: res.head,
+            };
+            versions.push((key, version));
+        }
+        self.certificates.record_versions(epoch, versions);
+        Ok(())
+    }
+
+    /// the latest retained epoch whose committed root is `root`
+    pub fn committed_epoch_of(&self, root: &[u8]) -> Option<u64> {
+        self.certificates.view().history.epoch_of(root)
+    }
+
+    /// Answers a history lookup from the locally retained key history, with one proof per value
+    /// and the root each proof was generated against. Returns `None` if the versions asked for
+    /// are not all retained, because they were purged or committed before the server started.
+    pub fn key_history(&self, key: &[u8], query: HistoryQuery) -> Option<KeyHistoryResponse> {
+        let view = self.certificates.view();
+        let history = &view.key_history;
+        let (versions, more) = match query {
+            HistoryQuery::Last(n) => {
+                let versions = history.last(key, n);
+                (history.covers_last(versions, n).then_some(versions)?, false)
+            }
+            HistoryQuery::Since(epoch) => (
+                history.covers(epoch).then(|| history.since(key, epoch))?,
+                false,
+            ),
+            HistoryQuery::Complete { after, limit } => {
+                let from = after.map_or(0, |epoch| epoch + 1);
+                history.covers(from).then(|| history.page(key, after, limit))?
+            }
+        };
+        Some(KeyHistoryResponse {
+            values: versions
+                .iter()
+                .map(|v| v.value.clone().unwrap_or_default())
+                .collect(),
+            proof: versions
+                .iter()
+                .map(|v| v.proof.clone().unwrap_or_default())
+                .collect(),
+            head: view.current_root.clone(),
+            epochs: versions.iter().map(|v| Epoch { epoch: v.epoch }).collect(),
+            next_page_token: match versions.last() {
+                Some(v) if more => v.epoch.to_be_bytes().to_vec(),
+                _ => vec![],
+            },
+            heads: versions.iter().map(|v| v.head.clone()).collect(),
+        })
+    }
+}
@@ -0,0 +1,708 @@
+use crate::grpc_handler::outer::mverseouter::membership_change::ChangeType;
+use crate::grpc_handler::outer::mverseouter::{
+    Empty, EpochCertificate, MemberApproval, MemberInfo, MembershipChange, MembershipHistory,
+    MembershipProposal, MembershipRecord as ProtoRecord, ServerIdentity,
+};
+use crate::server::synchronization::{MerkleVerseServerState, RunState};
+use crate::server::{
+    quorum, MerkleVerseServer, PeerServer, PrivateKey, PublicKey, ServerCluster, ServerId,
+};
+use anyhow::{anyhow, Result};
+use bls_signatures::{aggregate, Serialize, Signature};
+use ed25519_dalek::{Signer, Verifier};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+const MEMBERSHIP_DOMAIN: &[u8] = b"merkleverse-membership";
+
+fn push_field(msg: &mut Vec<u8>, field: &[u8]) {
+    msg.extend_from_slice(&(field.len() as u64).to_be_bytes());
+    msg.extend_from_slice(field);
+}
+
+fn push_member(msg: &mut Vec<u8>, member: &MemberInfo) {
+    push_field(msg, member.server_id.as_bytes());
+    push_field(msg, member.connection_string.as_bytes());
+    push_field(msg, &member.bls_pub_key);
+    push_field(msg, &member.dalek_pub_key);
+}
+
+/// the bytes the subject of a change signs with `tag` "/change", and the members approving it
+/// with "/approve"
+fn change_message(tag: &[u8], change: &MembershipChange) -> Vec<u8> {
+    let mut msg = MEMBERSHIP_DOMAIN.to_vec();
+    msg.extend_from_slice(tag);
+    msg.extend_from_slice(&change.change_type.to_be_bytes());
+    msg.extend_from_slice(&change.version.to_be_bytes());
+    if let Some(member) = &change.member {
+        push_member(&mut msg, member);
+    }
+    msg
+}
+
+/// the digest the members of a cluster sign when they commit a membership record
+pub fn record_digest(version: u64, epoch: u64, members: &[MemberInfo]) -> Vec<u8> {
+    let mut msg = MEMBERSHIP_DOMAIN.to_vec();
+    msg.extend_from_slice(b"/record");
+    msg.extend_from_slice(&version.to_be_bytes());
+    msg.extend_from_slice(&epoch.to_be_bytes());
+    for member in members {
+        push_member(&mut msg, member);
+    }
+    Sha256::digest(msg).to_vec()
+}
+
+pub fn member_key(member: &MemberInfo) -> Result<PublicKey> {
+    PublicKey::new(&member.bls_pub_key, &member.dalek_pub_key)
+}
+
+/// Signs `change` with the key of the server joining or leaving.
+pub fn propose(change: MembershipChange, key: &PrivateKey) -> MembershipProposal {
+    let msg = change_message(b"/change", &change);
+    MembershipProposal {
+        signature: key.dalek.sign(&msg).to_bytes().to_vec(),
+        proof_of_possession: match change.change_type() {
+            ChangeType::Join => key.bls.sign(&msg).as_bytes(),
+            ChangeType::Leave => vec![],
+        },
+        change: Some(change),
+        peer_identity: None,
+        approvals: vec![],
+    }
+}
+
+/// Signs the consent of the current member `server` to `change`.
+pub fn approve(change: &MembershipChange, server: &ServerId, key: &PrivateKey) -> MemberApproval {
+    MemberApproval {
+        server_id: server.0.clone(),
+        signature: key
+            .dalek
+            .sign(&change_message(b"/approve", change))
+            .to_bytes()
+            .to_vec(),
+    }
+}
+
+/// the number of distinct members of `keys` whose approval of `change` is valid
+pub fn count_approvals(
+    change: &MembershipChange,
+    approvals: &[MemberApproval],
+    keys: &HashMap<ServerId, PublicKey>,
+) -> usize {
+    let msg = change_message(b"/approve", change);
+    let approvers: HashSet<&str> = approvals
+        .iter()
+        .filter(|approval| {
+            let Some(key) = keys.get(&ServerId(approval.server_id.clone())) else {
+                return false;
+            };
+            ed25519_dalek::Signature::try_from(approval.signature.as_slice())
+                .is_ok_and(|sig| key.dalek.verify(&msg, &sig).is_ok())
+        })
+        .map(|approval| approval.server_id.as_str())
+        .collect();
+    approvers.len()
+}
+
+/// Checks that `proposal` was signed by `subject`, and for joins that the subject holds its BLS key.
+pub fn verify_proposal(proposal: &MembershipProposal, subject: &PublicKey) -> Result<()> {
+    let change = proposal
+        .change
+        .as_ref()
+        .ok_or(anyhow!("Proposal carries no change"))?;
+    let msg = change_message(b"/change", change);
+    let sig = ed25519_dalek::Signature::try_from(proposal.signature.as_slice())?;
+    subject
+        .dalek
+        .verify(&msg, &sig)
+        .map_err(|e| anyhow!("Proposal is not signed by its subject: {}", e))?;
+    if change.change_type() == ChangeType::Join
+        && !subject
+            .bls
+            .verify(Signature::from_bytes(&proposal.proof_of_possession)?, &msg)
+    {
+        return Err(anyhow!("Proposal carries an invalid proof of possession"));
+    }
+    Ok(())
+}
+
+/// The members of a cluster after a committed change, and the signatures of the members before it.
+#[derive(Debug, Clone)]
+pub struct MembershipRecord {
+    pub version: u64,
+    pub epoch: u64,
+    pub change: MembershipChange,
+    pub members: Vec<MemberInfo>, // sorted by server id
+    pub signers: Vec<ServerId>,   // the members allowed to sign the record
+    pub digest: Vec<u8>,
+    pub signatures: HashMap<ServerId, Signature>,
+}
+
+impl MembershipRecord {
+    pub fn certificate(&self) -> Option<EpochCertificate> {
+        let sigs: Vec<Signature> = self.signatures.values().copied().collect();
+        Some(EpochCertificate {
+            aggregate_signature: aggregate(&sigs).ok()?.as_bytes(),
+            signers: self.signatures.keys().map(|id| id.0.clone()).collect(),
+            signatures: sigs.iter().map(|sig| sig.as_bytes()).collect(),
+        })
+    }
+}
+
+impl From<&MembershipRecord> for ProtoRecord {
+    fn from(record: &MembershipRecord) -> Self {
+        Self {
+            version: record.version,
+            epoch: record.epoch,
+            change: Some(record.change.clone()),
+            members: record.members.clone(),
+            certificate: record.certificate(),
+        }
+    }
+}
+
+/// The parallel servers of a cluster, the changes proposed to them and the history of changes.
+#[derive(Debug, Default)]
+pub struct Membership {
+    parallel: Option<ServerCluster>,
+    records: Vec<MembershipRecord>,
+    pending: BTreeMap<u64, Vec<MembershipProposal>>, // proposals by the epoch they are committed with
+    early_signatures: Vec<(u64, ServerId, Signature)>, // signatures received before their record
+    base_version: u64, // version adopted from other members when joining
+    left: bool,
+}
+
+impl Membership {
+    pub fn new(parallel: Option<ServerCluster>) -> Self {
+        Self {
+            parallel,
+            ..Default::default()
+        }
+    }
+
+    pub fn parallel(&self) -> Option<&ServerCluster> {
+        self.parallel.as_ref().filter(|_| !self.left)
+    }
+
+    pub fn set_address(&mut self, id: &ServerId, connection_string: &str) -> bool {
+        self.parallel
+            .as_mut()
+            .is_some_and(|cluster| cluster.set_address(id, connection_string))
+    }
+
+    pub fn version(&self) -> u64 {
+        self.records
+            .last()
+            .map(|r| r.version)
+            .unwrap_or(self.base_version)
+    }
+
+    pub fn records(&self) -> &[MembershipRecord] {
+        &self.records
+    }
+
+    pub fn record(&mut self, version: u64) -> Option<&mut MembershipRecord> {
+        self.records.iter_mut().find(|r| r.version == version)
+    }
+
+    /// the key of `server` as it was recorded when it joined, if it ever did
+    pub fn recorded_key(&self, server: &ServerId) -> Option<PublicKey> {
+        self.records
+            .iter()
+            .flat_map(|r| r.members.iter())
+            .find(|m| m.server_id == server.0)
+            .and_then(|m| member_key(m).ok())
+    }
+
+    pub fn is_member(&self, server: &ServerId) -> bool {
+        self.parallel
+            .as_ref()
+            .is_some_and(|p| p.get_server(server).is_some())
+    }
+
+    /// Adds `proposal` to the changes committed with `epoch`. Returns whether it was new.
+    pub fn propose(&mut self, epoch: u64, proposal: MembershipProposal) -> bool {
+        let pending = self.pending.entry(epoch).or_default();
+        if pending.iter().any(|p| p.change == proposal.change) {
+            return false;
+        }
+        pending.push(proposal);
+        true
+    }
+
+    /// the changes committed with `epoch`, sent along its prepare
+    pub fn pending(&self, epoch: u64) -> Vec<MembershipProposal> {
+        self.pending.get(&epoch).cloned().unwrap_or_default()
+    }
+
+    /// Takes the changes committed with `epoch`, in an order every member agrees on.
+    fn take_pending(&mut self, epoch: u64) -> Vec<MembershipChange> {
+        let mut changes: Vec<MembershipChange> = self
+            .pending
+            .remove(&epoch)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|p| p.change)
+            .collect();
+        changes.sort_by_key(|c| {
+            let id = c.member.as_ref().map(|m| m.server_id.clone());
+            (c.version, c.change_type, id)
+        });
+        changes
+    }
+}
+
+impl MerkleVerseServer {
+    /// the parallel servers of this cluster, as of the latest committed membership change
+    pub(super) fn parallel(&self) -> Option<ServerCluster> {
+        self.state.lock().membership().parallel().cloned()
+    }
+
+    fn member_info(&self) -> MemberInfo {
+        MemberInfo {
+            server_id: self.id.0.clone(),
+            connection_string: self.connection_string.clone(),
+            bls_pub_key: self.public_key.bls.as_bytes(),
+            dalek_pub_key: self.public_key.dalek.as_bytes().to_vec(),
+        }
+    }
+
+    fn peer_server(&self, member: &MemberInfo) -> Result<PeerServer> {
+        Ok(PeerServer {
+            id: ServerId(member.server_id.clone()),
+            connection_string: format!("http://{}", member.connection_string),
+            prefix: self.prefix.clone(),
+            length: self.length,
+            public_key: member_key(member)?,
+        })
+    }
+
+    /// the key `server` signs with at `epoch`, for callers holding the state lock
+    fn registered_key_in(
+        &self,
+        state: &MerkleVerseServerState,
+        server: &ServerId,
+        epoch: u64,
+    ) -> Option<PublicKey> {
+        state
+            .keys()
+            .key_at(server, epoch)
+            .cloned()
+            .or_else(|| self.configured_key_in(state, server))
+    }
+
+    /// Checks that `proposal` is signed by its subject, applies to the current membership version,
+    /// and for joins that a quorum of the current members approved it under their registered keys.
+    fn check_proposal(
+        &self,
+        state: &MerkleVerseServerState,
+        proposal: &MembershipProposal,
+    ) -> Result<()> {
+        let change = proposal
+            .change
+            .as_ref()
+            .ok_or(anyhow!("Proposal carries no change"))?;
+        let member = change
+            .member
+            .as_ref()
+            .ok_or(anyhow!("Proposal carries no member"))?;
+        let subject = ServerId(member.server_id.clone());
+        let epoch = self.current_epoch();
+        let subject_key = match change.change_type() {
+            ChangeType::Join => member_key(member)?,
+            ChangeType::Leave => self
+                .registered_key_in(state, &subject, epoch)
+                .ok_or(anyhow!("Server {} is not known", subject.0))?,
+        };
+        verify_proposal(proposal, &subject_key)?;
+
+        let membership = state.membership();
+        if change.version != membership.version() {
+            return Err(anyhow!(
+                "Proposal applies to membership version {}, the current version is {}",
+                change.version,
+                membership.version()
+            ));
+        }
+        let is_member = subject == self.id || membership.is_member(&subject);
+        match change.change_type() {
+            ChangeType::Join if is_member => {
+                Err(anyhow!("Server {} is already a member", subject.0))
+            }
+            ChangeType::Leave if !is_member => {
+                Err(anyhow!("Server {} is not a member", subject.0))
+            }
+            ChangeType::Join => {
+                let members: Vec<ServerId> = membership
+                    .parallel()
+                    .into_iter()
+                    .flat_map(|p| p.servers.keys().cloned())
+                    .chain((!membership.left).then(|| self.id.clone()))
+                    .collect();
+                let keys: HashMap<ServerId, PublicKey> = members
+                    .iter()
+                    .filter_map(|id| Some((id.clone(), self.registered_key_in(state, id, epoch)?)))
+                    .collect();
+                let approved = count_approvals(change, &proposal.approvals, &keys);
+                let required = quorum(members.len());
+                if approved < required {
+                    return Err(anyhow!(
+                        "Join of {} is approved by {} of {} members, {} are required",
+                        subject.0,
+                        approved,
+                        members.len(),
+                        required
+                    ));
+                }
+                Ok(())
+            }
+            ChangeType::Leave => Ok(()),
+        }
+    }
+
+    /// Verifies a membership change proposed by its subject or forwarded by a member, and adds it
+    /// to the changes committed with the epoch this server commits next. New proposals are
+    /// forwarded to the members, and sent along the prepare of that epoch.
+    pub async fn receive_membership_proposal(
+        &self,
+        mut proposal: MembershipProposal,
+    ) -> Result<()> {
+        let parallel = {
+            let mut serv_state = self.state.lock();
+            self.check_proposal(&serv_state, &proposal)?;
+            let current_epoch = self.current_epoch();
+            let epoch = match serv_state.run_state() {
+                RunState::Prepare(_) => current_epoch + 1,
+                RunState::Normal => current_epoch,
+            };
+            proposal.peer_identity = Some(ServerIdentity {
+                server_id: self.id.0.clone(),
+            });
+            if !serv_state.membership_mut().propose(epoch, proposal.clone()) {
+                return Ok(());
+            }
+            tracing::info!(
+                "Membership change of {} proposed for epoch {}",
+                proposal
+                    .change
+                    .as_ref()
+                    .and_then(|c| c.member.as_ref())
+                    .map_or("", |m| m.server_id.as_str()),
+                epoch
+            );
+            serv_state.membership().parallel().cloned()
+        };
+
+        if let Some(parallel) = parallel {
+            self.broadcast("membership change", parallel.servers.into_values(), |mut client| {
+                let proposal = proposal.clone();
+                async move { client.propose_membership(proposal).await }
+            })
+            .await;
+        }
+        Ok(())
+    }
+
+    /// Adds the changes a member sent along its prepare of `epoch` to those committed with it, so
+    /// that the members preparing an epoch commit the same changes.
+    pub(super) fn receive_prepared_membership(
+        &self,
+        state: &mut MerkleVerseServerState,
+        epoch: u64,
+        proposals: Vec<MembershipProposal>,
+    ) {
+        if epoch < self.current_epoch() {
+            return;
+        }
+        for proposal in proposals {
+            match self.check_proposal(state, &proposal) {
+                Ok(()) => {
+                    state.membership_mut().propose(epoch, proposal);
+                }
+                Err(e) => tracing::warn!("Dropping a prepared membership change: {}", e),
+            }
+        }
+    }
+
+    /// Applies the changes proposed for `epoch`, which is being committed, updating the parallel
+    /// servers and their run states together, and signs the resulting membership records.
+    pub(super) fn apply_membership(
+        &self,
+        state: &mut MerkleVerseServerState,
+        epoch: u64,
+    ) -> Result<()> {
+        let changes = state.membership_mut().take_pending(epoch);
+        let signing_key = self.signing_key_in(state, epoch);
+        for change in changes {
+            let Some(member) = change.member.clone() else {
+                continue;
+            };
+            if change.version != state.membership().version() {
+                continue;
+            }
+            let subject = ServerId(member.server_id.clone());
+            let mut parallel = state
+                .membership()
+                .parallel
+                .clone()
+                .unwrap_or_else(ServerCluster::new);
+            let signers: Vec<ServerId> = parallel
+                .servers
+                .keys()
+                .cloned()
+                .chain([self.id.clone()])
+                .collect();
+            match change.change_type() {
+                ChangeType::Join => {
+                    parallel.insert(self.peer_server(&member)?);
+                    state.add_peer(subject.clone())?;
+                }
+                ChangeType::Leave => {
+                    parallel.servers.remove(&subject);
+                    state.remove_peer(&subject);
+                }
+            }
+            let left = subject == self.id && change.change_type() == ChangeType::Leave;
+            let mut members: Vec<MemberInfo> = parallel
+                .servers
+                .values()
+                .map(|srv| MemberInfo {
+                    server_id: srv.id.0.clone(),
+                    connection_string: srv
+                        .connection_string
+                        .trim_start_matches("http://")
+                        .to_string(),
+                    bls_pub_key: srv.public_key.bls.as_bytes(),
+                    dalek_pub_key: srv.public_key.dalek.as_bytes().to_vec(),
+                })
+                .chain((!left).then(|| self.member_info()))
+                .collect();
+            members.sort_by(|a, b| a.server_id.cmp(&b.server_id));
+
+            let version = change.version + 1;
+            let digest = record_digest(version, epoch + 1, &members);
+            let mut record = MembershipRecord {
+                version,
+                epoch: epoch + 1,
+                change,
+                members,
+                signers,
+                digest,
+                signatures: HashMap::new(),
+            };
+            record
+                .signatures
+                .insert(self.id.clone(), signing_key.bls.sign(&record.digest));
+            tracing::info!(
+                "Committed membership version {} at epoch {}: {} members",
+                version,
+                epoch,
+                record.members.len()
+            );
+            let membership = state.membership_mut();
+            membership.parallel = Some(parallel).filter(|p| p.len() > 0);
+            membership.left |= left;
+            membership.records.push(record);
+            let early = std::mem::take(&mut membership.early_signatures);
+            for (v, signer, sig) in early {
+                if let Err(e) = self.add_membership_signature(state, v, signer, sig) {
+                    tracing::warn!("Dropping membership signature: {}", e);
+                }
+            }
+            if left {
+                tracing::warn!("This server left its cluster, it no longer takes part in commits");
+            }
+        }
+        Ok(())
+    }
+
+    /// the signature of this server over the membership record committed with `epoch`, if any
+    pub(super) fn membership_signature(
+        &self,
+        state: &MerkleVerseServerState,
+        epoch: u64,
+    ) -> Option<(u64, Signature)> {
+        let record = state
+            .membership()
+            .records
+            .iter()
+            .rev()
+            .find(|r| r.epoch == epoch + 1)?;
+        Some((record.version, *record.signatures.get(&self.id)?))
+    }
+
+    /// Adopts the membership of a cluster this server joined while it was not running, as reported
+    /// by the members of its configuration.
+    pub async fn sync_membership(&self) -> Result<()> {
+        let Some(parallel) = self.parallel() else {
+            return Ok(());
+        };
+        let mut latest: Option<ProtoRecord> = None;
+        for srv in parallel.servers.values() {
+            let res = async {
+                let history = self.peer_client(srv)?.get_membership(Empty {}).await?;
+                anyhow::Ok(history.into_inner().records.pop())
+            };
+            match res.await {
+                Ok(Some(record))
+                    if latest.as_ref().is_none_or(|l| record.version > l.version) =>
+                {
+                    latest = Some(record)
+                }
+                Ok(_) => {}
+                Err(e) => tracing::debug!("Failed to fetch the membership of {}: {}", srv.id.0, e),
+            }
+        }
+        let Some(record) = latest else {
+            return Ok(());
+        };
+        if !record.members.iter().any(|m| m.server_id == self.id.0) {
+            return Ok(());
+        }
+        let mut cluster = ServerCluster::new();
+        for member in record.members.iter().filter(|m| m.server_id != self.id.0) {
+            cluster.insert(self.peer_server(member)?);
+        }
+        let mut serv_state = self.state.lock();
+        if serv_state.membership().version() >= record.version {
+            return Ok(());
+        }
+        for id in cluster.servers.keys() {
+            serv_state.add_peer(id.clone())?;
+        }
+        let membership = serv_state.membership_mut();
+        membership.parallel = Some(cluster);
+        membership.base_version = record.version;
+        tracing::info!("Adopted membership version {}", record.version);
+        Ok(())
+    }
+
+    fn add_membership_signature(
+        &self,
+        state: &mut MerkleVerseServerState,
+        version: u64,
+        signer: ServerId,
+        sig: Signature,
+    ) -> Result<()> {
+        let Some(record) = state.membership_mut().record(version) else {
+            if version > state.membership().version() {
+                state
+                    .membership_mut()
+                    .early_signatures
+                    .push((version, signer, sig));
+                return Ok(());
+            }
+            return Err(anyhow!("Membership version {} is not retained", version));
+        };
+        if !record.signers.contains(&signer) {
+            return Err(anyhow!(
+                "Server {} was not a member before version {}",
+                signer.0,
+                version
+            ));
+        }
+        let (epoch, digest) = (record.epoch, record.digest.clone());
+        let key = self
+            .registered_key_in(state, &signer, epoch - 1)
+            .ok_or(anyhow!("No key is known for server {}", signer.0))?;
+        if !key.bls.verify(sig, &digest) {
+            return Err(anyhow!(
+                "Membership signature of server {} is invalid",
+                signer.0
+            ));
+        }
+        if let Some(record) = state.membership_mut().record(version) {
+            record.signatures.insert(signer, sig);
+        }
+        Ok(())
+    }
+
+    /// Verifies and records the signature of `signer` over a membership record.
+    pub fn receive_membership_signature(
+        &self,
+        version: u64,
+        signer: ServerId,
+        sig_bytes: &[u8],
+    ) -> Result<()> {
+        let sig = Signature::from_bytes(sig_bytes)?;
+        let mut serv_state = self.state.lock();
+        self.add_membership_signature(&mut serv_state, version, signer, sig)
+    }
+
+    pub fn membership_history(&self) -> MembershipHistory {
+        let serv_state = self.state.lock();
+        let membership = serv_state.membership();
+        MembershipHistory {
+            records: membership.records().iter().map(Into::into).collect(),
+            version: membership.version(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(id: &str, key: &PrivateKey) -> MemberInfo {
+        let public_key = key.public_key();
+        MemberInfo {
+            server_id: id.into(),
+            connection_string: "127.0.0.1:8000".into(),
+            bls_pub_key: public_key.bls.as_bytes(),
+            dalek_pub_key: public_key.dalek.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn tst_membership_proposal() -> Result<()> {
+        let key = PrivateKey::from([3u8; 32]);
+        let change = MembershipChange {
+            change_type: ChangeType::Join.into(),
+            member: Some(member("srv_3", &key)),
+            version: 2,
+        };
+        let proposal = propose(change, &key);
+        verify_proposal(&proposal, &key.public_key())?;
+        assert!(verify_proposal(&proposal, &PrivateKey::from([4u8; 32]).public_key()).is_err());
+
+        let mut replayed = proposal.clone();
+        replayed.change.as_mut().unwrap().version = 3;
+        assert!(verify_proposal(&replayed, &key.public_key()).is_err());
+
+        // a join counts the approvals of distinct members under their registered keys
+        let change = proposal.change.clone().unwrap();
+        let (id_0, key_0) = (ServerId("srv_0".into()), PrivateKey::from([1u8; 32]));
+        let (id_1, key_1) = (ServerId("srv_1".into()), PrivateKey::from([2u8; 32]));
+        let keys = HashMap::from([
+            (id_0.clone(), key_0.public_key()),
+            (id_1.clone(), key_1.public_key()),
+        ]);
+        let approvals = vec![
+            approve(&change, &id_0, &key_0),
+            approve(&change, &id_0, &key_0),
+            approve(&change, &id_1, &key_0),
+            approve(&change, &ServerId("srv_3".into()), &key),
+        ];
+        assert_eq!(count_approvals(&change, &approvals, &keys), 1);
+        assert_eq!(count_approvals(&replayed.change.unwrap(), &approvals, &keys), 0);
+
+        let mut membership = Membership::default();
+        assert!(membership.propose(5, proposal.clone()));
+        assert!(!membership.propose(5, proposal));
+        assert_eq!(membership.pending(5).len(), 1);
+        assert_eq!(membership.take_pending(5), vec![change]);
+        assert!(membership.pending(5).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn tst_record_digest() {
+        let members = vec![
+            member("srv_0", &PrivateKey::from([1u8; 32])),
+            member("srv_1", &PrivateKey::from([2u8; 32])),
+        ];
+        let digest = record_digest(1, 4, &members);
+        assert_ne!(digest, record_digest(1, 5, &members));
+        assert_ne!(digest, record_digest(1, 4, &members[..1]));
+    }
+}
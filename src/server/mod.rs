@@ -1,20 +1,52 @@
+mod admission;
+mod broadcast;
+mod certificates;
+mod connections;
+mod root_chain;
+mod gossip;
+mod history;
+mod lookup;
+mod membership;
 mod messages;
 mod mverse;
+mod peer_auth;
+mod pool;
+mod rotation;
+mod shutdown;
+mod status;
+mod store;
+mod subscriptions;
 mod synchronization;
+mod tls;
 mod transactions;
 mod validation;
 
 use crate::utils;
 use std::collections::HashMap;
 
+use crate::server::certificates::CertificateStore;
+use crate::server::connections::Connections;
 use crate::server::mverse::PeerServerPointer;
+use crate::server::pool::ShardedPool;
+use crate::server::store::DataDir;
 use crate::server::synchronization::MerkleVerseServerState;
 use anyhow::Result;
 
 use std::convert::TryFrom;
 use std::fmt::{Debug, Formatter};
-use std::sync::{Arc, Mutex};
-pub use validation::{PrivateKey, PublicKey};
+use parking_lot::Mutex;
+use std::sync::Arc;
+pub use admission::AdmissionError;
+pub use certificates::quorum;
+pub use root_chain::verify_root_chain;
+pub use gossip::cluster_id;
+pub use lookup::HistoryQuery;
+pub use membership::{approve, member_key, propose, record_digest};
+pub use rotation::verify_announcement;
+pub use tls::{client_endpoint, peer_ids, tls_name, Authority, Tls};
+#[cfg(test)]
+pub use rotation::announce;
+pub use validation::{verify_multisig, KeyDerivation, PrivateKey, PublicKey};
 
 struct Signature {
     signature: Vec<u8>,
@@ -59,6 +91,17 @@ impl ServerCluster {
     fn insert(&mut self, server: PeerServer) {
         self.servers.insert(server.id.clone(), server);
     }
+
+    /// points `id` to `connection_string`, returns whether the server belongs to this cluster
+    fn set_address(&mut self, id: &ServerId, connection_string: &str) -> bool {
+        match self.servers.get_mut(id) {
+            Some(srv) => {
+                srv.connection_string = connection_string.to_string();
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq, Default)]
@@ -85,12 +128,13 @@ pub struct MerkleVerseServer {
     pub id: ServerId,
     prefix: Index,
     length: u32,
-    superior: Option<ServerCluster>,
-    parallel: Option<ServerCluster>,
-    epoch_interval: u32,
     private_key: PrivateKey,
     public_key: PublicKey,
-    state: Arc<Mutex<MerkleVerseServerState>>, // TODO: consider using a RwLock instead
+    connections: Arc<Connections>,
+    pool: Arc<ShardedPool>,
+    certificates: Arc<CertificateStore>,
+    data_dir: Arc<DataDir>,
+    state: Arc<Mutex<MerkleVerseServerState>>, // consensus state: phase, peers, keys and membership
 }
 
 impl Debug for MerkleVerseServer{
@@ -101,7 +145,6 @@ impl Debug for MerkleVerseServer{
             .field("id", &self.id)
             .field("prefix", &self.prefix)
             .field("length", &self.length)
-            .field("epoch_interval", &self.epoch_interval)
             .field("state", &self.state)
             .finish()
     }
@@ -119,6 +162,12 @@ impl Index {
             usize::try_from(self.length)?,
         ))
     }
+
+    /// whether `key`, read as an index of `length` bits, starts with this prefix
+    pub fn is_prefix_of(&self, key: &[u8], length: u32) -> Result<bool> {
+        let key_bin = utils::binary_string(&key.to_vec(), usize::try_from(length)?);
+        Ok(key_bin.starts_with(&self.to_binstring()?))
+    }
 }
 
 impl From<Vec<u8>> for Index {
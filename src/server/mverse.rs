@@ -1,27 +1,29 @@
-use super::{Index, MerkleVerseServer, PrivateKey, PublicKey, ServerId};
+use super::membership::Membership;
+use super::{Index, MerkleVerseServer, PublicKey, ServerId};
 use crate::config;
 use crate::grpc_handler::inner::mversegrpc;
 use crate::grpc_handler::inner::MerkleProviderClient;
 use crate::grpc_handler::outer;
 use crate::grpc_handler::outer::mverseouter::ClientTransactionRequest;
 use crate::server::synchronization::MerkleVerseServerState;
-use crate::server::{PeerServer, ServerCluster};
+use crate::server::certificates::CertificateStore;
+use crate::server::pool::ShardedPool;
+use crate::server::connections::Connections;
+use crate::server::store::DataDir;
+use crate::server::{PeerServer, ServerCluster, Tls};
 use anyhow::{anyhow, Result};
 use base64::{engine::general_purpose, Engine as _};
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
-use std::sync::{Arc, Mutex};
+use parking_lot::Mutex;
+use std::sync::Arc;
 use tonic::transport::Channel;
-use tonic::{IntoRequest, Status};
+use tonic::IntoRequest;
 
 pub type PeerServerPointer = Rc<RefCell<PeerServer>>;
 
 impl PeerServer {
-    pub async fn get_client(&self) -> Result<outer::MerkleVerseClient<Channel>> {
-        Ok(outer::MerkleVerseClient::connect(self.connection_string.clone()).await?)
-    }
-
     pub async fn from_config(config: &config::ServerConfig) -> Result<Self> {
         Ok(Self {
             id: ServerId(config.id.clone()),
@@ -61,22 +63,19 @@ impl MerkleVerseServer {
         }
     }
 
-    pub async fn get_client(&self) -> Result<outer::MerkleVerseClient<Channel>> {
-        Ok(outer::MerkleVerseClient::connect(self.connection_string.clone()).await?)
+    pub fn get_client(&self) -> Result<outer::MerkleVerseClient<Channel>> {
+        self.connections
+            .peer(&format!("http://{}", self.connection_string), &self.id.0)
     }
 
-    pub async fn get_inner_client(&self) -> Result<MerkleProviderClient<Channel>, Status> {
-        match MerkleProviderClient::connect(self.inner_dst.clone()).await {
-            Ok(res) => Ok(res),
-            Err(e) => Err(Status::internal(format!(
-                "Failed to connect to inner server: {}",
-                e
-            ))),
-        }
+    pub fn get_inner_client(&self) -> Result<MerkleProviderClient<Channel>> {
+        self.connections
+            .inner(&self.inner_dst)
+            .map_err(|e| anyhow!("Failed to connect to inner server: {}", e))
     }
 
     async fn trigger_epoch(&self) -> Result<()> {
-        let mut inn_client = self.get_inner_client().await?;
+        let mut inn_client = self.get_inner_client()?;
         let res = inn_client
             .trigger_epoch(mversegrpc::Empty {}.into_request())
             .await?
@@ -85,14 +84,14 @@ impl MerkleVerseServer {
             "Epoch #{:?} triggered, new head: {:?}",
             res.new_epoch, res.head
         );
-        if let Some(servers) = &self.superior {
+        if let Some(servers) = &self.superior() {
             let mut futures = vec![];
             for (_, srv) in &servers.servers {
                 tracing::info!(
                     "Triggering epoch on superior server: {:?}",
                     srv.connection_string
                 );
-                let mut client = srv.get_client().await?;
+                let mut client = self.peer_client(srv)?;
                 let cphead = res.head.clone();
                 futures.push(async move {
                     client
@@ -107,6 +106,7 @@ impl MerkleVerseServer {
                                 }),
                                 auxiliary: None, // TODO: make auxiliary a signature of the head
                                 wait: false,
+                                idempotency_id: None,
                             }
                             .into_request(),
                         )
@@ -118,11 +118,51 @@ impl MerkleVerseServer {
         Ok(())
     }
 
+    pub(super) fn superior(&self) -> Option<ServerCluster> {
+        self.state.lock().superior().cloned()
+    }
+
+    pub(super) fn gossip_peers(&self) -> Option<ServerCluster> {
+        self.state.lock().gossip_peers().cloned()
+    }
+
+    /// Applies the settings of `config` that may change while the server runs: the epoch interval
+    /// and the addresses of known peers. Anything else in `config` is ignored.
+    pub fn apply_config(&self, config: &config::ServersConfig) {
+        let mut serv_state = self.state.lock();
+        serv_state.set_epoch_interval(config.server.epoch_interval);
+        for peer in config.peers.iter().flatten() {
+            let id = ServerId(peer.id.clone());
+            if !serv_state.set_address(&id, &format!("http://{}", peer.connection_string)) {
+                tracing::warn!("Server {} is no longer a peer, ignoring its address", peer.id);
+            }
+        }
+    }
+
     /// Generate from a single Server config
     pub async fn from_config(config: &config::LocalServerConfig) -> Result<Self> {
         let inn_cfig = &config.server_config;
+        let mut state = MerkleVerseServerState::new();
+        state.set_epoch_interval(config.epoch_interval);
+        let data_dir = DataDir::new(config.data_dir());
+        let certificates = CertificateStore::default();
+        let pool = ShardedPool::new(config.pool.clone(), config.admission.clone());
+        match data_dir.load_commit()? {
+            Some(record) => {
+                tracing::info!(
+                    "Resuming after epoch {}, restored from {}",
+                    record.epoch,
+                    data_dir.path().display()
+                );
+                pool.set_intake_epoch(record.epoch + 1);
+                certificates.restore(record);
+            }
+            None => tracing::info!(
+                "No committed epoch in {}, starting from the genesis chain",
+                data_dir.path().display()
+            ),
+        }
         Ok(Self {
-            epoch_interval: config.epoch_interval,
             id: ServerId(inn_cfig.id.clone()),
             inner_dst: format!("http://127.0.0.1:{}", config.inner_port),
             length: inn_cfig.length,
@@ -135,17 +175,20 @@ impl MerkleVerseServer {
                 )?,
                 None => Index::default(),
             },
-            connection_string: format!("127.0.0.1:{}", config.outer_port),
-            parallel: None,
-            superior: None,
-            private_key: PrivateKey::try_from(
-                general_purpose::STANDARD.decode(&config.private_key)?,
-            )?,
+            connection_string: inn_cfig.connection_string.clone(),
+            private_key: config.load_private_key()?,
+            connections: Arc::new(Connections::new(
+                config.connections.clone(),
+                config.tls_files().as_ref().map(Tls::load).transpose()?,
+            )),
             public_key: PublicKey::new(
                 &general_purpose::STANDARD.decode(&inn_cfig.bls_pub_key)?,
                 &general_purpose::STANDARD.decode(&inn_cfig.dalek_pub_key)?,
             )?,
-            state: Arc::new(Mutex::new(MerkleVerseServerState::new())),
+            pool: Arc::new(pool),
+            certificates: Arc::new(certificates),
+            data_dir: Arc::new(data_dir),
+            state: Arc::new(Mutex::new(state)),
         })
     }
 
@@ -180,7 +223,7 @@ impl MerkleVerseServer {
                 .cmp(&b.1.borrow().prefix.to_binstring().unwrap())
         });
 
-        let mut cur_srv = Self::from_config(&config.server).await?;
+        let cur_srv = Self::from_config(&config.server).await?;
         let cur_pref = cur_srv.prefix.to_binstring()?;
         let mut superiors = vec![];
         let mut parallels = vec![];
@@ -199,19 +242,28 @@ impl MerkleVerseServer {
             cur_srv
                 .state
                 .lock()
-                .unwrap()
                 .add_peer(
                     peer_servers[i].1.clone().borrow().id.clone(),
                 )?;
         }
 
         if !superiors.is_empty() {
-            cur_srv.superior = Some(ServerCluster::from(superiors));
+            let superior = ServerCluster::from(superiors);
+            cur_srv.state.lock().set_superior(Some(superior));
         }
 
         if !parallels.is_empty() {
-            cur_srv.parallel = Some(ServerCluster::from(parallels));
+            *cur_srv.state.lock().membership_mut() =
+                Membership::new(Some(ServerCluster::from(parallels)));
+        }
+
+        if !peer_servers.is_empty() {
+            let gossip_peers = ServerCluster::from(
+                peer_servers.into_iter().map(|(_, srv)| srv).collect::<Vec<_>>(),
+            );
+            cur_srv.state.lock().set_gossip_peers(Some(gossip_peers));
         }
+        cur_srv.restore_key_announcements()?;
 
         Ok(cur_srv)
     }
@@ -0,0 +1,228 @@
+use crate::grpc_handler::outer::mverseouter::{
+    PeerAuth, PeerCommitRequest, PeerLeavingRequest, PeerPrepareRequest,
+};
+use crate::server::{MerkleVerseServer, PrivateKey, PublicKey, ServerId};
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{Signer, Verifier};
+use prost::Message;
+use std::collections::BTreeSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PEER_RPC_DOMAIN: &[u8] = b"merkleverse-peer-rpc";
+const REPLAY_WINDOW_MS: u64 = 30_000; // tolerated clock skew and delay of signed peer RPCs
+
+/// A peer RPC carrying the signature of its sender.
+pub trait SignedRpc: Message + Clone {
+    const METHOD: &'static str;
+    fn epoch(&self) -> u64;
+    fn auth(&self) -> Option<&PeerAuth>;
+    fn set_auth(&mut self, auth: Option<PeerAuth>);
+}
+
+impl SignedRpc for PeerPrepareRequest {
+    const METHOD: &'static str = "peer_prepare";
+
+    fn epoch(&self) -> u64 {
+        self.epoch.as_ref().map_or(0, |e| e.epoch)
+    }
+
+    fn auth(&self) -> Option<&PeerAuth> {
+        self.auth.as_ref()
+    }
+
+    fn set_auth(&mut self, auth: Option<PeerAuth>) {
+        self.auth = auth;
+    }
+}
+
+impl SignedRpc for PeerCommitRequest {
+    const METHOD: &'static str = "peer_commit";
+
+    fn epoch(&self) -> u64 {
+        self.epoch.as_ref().map_or(0, |e| e.epoch)
+    }
+
+    fn auth(&self) -> Option<&PeerAuth> {
+        self.auth.as_ref()
+    }
+
+    fn set_auth(&mut self, auth: Option<PeerAuth>) {
+        self.auth = auth;
+    }
+}
+
+impl SignedRpc for PeerLeavingRequest {
+    const METHOD: &'static str = "peer_leaving";
+
+    fn epoch(&self) -> u64 {
+        self.epoch.as_ref().map_or(0, |e| e.epoch)
+    }
+
+    fn auth(&self) -> Option<&PeerAuth> {
+        self.auth.as_ref()
+    }
+
+    fn set_auth(&mut self, auth: Option<PeerAuth>) {
+        self.auth = auth;
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// the bytes signed by the sender of `req`
+fn rpc_message<T: SignedRpc>(req: &T, auth: &PeerAuth) -> Vec<u8> {
+    let mut unsigned = req.clone();
+    unsigned.set_auth(None);
+    let mut msg = PEER_RPC_DOMAIN.to_vec();
+    for field in [
+        T::METHOD.as_bytes(),
+        auth.server_id.as_bytes(),
+        unsigned.encode_to_vec().as_slice(),
+    ] {
+        msg.extend_from_slice(&(field.len() as u64).to_be_bytes());
+        msg.extend_from_slice(field);
+    }
+    msg.extend_from_slice(&req.epoch().to_be_bytes());
+    msg.extend_from_slice(&auth.timestamp_ms.to_be_bytes());
+    msg.extend_from_slice(&auth.nonce.to_be_bytes());
+    msg
+}
+
+/// Signs `req` as sent now by `server_id`.
+pub fn sign_rpc<T: SignedRpc>(mut req: T, server_id: &ServerId, key: &PrivateKey) -> T {
+    let mut auth = PeerAuth {
+        server_id: server_id.0.clone(),
+        timestamp_ms: now_ms(),
+        nonce: rand::random(),
+        signature: vec![],
+    };
+    auth.signature = key
+        .dalek
+        .sign(&rpc_message(&req, &auth))
+        .to_bytes()
+        .to_vec();
+    req.set_auth(Some(auth));
+    req
+}
+
+/// Checks that `req` was signed with `key`.
+pub fn verify_rpc<T: SignedRpc>(req: &T, key: &PublicKey) -> Result<()> {
+    let auth = req
+        .auth()
+        .ok_or(anyhow!("{} requests must be signed", T::METHOD))?;
+    let sig = ed25519_dalek::Signature::try_from(auth.signature.as_slice())?;
+    key.dalek
+        .verify(&rpc_message(req, auth), &sig)
+        .map_err(|e| {
+            anyhow!(
+                "{} request is not signed by {}: {}",
+                T::METHOD,
+                auth.server_id,
+                e
+            )
+        })
+}
+
+/// The signed peer RPCs accepted within the replay window, older ones are rejected by timestamp.
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    seen: BTreeSet<(u64, String, u64)>, // timestamp, sender and nonce
+}
+
+impl ReplayWindow {
+    /// Accepts `auth` unless its timestamp is too far from `now_ms` or it was accepted before.
+    pub fn accept(&mut self, auth: &PeerAuth, now_ms: u64) -> Result<()> {
+        if auth.timestamp_ms.abs_diff(now_ms) > REPLAY_WINDOW_MS {
+            return Err(anyhow!(
+                "Request of {} is {}ms away from the local clock, beyond the replay window",
+                auth.server_id,
+                auth.timestamp_ms.abs_diff(now_ms)
+            ));
+        }
+        let oldest = (now_ms.saturating_sub(REPLAY_WINDOW_MS), String::new(), 0);
+        self.seen = self.seen.split_off(&oldest);
+        if !self
+            .seen
+            .insert((auth.timestamp_ms, auth.server_id.clone(), auth.nonce))
+        {
+            return Err(anyhow!("Replayed request of {}", auth.server_id));
+        }
+        Ok(())
+    }
+}
+
+impl MerkleVerseServer {
+    /// Signs `req` with the key of this server at the epoch of the request.
+    pub(super) fn sign_rpc<T: SignedRpc>(&self, req: T) -> T {
+        let key = self.signing_key(req.epoch());
+        sign_rpc(req, &self.id, &key)
+    }
+
+    /// Checks that `req` was signed by a member of this cluster, with its key at the epoch of the
+    /// request, and that it is not a replay. Returns the sender.
+    pub fn authenticate_rpc<T: SignedRpc>(&self, req: &T) -> Result<ServerId> {
+        let auth = req
+            .auth()
+            .ok_or(anyhow!("{} requests must be signed", T::METHOD))?;
+        let sender = ServerId(auth.server_id.clone());
+        if self
+            .parallel()
+            .and_then(|p| p.get_server(&sender).cloned())
+            .is_none()
+        {
+            return Err(anyhow!(
+                "Server {} is not a member of this cluster",
+                sender.0
+            ));
+        }
+        let key = self
+            .public_key_at(&sender, req.epoch())
+            .ok_or(anyhow!("No key is known for server {}", sender.0))?;
+        verify_rpc(req, &key)?;
+        self.state
+            .lock()
+            .replay_window_mut()
+            .accept(auth, now_ms())?;
+        Ok(sender)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc_handler::inner::mversegrpc::Epoch;
+
+    #[test]
+    fn tst_signed_rpc() -> Result<()> {
+        let key = PrivateKey::generate();
+        let req = PeerPrepareRequest {
+            epoch: Some(Epoch { epoch: 3 }),
+            ..Default::default()
+        };
+        let signed = sign_rpc(req, &ServerId("s1".into()), &key);
+        verify_rpc(&signed, &key.public_key())?;
+
+        // the signature covers the epoch, and the key of another server does not verify it
+        let mut tampered = signed.clone();
+        tampered.epoch = Some(Epoch { epoch: 4 });
+        assert!(verify_rpc(&tampered, &key.public_key()).is_err());
+        assert!(verify_rpc(&signed, &PrivateKey::generate().public_key()).is_err());
+
+        let mut window = ReplayWindow::default();
+        let auth = signed.auth.as_ref().unwrap();
+        window.accept(auth, auth.timestamp_ms)?;
+        assert!(window.accept(auth, auth.timestamp_ms + 1).is_err());
+        let stale = PeerAuth {
+            nonce: auth.nonce.wrapping_add(1),
+            ..auth.clone()
+        };
+        assert!(window
+            .accept(&stale, auth.timestamp_ms + REPLAY_WINDOW_MS + 1)
+            .is_err());
+        Ok(())
+    }
+}
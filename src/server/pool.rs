@@ -0,0 +1,379 @@
+use crate::config::{AdmissionConfig, PoolConfig};
+use crate::grpc_handler::outer::mverseouter::{
+    AdmissionState, ClientTransactionRequest, PeerTransactionRequest,
+};
+use crate::grpc_handler::outer::TransactionRequest;
+use crate::server::admission::Admission;
+use crate::server::transactions::{Receipt, Receipts, Repooled, Transaction, TransactionPool};
+use anyhow::{anyhow, Result};
+use parking_lot::{Mutex, RwLock};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub const SHARDS: usize = 16; // shards of the transaction pool and of the client buckets
+
+/// the shard `value` belongs to
+pub fn shard_of<T: Hash + ?Sized>(value: &T) -> usize {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish() as usize % SHARDS
+}
+
+/// The pending transactions of each epoch, over all shards of the pool. Counts are taken while
+/// holding the lock of the shard that changes, and are read without locking any.
+#[derive(Debug, Default)]
+pub struct EpochCounts {
+    counts: RwLock<BTreeMap<u64, AtomicUsize>>,
+}
+
+impl EpochCounts {
+    pub fn get(&self, epoch: u64) -> usize {
+        self.counts
+            .read()
+            .get(&epoch)
+            .map_or(0, |count| count.load(Ordering::SeqCst))
+    }
+
+    /// Counts `n` more transactions for `epoch` if that keeps it within `capacity`, and returns
+    /// whether it did.
+    fn try_add(&self, epoch: u64, n: usize, capacity: usize) -> bool {
+        loop {
+            if let Some(count) = self.counts.read().get(&epoch) {
+                return count
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pending| {
+                        (pending + n <= capacity).then_some(pending + n)
+                    })
+                    .is_ok();
+            }
+            self.counts.write().entry(epoch).or_default();
+        }
+    }
+
+    /// Reserves a slot of the pool of `epoch`, holding at most `capacity` transactions.
+    pub fn reserve(&self, epoch: u64, capacity: usize) -> bool {
+        self.try_add(epoch, 1, capacity)
+    }
+
+    fn add(&self, epoch: u64, n: usize) {
+        self.try_add(epoch, n, usize::MAX);
+    }
+
+    fn sub(&self, epoch: u64, n: usize) {
+        if let Some(count) = self.counts.read().get(&epoch) {
+            count.fetch_sub(n, Ordering::SeqCst);
+        }
+    }
+
+    /// forgets the epochs up to `epoch` that have no transaction pending anymore
+    fn retire(&self, epoch: u64) {
+        self.counts
+            .write()
+            .retain(|ep, count| *ep > epoch || count.load(Ordering::SeqCst) > 0);
+    }
+
+    fn per_epoch(&self) -> BTreeMap<u64, usize> {
+        self.counts
+            .read()
+            .iter()
+            .map(|(epoch, count)| (*epoch, count.load(Ordering::SeqCst)))
+            .filter(|(_, pending)| *pending > 0)
+            .collect()
+    }
+}
+
+/// The transaction pool, split into shards by key that are each behind their own lock, the
+/// receipts of idempotency ids, split into shards by client and id, and the admission control of
+/// client transactions.
+#[derive(Debug)]
+pub struct ShardedPool {
+    shards: Vec<Mutex<TransactionPool>>,
+    counts: EpochCounts,
+    receipts: Vec<Mutex<Receipts>>,
+    receipt_window: Duration, // how long receipts are remembered
+    admission: Admission,
+    intake_epoch: AtomicU64, // the epoch new transactions are pooled for
+    closed: AtomicBool,      // no client transaction is admitted once the server drains
+}
+
+impl ShardedPool {
+    pub fn new(pool: PoolConfig, admission: AdmissionConfig) -> Self {
+        Self {
+            shards: (0..SHARDS)
+                .map(|_| Mutex::new(TransactionPool::with_config(pool.clone())))
+                .collect(),
+            counts: EpochCounts::default(),
+            receipts: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            receipt_window: Duration::from_millis(pool.idempotency_window_ms),
+            admission: Admission::new(admission),
+            intake_epoch: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn intake_epoch(&self) -> u64 {
+        self.intake_epoch.load(Ordering::SeqCst)
+    }
+
+    /// Pools new transactions for `epoch`. Transactions are inserted while holding the lock of
+    /// their shard, so once this returns, any transaction still pooled for an earlier epoch is
+    /// seen by whoever locks its shard next.
+    pub fn set_intake_epoch(&self, epoch: u64) {
+        self.intake_epoch.store(epoch, Ordering::SeqCst);
+    }
+
+    /// Stops admitting client transactions. Peer transactions are still pooled.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Admits the transaction of `client` and pools it for the intake epoch. Retries with a
+    /// remembered idempotency id get its receipt, without being admitted again. The shard of the
+    /// receipt is locked before the shard of the key, and held until the receipt is recorded, so
+    /// that concurrent retries are admitted once.
+    pub fn submit(&self, client: &str, req: &ClientTransactionRequest) -> Result<Receipt> {
+        if self.is_closed() {
+            return Err(anyhow!("The server is shutting down"));
+        }
+        let transaction = Transaction::from_client(req)?;
+        let (key, _) = transaction.operation.to_raw();
+        let id = req.idempotency_id.clone().unwrap_or_default();
+        let mut receipts = self.receipts[shard_of(&(client, &id))].lock();
+        if let Some(receipt) = receipts.get(client, req)? {
+            return Ok(receipt);
+        }
+        let mut pool = self.shards[shard_of(&key)].lock();
+        let epoch = self.intake_epoch();
+        let reserved = self.admission.admit(
+            client,
+            &pool,
+            &self.counts,
+            epoch,
+            &transaction,
+            req.idempotency_id.is_some(),
+            Instant::now(),
+        )?;
+        let receipt = pool.insert_client(epoch, client, req);
+        let inserted = receipt.as_ref().is_ok_and(|receipt| !receipt.duplicate);
+        if reserved && !inserted {
+            self.counts.sub(epoch, 1);
+        }
+        let receipt = receipt?;
+        receipts.insert(client, req, receipt);
+        Ok(receipt)
+    }
+
+    /// Pools the transaction a peer forwarded for its epoch, within the capacity of the pool of
+    /// that epoch, see `Admission::reserve`.
+    pub fn insert_peer(&self, req: PeerTransactionRequest) -> Result<Option<()>> {
+        let key = req.transaction.as_ref().map(|t| t.key.clone());
+        let epoch = req
+            .epoch
+            .as_ref()
+            .ok_or(anyhow!("An epoch number must be provided!"))?
+            .epoch;
+        let mut pool = self.shards[shard_of(&key.unwrap_or_default())].lock();
+        self.admission.reserve(&self.counts, epoch)?;
+        let res = pool.insert_peer(req);
+        if !matches!(res, Ok(None)) {
+            self.counts.sub(epoch, 1);
+        }
+        res
+    }
+
+    /// the number of transactions pending for `epoch`
+    pub fn len(&self, epoch: u64) -> usize {
+        self.counts.get(epoch)
+    }
+
+    /// the number of transactions pending for each epoch that has some
+    pub fn pending_per_epoch(&self) -> BTreeMap<u64, usize> {
+        self.counts.per_epoch()
+    }
+
+    /// the transactions of `epoch`, pending or committed within the retained epochs
+    pub fn get_epoch(&self, epoch: u64) -> Vec<TransactionRequest> {
+        self.shards
+            .iter()
+            .flat_map(|pool| {
+                pool.lock()
+                    .get_epoch(epoch)
+                    .into_iter()
+                    .map(TransactionRequest::from)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// the transactions pending for `epoch`, to be sent to the inner provider when it is committed
+    pub fn pending(&self, epoch: u64) -> HashSet<Transaction> {
+        self.shards
+            .iter()
+            .flat_map(|pool| pool.lock().pending(epoch))
+            .collect()
+    }
+
+    /// the oldest committed epoch whose transactions every shard retains
+    pub fn oldest_committed(&self) -> Option<u64> {
+        self.shards
+            .iter()
+            .filter_map(|pool| pool.lock().oldest_committed())
+            .max()
+    }
+
+    /// the transactions received from clients that are pending for any epoch, with their epoch
+    pub fn pending_client(&self) -> Vec<(u64, TransactionRequest)> {
+        self.shards
+            .iter()
+            .flat_map(|pool| {
+                pool.lock()
+                    .pending_client()
+                    .into_iter()
+                    .map(|(epoch, t)| (epoch, TransactionRequest::from(t)))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// See `TransactionPool::commit`. The receipts of the transactions pooled again follow them.
+    pub fn commit(&self, epoch: u64, sent: &HashSet<Transaction>) {
+        let mut repooled = Repooled::default();
+        for shard in &self.shards {
+            let mut pool = shard.lock();
+            let before: Vec<(u64, usize)> = pool.pending_per_epoch().collect();
+            let res = pool.commit(epoch, sent);
+            repooled.moved.extend(res.moved);
+            repooled.dropped.extend(res.dropped);
+            let after: Vec<(u64, usize)> = pool.pending_per_epoch().collect();
+            // added first, so that the counts never fall below what the shards hold
+            for (ep, pending) in after {
+                self.counts.add(ep, pending);
+            }
+            for (ep, pending) in before {
+                self.counts.sub(ep, pending);
+            }
+        }
+        self.counts.retire(epoch);
+        for key in repooled.moved {
+            self.receipts[shard_of(&(&key.0, &key.1))]
+                .lock()
+                .move_to(&key, epoch + 1);
+        }
+        for key in repooled.dropped {
+            self.receipts[shard_of(&(&key.0, &key.1))]
+                .lock()
+                .remove(&key);
+        }
+    }
+
+    /// Forgets the receipts older than the idempotency window. Pending transactions expire by
+    /// epochs instead, when `commit` drops them.
+    pub fn expire(&self, now: Instant) {
+        for receipts in &self.receipts {
+            receipts.lock().expire(now, self.receipt_window);
+        }
+    }
+
+    pub fn admission_state(&self) -> AdmissionState {
+        self.admission.state(self.counts.get(self.intake_epoch()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc_handler::inner::mversegrpc::transaction_request::TransactionType;
+    use crate::grpc_handler::inner::mversegrpc::Epoch;
+    use crate::server::admission::{AdmissionError, Rejection};
+
+    fn request(key: u32) -> ClientTransactionRequest {
+        ClientTransactionRequest {
+            transaction: Some(TransactionRequest {
+                key: key.to_be_bytes().to_vec(),
+                value: Some(vec![1]),
+                transaction_type: TransactionType::Update.into(),
+            }),
+            auxiliary: None,
+            wait: false,
+            idempotency_id: Some(format!("t{}", key)),
+        }
+    }
+
+    #[test]
+    fn tst_sharded_pool() -> Result<()> {
+        let pool = ShardedPool::new(
+            PoolConfig::default(),
+            AdmissionConfig {
+                client_burst: 1000,
+                pool_capacity: 400,
+                ..Default::default()
+            },
+        );
+        std::thread::scope(|s| {
+            for client in 0..4u32 {
+                let pool = &pool;
+                s.spawn(move || {
+                    for i in 0..100 {
+                        let receipt = pool.submit(&client.to_string(), &request(client * 100 + i));
+                        assert!(!receipt.unwrap().duplicate);
+                    }
+                });
+            }
+        });
+        assert_eq!(pool.len(0), 400);
+        assert!(pool.submit("0", &request(400)).is_err());
+
+        // peers are held to the same capacity, and are told when the pool is full
+        let peer = PeerTransactionRequest {
+            epoch: Some(Epoch { epoch: 0 }),
+            transaction: request(400).transaction,
+            server_id: "srv_1".into(),
+            signature: vec![],
+            auxiliary: None,
+        };
+        let err = pool.insert_peer(peer).unwrap_err();
+        let rejection = err.downcast_ref::<AdmissionError>().unwrap();
+        assert_eq!(rejection.reason, Rejection::PoolFull);
+        assert_eq!(pool.len(0), 400);
+
+        // transactions pooled after the intake moves on belong to the next epoch, its capacity
+        // is its own, and retries are recognized whatever key they write
+        pool.set_intake_epoch(1);
+        assert!(pool.submit("0", &request(7))?.replayed);
+        let mut other = request(8);
+        other.idempotency_id = Some("t7".into());
+        assert!(pool.submit("0", &other).is_err());
+        pool.submit("0", &request(400))?;
+        assert_eq!(pool.get_epoch(0).len(), 400);
+        pool.commit(0, &pool.pending(0));
+        assert_eq!((pool.len(0), pool.len(1)), (0, 1));
+        assert_eq!(pool.pending_per_epoch(), BTreeMap::from([(1, 1)]));
+
+        // a transaction pooled for the epoch after it was sent to the inner provider is not
+        // committed with it, it moves on to the next epoch along with its receipt
+        let sent = pool.pending(1);
+        pool.submit("1", &request(402))?;
+        pool.set_intake_epoch(2);
+        pool.commit(1, &sent);
+        assert_eq!((pool.get_epoch(1).len(), pool.len(2)), (1, 1));
+        assert_eq!(pool.submit("1", &request(402))?.epoch, 2);
+        let mut late = request(402);
+        late.idempotency_id = None;
+        let resubmitted = pool.submit("1", &late)?;
+        assert_eq!((resubmitted.epoch, resubmitted.duplicate), (2, true));
+        assert_eq!(pool.admission_state().tracked_clients, 4);
+
+        // a draining pool hands over what it holds, and admits nothing new
+        pool.close();
+        assert!(pool.submit("0", &request(401)).is_err());
+        assert_eq!(pool.pending_client().len(), 1);
+        Ok(())
+    }
+}
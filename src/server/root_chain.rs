@@ -0,0 +1,108 @@
+use crate::grpc_handler::outer::mverseouter::RootChain;
+use crate::server::MerkleVerseServer;
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+/// the chain digest preceding the first epoch a server commits
+pub const GENESIS_CHAIN: [u8; 32] = [0u8; 32];
+
+/// The chain digest of `epoch`, committing to its root and, through `previous`, to the roots of
+/// every earlier epoch. Epoch certificates are signed over this digest.
+pub fn chain_digest(previous: &[u8], epoch: u64, root: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(previous);
+    hasher.update(epoch.to_be_bytes());
+    hasher.update(root);
+    hasher.finalize().to_vec()
+}
+
+/// Verifies that the chain certified at `to_epoch` extends the chain certified at `from_epoch`
+/// by exactly `roots`, i.e. that no root between the two epochs was rewritten. This is a history
+/// of roots, not a consistency proof: it says nothing about how the tree changed from one root
+/// to the next, so it does not prove that a tree kept every earlier entry.
+pub fn verify_root_chain(
+    from_epoch: u64,
+    from_chain: &[u8],
+    to_epoch: u64,
+    to_chain: &[u8],
+    roots: &[Vec<u8>],
+) -> Result<()> {
+    if to_epoch < from_epoch {
+        return Err(anyhow!(
+            "Epoch {} does not follow epoch {}",
+            to_epoch,
+            from_epoch
+        ));
+    }
+    if roots.len() as u64 != to_epoch - from_epoch {
+        return Err(anyhow!(
+            "Expected {} roots between epoch {} and {}, got {}",
+            to_epoch - from_epoch,
+            from_epoch,
+            to_epoch,
+            roots.len()
+        ));
+    }
+    let chain = roots
+        .iter()
+        .zip(from_epoch + 1..)
+        .fold(from_chain.to_vec(), |chain, (root, epoch)| {
+            chain_digest(&chain, epoch, root)
+        });
+    if chain != to_chain {
+        return Err(anyhow!(
+            "The chain of epoch {} does not extend the chain of epoch {}",
+            to_epoch,
+            from_epoch
+        ));
+    }
+    Ok(())
+}
+
+impl MerkleVerseServer {
+    /// The roots linking the chain of `from_epoch` to the chain of `to_epoch`, if both epochs are
+    /// still retained.
+    pub fn root_chain(&self, from_epoch: u64, to_epoch: u64) -> Option<RootChain> {
+        let view = self.certificates.view();
+        let history = &view.history;
+        let from = history.get(from_epoch)?;
+        let to = history.get(to_epoch)?;
+        Some(RootChain {
+            from_epoch,
+            to_epoch,
+            from_chain: from.chain.clone(),
+            to_chain: to.chain.clone(),
+            roots: history
+                .since(from_epoch + 1)
+                .take_while(|r| r.epoch <= to_epoch)
+                .map(|r| r.root.clone())
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tst_root_chain() -> Result<()> {
+        let roots: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 4]).collect();
+        let chains: Vec<Vec<u8>> = roots
+            .iter()
+            .zip(0..)
+            .scan(GENESIS_CHAIN.to_vec(), |chain, (root, epoch)| {
+                *chain = chain_digest(chain, epoch, root);
+                Some(chain.clone())
+            })
+            .collect();
+
+        verify_root_chain(1, &chains[1], 4, &chains[4], &roots[2..5])?;
+        verify_root_chain(2, &chains[2], 2, &chains[2], &[])?;
+        assert!(verify_root_chain(1, &chains[1], 4, &chains[4], &roots[1..4]).is_err());
+        let mut forked = roots[2..5].to_vec();
+        forked[1] = vec![9; 4];
+        assert!(verify_root_chain(1, &chains[1], 4, &chains[4], &forked).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,311 @@
+use crate::grpc_handler::outer::mverseouter::{KeyAnnouncement, KeyAnnouncements};
+use crate::server::synchronization::MerkleVerseServerState;
+use crate::server::{MerkleVerseServer, PeerServer, PrivateKey, PublicKey, ServerId};
+use anyhow::{anyhow, Result};
+use bls_signatures::{Serialize, Signature};
+use ed25519_dalek::{Signer, Verifier};
+use std::collections::{BTreeMap, HashMap};
+
+const KEY_ACTIVATION_DELAY: u64 = 4; // number of epochs between announcing a key and signing with it
+const ANNOUNCEMENT_DOMAIN: &[u8] = b"merkleverse-key-announcement";
+
+/// the bytes signed by both the previous and the announced key
+fn announcement_message(ann: &KeyAnnouncement) -> Vec<u8> {
+    let mut msg = ANNOUNCEMENT_DOMAIN.to_vec();
+    for field in [
+        ann.server_id.as_bytes(),
+        ann.bls_pub_key.as_slice(),
+        ann.dalek_pub_key.as_slice(),
+    ] {
+        msg.extend_from_slice(&(field.len() as u64).to_be_bytes());
+        msg.extend_from_slice(field);
+    }
+    msg.extend_from_slice(&ann.activation_epoch.to_be_bytes());
+    msg
+}
+
+/// Builds the announcement of `new_key` for `server_id`, signed by the `previous` key of the server.
+pub fn announce(
+    server_id: &ServerId,
+    previous: &PrivateKey,
+    new_key: &PrivateKey,
+    activation_epoch: u64,
+) -> KeyAnnouncement {
+    let public_key = new_key.public_key();
+    let mut ann = KeyAnnouncement {
+        server_id: server_id.0.clone(),
+        bls_pub_key: public_key.bls.as_bytes(),
+        dalek_pub_key: public_key.dalek.as_bytes().to_vec(),
+        activation_epoch,
+        signature: vec![],
+        proof_of_possession: vec![],
+    };
+    let msg = announcement_message(&ann);
+    ann.signature = previous.dalek.sign(&msg).to_bytes().to_vec();
+    ann.proof_of_possession = new_key.bls.sign(&msg).as_bytes();
+    ann
+}
+
+/// Checks that `ann` was signed by the `previous` key of its server, and that the announcer holds
+/// the new BLS key, so that it cannot be used to forge multi-signatures. Returns the new key.
+pub fn verify_announcement(ann: &KeyAnnouncement, previous: &PublicKey) -> Result<PublicKey> {
+    let public_key = PublicKey::new(&ann.bls_pub_key, &ann.dalek_pub_key)?;
+    let msg = announcement_message(ann);
+    let sig = ed25519_dalek::Signature::try_from(ann.signature.as_slice())?;
+    previous
+        .dalek
+        .verify(&msg, &sig)
+        .map_err(|e| anyhow!("Key announcement is not signed by the previous key: {}", e))?;
+    if !public_key
+        .bls
+        .verify(Signature::from_bytes(&ann.proof_of_possession)?, &msg)
+    {
+        return Err(anyhow!(
+            "Key announcement carries an invalid proof of possession"
+        ));
+    }
+    Ok(public_key)
+}
+
+/// The keys servers rotated to, by activation epoch, on top of the keys of the configuration.
+#[derive(Debug, Default)]
+pub struct KeyRegistry {
+    rotations: HashMap<ServerId, BTreeMap<u64, (PublicKey, KeyAnnouncement)>>,
+    signing_keys: BTreeMap<u64, PrivateKey>, // the rotated keys of this server
+}
+
+impl KeyRegistry {
+    /// the key `server` rotated to that is valid at `epoch`, if any
+    pub fn key_at(&self, server: &ServerId, epoch: u64) -> Option<&PublicKey> {
+        self.rotations
+            .get(server)?
+            .range(..=epoch)
+            .next_back()
+            .map(|(_, (key, _))| key)
+    }
+
+    /// the latest key `server` rotated to, and its activation epoch
+    pub fn latest(&self, server: &ServerId) -> Option<(u64, &PublicKey)> {
+        self.rotations
+            .get(server)?
+            .iter()
+            .next_back()
+            .map(|(epoch, (key, _))| (*epoch, key))
+    }
+
+    pub fn announcement(&self, server: &ServerId, epoch: u64) -> Option<&KeyAnnouncement> {
+        self.rotations.get(server)?.get(&epoch).map(|(_, ann)| ann)
+    }
+
+    pub fn record(&mut self, server: ServerId, key: PublicKey, ann: KeyAnnouncement) {
+        self.rotations
+            .entry(server)
+            .or_default()
+            .insert(ann.activation_epoch, (key, ann));
+    }
+
+    pub fn announcements(&self) -> Vec<KeyAnnouncement> {
+        self.rotations
+            .values()
+            .flat_map(|r| r.values().map(|(_, ann)| ann.clone()))
+            .collect()
+    }
+
+    pub fn signing_key_at(&self, epoch: u64) -> Option<&PrivateKey> {
+        self.signing_keys
+            .range(..=epoch)
+            .next_back()
+            .map(|(_, key)| key)
+    }
+
+    pub fn add_signing_key(&mut self, activation_epoch: u64, key: PrivateKey) {
+        self.signing_keys.insert(activation_epoch, key);
+    }
+}
+
+impl MerkleVerseServer {
+    /// the key `server` was configured with, or joined the cluster with
+    fn configured_key(&self, server: &ServerId) -> Option<PublicKey> {
+        self.configured_key_in(&self.state.lock(), server)
+    }
+
+    /// `configured_key`, for callers already holding the state lock
+    pub(super) fn configured_key_in(
+        &self,
+        state: &MerkleVerseServerState,
+        server: &ServerId,
+    ) -> Option<PublicKey> {
+        if *server == self.id {
+            return Some(self.public_key.clone());
+        }
+        let membership = state.membership();
+        [membership.parallel(), state.gossip_peers()]
+            .into_iter()
+            .flatten()
+            .find_map(|cluster| cluster.get_server(server))
+            .map(|srv| srv.public_key.clone())
+            .or_else(|| membership.recorded_key(server))
+    }
+
+    /// the key `server` signs with at `epoch`, taking announced rotations into account
+    pub fn public_key_at(&self, server: &ServerId, epoch: u64) -> Option<PublicKey> {
+        let rotated = self
+            .state
+            .lock()
+            .keys()
+            .key_at(server, epoch)
+            .cloned();
+        rotated.or_else(|| self.configured_key(server))
+    }
+
+    /// the key this server signs with at `epoch`
+    pub fn signing_key(&self, epoch: u64) -> PrivateKey {
+        self.signing_key_in(&self.state.lock(), epoch)
+    }
+
+    /// `signing_key`, for callers already holding the state lock
+    pub(super) fn signing_key_in(&self, state: &MerkleVerseServerState, epoch: u64) -> PrivateKey {
+        state
+            .keys()
+            .signing_key_at(epoch)
+            .cloned()
+            .unwrap_or_else(|| self.private_key.clone())
+    }
+
+    /// Verifies and records the key announced by another server, and persists it in the data
+    /// directory. Returns whether it was new. A key must be announced before the epoch it
+    /// activates at, so that nothing already signed can be attributed to it.
+    pub fn receive_key_announcement(&self, ann: KeyAnnouncement) -> Result<bool> {
+        let server = ServerId(ann.server_id.clone());
+        let configured = self
+            .configured_key(&server)
+            .ok_or(anyhow!("Server {} is not known to this server", server.0))?;
+        let mut serv_state = self.state.lock();
+        let keys = serv_state.keys_mut();
+        if let Some(known) = keys.announcement(&server, ann.activation_epoch) {
+            return match *known == ann {
+                true => Ok(false),
+                false => Err(anyhow!(
+                    "Server {} already announced another key for epoch {}",
+                    server.0,
+                    ann.activation_epoch
+                )),
+            };
+        }
+        let current_epoch = self.current_epoch();
+        if ann.activation_epoch <= current_epoch {
+            return Err(anyhow!(
+                "Key of server {} activates at epoch {}, which is not after the current epoch {}",
+                server.0,
+                ann.activation_epoch,
+                current_epoch
+            ));
+        }
+        let previous = match keys.latest(&server) {
+            Some((epoch, _)) if epoch > ann.activation_epoch => {
+                return Err(anyhow!(
+                    "Server {} already rotated its key at epoch {}",
+                    server.0,
+                    epoch
+                ))
+            }
+            Some((_, key)) => key.clone(),
+            None => configured,
+        };
+        let key = verify_announcement(&ann, &previous)?;
+        let mut announcements = keys.announcements();
+        announcements.push(ann.clone());
+        self.data_dir
+            .save_announcements(&KeyAnnouncements { announcements })?;
+        tracing::info!(
+            "Server {} rotates its key at epoch {}",
+            server.0,
+            ann.activation_epoch
+        );
+        keys.record(server, key, ann);
+        Ok(true)
+    }
+
+    /// Records the key announcements persisted before a restart. They were verified when they
+    /// were received, and may have activated since.
+    pub(super) fn restore_key_announcements(&self) -> Result<()> {
+        let announcements = self.data_dir.load_announcements()?.announcements;
+        let mut serv_state = self.state.lock();
+        for ann in &announcements {
+            let key = PublicKey::new(&ann.bls_pub_key, &ann.dalek_pub_key)?;
+            serv_state
+                .keys_mut()
+                .record(ServerId(ann.server_id.clone()), key, ann.clone());
+        }
+        if !announcements.is_empty() {
+            tracing::info!("Restored {} key announcements", announcements.len());
+        }
+        Ok(())
+    }
+
+    /// Starts signing with `new_key` a few epochs from now, and announces it to every known server.
+    /// Returns the activation epoch.
+    pub async fn rotate_key(&self, new_key: PrivateKey) -> Result<u64> {
+        let activation_epoch = self.current_epoch() + KEY_ACTIVATION_DELAY;
+        let previous = self.signing_key(u64::MAX);
+        if previous.public_key() == new_key.public_key() {
+            return Err(anyhow!("The new key is the key currently in use"));
+        }
+        let ann = announce(&self.id, &previous, &new_key, activation_epoch);
+        self.receive_key_announcement(ann.clone())?;
+        self.state
+            .lock()
+            .keys_mut()
+            .add_signing_key(activation_epoch, new_key);
+
+        let mut targets: HashMap<ServerId, PeerServer> = HashMap::new();
+        for cluster in [self.parallel(), self.gossip_peers()]
+            .into_iter()
+            .flatten()
+        {
+            for srv in cluster.servers.values() {
+                targets.insert(srv.id.clone(), srv.clone());
+            }
+        }
+        self.broadcast("key announcement", targets.into_values(), |mut client| {
+            let ann = ann.clone();
+            async move { client.announce_key(ann).await }
+        })
+        .await;
+        Ok(activation_epoch)
+    }
+
+    pub fn key_announcements(&self) -> KeyAnnouncements {
+        KeyAnnouncements {
+            announcements: self.state.lock().keys().announcements(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tst_key_rotation() -> Result<()> {
+        let server = ServerId("srv_0".into());
+        let (old, new) = (PrivateKey::from([1u8; 32]), PrivateKey::from([2u8; 32]));
+        let ann = announce(&server, &old, &new, 5);
+        assert_eq!(
+            verify_announcement(&ann, &old.public_key())?,
+            new.public_key()
+        );
+        assert!(verify_announcement(&ann, &new.public_key()).is_err());
+
+        let mut forged = ann.clone();
+        forged.activation_epoch = 6;
+        assert!(verify_announcement(&forged, &old.public_key()).is_err());
+
+        let mut registry = KeyRegistry::default();
+        registry.record(server.clone(), new.public_key(), ann);
+        assert!(registry.key_at(&server, 4).is_none());
+        assert_eq!(registry.key_at(&server, 5), Some(&new.public_key()));
+        assert_eq!(registry.key_at(&server, 9), Some(&new.public_key()));
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use crate::grpc_handler::inner::mversegrpc::Epoch;
+use crate::grpc_handler::outer::mverseouter::{PeerLeavingRequest, PeerTransactionRequest};
+use crate::server::synchronization::RunState;
+use crate::server::{MerkleVerseServer, ServerId};
+use anyhow::Result;
+use std::time::Duration;
+use tokio::time::Instant;
+use tonic::IntoRequest;
+
+const DRAIN_POLL_MS: u64 = 50; // how often a drain checks whether the epoch in flight finished
+
+impl MerkleVerseServer {
+    /// whether the server is shutting down, and no longer admits client transactions
+    pub fn is_draining(&self) -> bool {
+        self.pool.is_closed()
+    }
+
+    /// Drains the server before it exits. Client transactions are no longer admitted and no new
+    /// epoch is prepared. The prepare or commit in flight may finish until the deadline. The
+    /// client transactions still pending are then handed to the parallel servers, and these are
+    /// told that this server is leaving. Whatever is left at the deadline is abandoned.
+    pub async fn drain(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        self.pool.close();
+        self.state.lock().set_ready(false);
+        tracing::info!("Draining, client transactions are no longer admitted");
+        if !self.wait_for_commit(deadline).await {
+            tracing::warn!(
+                "Aborting epoch {}, it was not committed within the drain timeout",
+                self.current_epoch()
+            );
+        }
+        let notify = async {
+            self.hand_off_pool().await;
+            self.announce_leaving().await;
+        };
+        if tokio::time::timeout_at(deadline, notify).await.is_err() {
+            tracing::warn!("Drain timeout reached before the parallel servers were notified");
+        }
+    }
+
+    /// Waits until no epoch is being prepared or committed, and returns whether that happened
+    /// before `deadline`.
+    async fn wait_for_commit(&self, deadline: Instant) -> bool {
+        loop {
+            if matches!(self.state.lock().run_state(), RunState::Normal) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(DRAIN_POLL_MS)).await;
+        }
+    }
+
+    /// Sends the client transactions still pending to the parallel servers, signed for the epoch
+    /// they were pooled for. Those a server already received when they were forwarded are
+    /// duplicates there.
+    async fn hand_off_pool(&self) {
+        let pending = self.pool.pending_client();
+        if pending.is_empty() {
+            return;
+        }
+        let Some(servers) = self.parallel() else {
+            tracing::warn!(
+                "Dropping {} pending transactions, there is no parallel server to hand them to",
+                pending.len()
+            );
+            return;
+        };
+        let requests = pending
+            .into_iter()
+            .map(|(epoch, transaction)| {
+                Ok(PeerTransactionRequest {
+                    signature: self.sign_transaction(
+                        &transaction,
+                        epoch,
+                        &self.signing_key(epoch),
+                    )?,
+                    transaction: Some(transaction),
+                    server_id: self.id.0.clone(),
+                    epoch: Some(Epoch { epoch }),
+                    auxiliary: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>();
+        let requests = match requests {
+            Ok(requests) => requests,
+            Err(e) => {
+                tracing::error!("Failed to sign the pending transactions: {}", e);
+                return;
+            }
+        };
+        let outcome = self
+            .broadcast(
+                "pool handoff",
+                servers.servers.into_values(),
+                |mut client| {
+                    let requests = requests.clone();
+                    async move {
+                        for request in requests {
+                            client.peer_transaction(request).await?;
+                        }
+                        Ok(())
+                    }
+                },
+            )
+            .await;
+        tracing::info!(
+            "Handed {} pending transactions over, {}",
+            requests.len(),
+            outcome.summary()
+        );
+    }
+
+    /// Tells the parallel servers that this server is shutting down.
+    async fn announce_leaving(&self) {
+        let Some(servers) = self.parallel() else {
+            return;
+        };
+        let epoch = self.current_epoch();
+        let outcome = self
+            .broadcast(
+                "leave notice",
+                servers.servers.into_values(),
+                |mut client| {
+                    let request = self.sign_rpc(PeerLeavingRequest {
+                        epoch: Some(Epoch { epoch }),
+                        peer_identity: Some(self.server_identity()),
+                        auth: None,
+                    });
+                    async move { client.peer_leaving(request.into_request()).await }
+                },
+            )
+            .await;
+        tracing::info!("Announced the shutdown, {}", outcome.summary());
+    }
+
+    /// Records that `server_id` is shutting down, so broadcasts to it are not retried.
+    pub fn receive_leaving(&self, server_id: ServerId) -> Result<()> {
+        tracing::info!("Server {} is shutting down", server_id.0);
+        self.state.lock().mark_leaving(&server_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::synchronization::MerkleVerseServerState;
+    use crate::server::ServerId;
+
+    #[test]
+    fn tst_peer_leaving() {
+        let mut state = MerkleVerseServerState::new();
+        let peer = ServerId("s1".into());
+        state.add_peer(peer.clone()).unwrap();
+        state.mark_leaving(&ServerId("unknown".into()));
+        assert!(!state.is_leaving(&ServerId("unknown".into())));
+
+        // a failed broadcast keeps it leaving, its next answer means it is back
+        state.mark_leaving(&peer);
+        state.record_broadcast(&peer, Some(&"down".to_string()));
+        assert!(state.is_leaving(&peer));
+        state.record_broadcast(&peer, None);
+        assert!(!state.is_leaving(&peer));
+    }
+}
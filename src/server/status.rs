@@ -0,0 +1,121 @@
+use crate::grpc_handler::inner::mversegrpc;
+use crate::grpc_handler::outer::mverseouter::run_state::Phase;
+use crate::grpc_handler::outer::mverseouter::{
+    ClusterMember, EpochPool, PeerStatus, RunState as ProtoRunState, ServerStatus,
+};
+use crate::server::synchronization::RunState;
+use crate::server::{MerkleVerseServer, ServerCluster};
+use anyhow::Result;
+use tokio::sync::watch::Receiver;
+
+impl From<RunState> for ProtoRunState {
+    fn from(state: RunState) -> Self {
+        match state {
+            RunState::Normal => ProtoRunState {
+                phase: Phase::Normal.into(),
+                epoch: 0,
+            },
+            RunState::Prepare(epoch) => ProtoRunState {
+                phase: Phase::Prepare.into(),
+                epoch,
+            },
+        }
+    }
+}
+
+/// the servers of `cluster`, ordered by id
+fn members(cluster: Option<ServerCluster>) -> Vec<ClusterMember> {
+    let mut members: Vec<ClusterMember> = cluster
+        .into_iter()
+        .flat_map(|cluster| cluster.servers.into_values())
+        .map(|srv| ClusterMember {
+            server_id: srv.id.0,
+            connection_string: srv.connection_string,
+        })
+        .collect();
+    members.sort_by(|a, b| a.server_id.cmp(&b.server_id));
+    members
+}
+
+impl MerkleVerseServer {
+    /// a receiver that is notified every time the server becomes ready or stops being ready
+    pub fn ready_receiver(&self) -> Receiver<bool> {
+        self.state.lock().ready_receiver()
+    }
+
+    async fn probe_inner(&self) -> Result<()> {
+        self.get_inner_client()?
+            .get_current_root(mversegrpc::Empty {})
+            .await?;
+        Ok(())
+    }
+
+    /// The detailed state of this server. The inner provider is probed on every call.
+    pub async fn status(&self) -> ServerStatus {
+        let inner = self.probe_inner().await;
+        let (ready, run_state, since_last_commit, mut peers) = {
+            let serv_state = self.state.lock();
+            let peers: Vec<PeerStatus> = serv_state
+                .peer_states()
+                .iter()
+                .map(|(id, peer)| PeerStatus {
+                    server_id: id.0.clone(),
+                    run_state: Some(peer.run_state.into()),
+                    failures: peer.failures,
+                    last_error: peer.last_error.clone().unwrap_or_default(),
+                    leaving: peer.leaving,
+                })
+                .collect();
+            (
+                serv_state.is_ready(),
+                serv_state.run_state(),
+                serv_state.last_commit_time().map(|t| t.elapsed()),
+                peers,
+            )
+        };
+        peers.sort_by(|a, b| a.server_id.cmp(&b.server_id));
+        ServerStatus {
+            server_id: self.id.0.clone(),
+            ready,
+            draining: self.is_draining(),
+            current_epoch: self.current_epoch(),
+            run_state: Some(run_state.into()),
+            pool: self
+                .pool
+                .pending_per_epoch()
+                .into_iter()
+                .map(|(epoch, pending)| EpochPool {
+                    epoch,
+                    pending: pending as u64,
+                })
+                .collect(),
+            peers,
+            since_last_commit_ms: since_last_commit.map(|d| d.as_millis() as u64),
+            inner_reachable: inner.is_ok(),
+            inner_error: inner.err().map(|e| e.to_string()).unwrap_or_default(),
+            superior: members(self.superior()),
+            parallel: members(self.parallel()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::synchronization::MerkleVerseServerState;
+
+    #[test]
+    fn tst_readiness() {
+        let mut state = MerkleVerseServerState::new();
+        let mut ready = state.ready_receiver();
+        assert!(!state.is_ready());
+        state.set_ready(true);
+        assert!(ready.has_changed().unwrap() && *ready.borrow_and_update());
+        state.set_ready(false);
+        assert!(!state.is_ready());
+
+        let prepare = ProtoRunState::from(RunState::Prepare(4));
+        assert_eq!((prepare.phase(), prepare.epoch), (Phase::Prepare, 4));
+        assert_eq!(ProtoRunState::from(RunState::Normal).phase(), Phase::Normal);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::grpc_handler::inner::mversegrpc::Epoch;
+use crate::grpc_handler::outer::mverseouter::{KeyAnnouncements, RootUpdate};
+use crate::server::history::EpochRecord;
+use anyhow::{anyhow, Context, Result};
+use prost::Message;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const LAST_COMMIT_FILE: &str = "last_commit.pb";
+const KEY_ANNOUNCEMENTS_FILE: &str = "key_announcements.pb";
+
+/// The directory holding what a server must not forget across restarts. Each file is replaced
+/// atomically, so a crash leaves either the previous or the new content.
+#[derive(Debug, Clone)]
+pub struct DataDir {
+    path: PathBuf,
+}
+
+impl DataDir {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path.join(name);
+        match std::fs::read(&path) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    fn write(&self, name: &str, content: &[u8]) -> Result<()> {
+        std::fs::create_dir_all(&self.path)
+            .with_context(|| format!("Failed to create {}", self.path.display()))?;
+        let path = self.path.join(name);
+        let tmp = self.path.join(format!("{}.tmp", name));
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    /// the last epoch this server committed, with its root and chain digest
+    pub fn load_commit(&self) -> Result<Option<EpochRecord>> {
+        let Some(content) = self.read(LAST_COMMIT_FILE)? else {
+            return Ok(None);
+        };
+        let update = RootUpdate::decode(content.as_slice())?;
+        Ok(Some(EpochRecord {
+            epoch: update
+                .epoch
+                .ok_or(anyhow!("{} has no epoch", LAST_COMMIT_FILE))?
+                .epoch,
+            root: update.head,
+            chain: update.chain,
+        }))
+    }
+
+    /// Records `record` as the last epoch committed, before its chain digest is signed, so that
+    /// the chain continues from it after a restart.
+    pub fn save_commit(&self, record: &EpochRecord) -> Result<()> {
+        let update = RootUpdate {
+            epoch: Some(Epoch {
+                epoch: record.epoch,
+            }),
+            head: record.root.clone(),
+            certificate: None,
+            chain: record.chain.clone(),
+        };
+        self.write(LAST_COMMIT_FILE, &update.encode_to_vec())
+    }
+
+    /// the key announcements this server verified before it restarted
+    pub fn load_announcements(&self) -> Result<KeyAnnouncements> {
+        match self.read(KEY_ANNOUNCEMENTS_FILE)? {
+            Some(content) => Ok(KeyAnnouncements::decode(content.as_slice())?),
+            None => Ok(KeyAnnouncements::default()),
+        }
+    }
+
+    pub fn save_announcements(&self, announcements: &KeyAnnouncements) -> Result<()> {
+        self.write(KEY_ANNOUNCEMENTS_FILE, &announcements.encode_to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tst_last_commit() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("mverse-store-{}", std::process::id()));
+        let store = DataDir::new(dir.clone());
+        assert!(store.load_commit()?.is_none());
+
+        for epoch in [3, 4] {
+            store.save_commit(&EpochRecord {
+                epoch,
+                root: vec![epoch as u8],
+                chain: vec![0xc0, epoch as u8],
+            })?;
+        }
+        let record = store.load_commit()?.unwrap();
+        assert_eq!((record.epoch, record.root, record.chain), (4, vec![4], vec![0xc0, 4]));
+
+        assert!(store.load_announcements()?.announcements.is_empty());
+        let announcements = KeyAnnouncements {
+            announcements: vec![Default::default()],
+        };
+        store.save_announcements(&announcements)?;
+        assert_eq!(store.load_announcements()?, announcements);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+use crate::grpc_handler::inner::mversegrpc::Epoch;
+use crate::grpc_handler::outer::mverseouter::{EpochCertificate, KeyUpdate, RootUpdate};
+use crate::server::history::EpochRecord;
+use crate::server::certificates::{quorum, CommittedView, MultiSig};
+use crate::server::pool::ShardedPool;
+use crate::server::{Index, MerkleVerseServer};
+use bls_signatures::Serialize;
+use std::collections::{BTreeSet, HashSet};
+use std::ops::Range;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+
+const WATCH_BUFFER: usize = 16; // number of updates buffered for each subscriber before it applies backpressure
+const QUORUM_WAIT_EPOCHS: u32 = 3; // epoch intervals a root waits for a quorum of signers before it is sent without
+
+impl From<&MultiSig> for EpochCertificate {
+    fn from(multi_sig: &MultiSig) -> Self {
+        let (signers, signatures) = multi_sig
+            .signatures
+            .iter()
+            .map(|(id, sig)| (id.0.clone(), sig.as_bytes()))
+            .unzip();
+        Self {
+            aggregate_signature: multi_sig.aggregate.as_bytes(),
+            signers,
+            signatures,
+        }
+    }
+}
+
+/// The set of keys and key prefixes a `WatchKeys` subscriber is interested in.
+#[derive(Debug, Clone)]
+struct KeyFilter {
+    keys: HashSet<Vec<u8>>,
+    prefixes: Vec<Index>,
+    length: u32,
+}
+
+impl KeyFilter {
+    fn new(keys: Vec<Vec<u8>>, prefixes: Vec<(Vec<u8>, u32)>, length: u32) -> Self {
+        Self {
+            keys: keys.into_iter().collect(),
+            prefixes: prefixes
+                .into_iter()
+                .map(|(index, length)| Index { index, length })
+                .collect(),
+            length,
+        }
+    }
+
+    fn matches(&self, key: &[u8]) -> bool {
+        self.keys.contains(key)
+            || self
+                .prefixes
+                .iter()
+                .any(|p| p.is_prefix_of(key, self.length).unwrap_or(false))
+    }
+}
+
+/// The records that can be sent: those up to the latest one `certified` by a quorum of signers,
+/// or all of them once the wait for a quorum `stalled`.
+fn publishable(
+    records: &[EpochRecord],
+    certified: impl Fn(&EpochRecord) -> bool,
+    stalled: bool,
+) -> &[EpochRecord] {
+    if stalled {
+        return records;
+    }
+    let end = records.iter().rposition(certified).map_or(0, |i| i + 1);
+    &records[..end]
+}
+
+/// The epochs a `WatchKeys` subscriber waiting for `next` catches up on, up to `committed`, or
+/// `None` if `pool` no longer retains the transactions of `next`.
+fn catch_up(pool: &ShardedPool, next: u64, committed: u64) -> Option<Range<u64>> {
+    match pool.oldest_committed() {
+        Some(oldest) if next < oldest && next < committed => None,
+        _ => Some(next..committed),
+    }
+}
+
+impl MerkleVerseServer {
+    fn root_update(view: &CommittedView, record: &EpochRecord) -> RootUpdate {
+        RootUpdate {
+            epoch: Some(Epoch {
+                epoch: record.epoch,
+            }),
+            head: record.root.clone(),
+            chain: record.chain.clone(),
+            certificate: view.certificate_of(record).map(EpochCertificate::from),
+        }
+    }
+
+    /// the root and certificate of a retained epoch, or of the latest committed one
+    pub fn certified_root(&self, epoch: Option<u64>) -> Option<RootUpdate> {
+        let view = self.certificates.view();
+        let record = match epoch {
+            Some(epoch) => view.history.get(epoch),
+            None => view.history.latest(),
+        }?;
+        Some(Self::root_update(&view, record))
+    }
+
+    /// Streams the root of every committed epoch, starting at `from_epoch` or at the next commit.
+    /// An epoch is sent once its certificate is signed by a quorum of the cluster. An epoch that
+    /// never gets there is sent with the signatures it has once a later epoch does, or once it
+    /// waited `QUORUM_WAIT_EPOCHS` epoch intervals, so that subscribers of a cluster without a
+    /// quorum still see its roots. Subscribers asking for, or falling behind to, an epoch that
+    /// is no longer retained receive `DATA_LOSS` and are disconnected.
+    pub fn subscribe_roots(
+        &self,
+        from_epoch: Option<u64>,
+    ) -> ReceiverStream<Result<RootUpdate, Status>> {
+        let mut changes = self.certificates.change_receiver();
+        let mut next = from_epoch.unwrap_or(self.current_epoch());
+        let quorum_wait =
+            Duration::from_millis(self.state.lock().epoch_interval() as u64) * QUORUM_WAIT_EPOCHS;
+        let mut waiting: Option<Instant> = None; // since when the first unsent epoch waits
+
+        let (tx, rx) = mpsc::channel(WATCH_BUFFER);
+        let srv = self.clone();
+        tokio::spawn(async move {
+            loop {
+                changes.borrow_and_update();
+                let view = srv.certificates.view();
+                let records: Option<Vec<EpochRecord>> = match view.history.oldest() {
+                    Some(oldest) if next < oldest => None,
+                    _ => Some(view.history.since(next).cloned().collect()),
+                };
+                let Some(records) = records else {
+                    let _ = tx
+                        .send(Err(Status::data_loss(format!(
+                            "Epoch {} is no longer retained",
+                            next
+                        ))))
+                        .await;
+                    return;
+                };
+                let required = quorum(srv.parallel().map_or(0, |p| p.len()) + 1);
+                let stalled = waiting.is_some_and(|since| since.elapsed() >= quorum_wait);
+                let ready = publishable(&records, |r| view.signers(r.epoch) >= required, stalled);
+                if stalled && !ready.is_empty() {
+                    tracing::warn!(
+                        "Sending roots from epoch {} without a quorum of {} signers",
+                        next,
+                        required
+                    );
+                }
+                for record in ready {
+                    next = record.epoch + 1;
+                    if tx.send(Ok(Self::root_update(&view, record))).await.is_err() {
+                        return; // the subscriber went away
+                    }
+                }
+                waiting = match (ready.len() < records.len(), ready.is_empty()) {
+                    (false, _) => None,
+                    (true, true) => waiting.or(Some(Instant::now())),
+                    (true, false) => Some(Instant::now()),
+                };
+                let changed = match waiting {
+                    Some(since) => tokio::time::timeout_at(since + quorum_wait, changes.changed())
+                        .await
+                        .unwrap_or(Ok(())),
+                    None => changes.changed().await,
+                };
+                if changed.is_err() {
+                    return;
+                }
+            }
+        });
+        ReceiverStream::new(rx)
+    }
+
+    /// the recorded versions of the keys matching `filter` that were written in `epoch`
+    fn key_updates(&self, epoch: u64, filter: &KeyFilter) -> Vec<KeyUpdate> {
+        let keys: BTreeSet<Vec<u8>> = self
+            .pool
+            .get_epoch(epoch)
+            .into_iter()
+            .map(|t| t.key)
+            .filter(|key| filter.matches(key))
+            .collect();
+        let view = self.certificates.view();
+        keys.into_iter()
+            .filter_map(|key| {
+                let version = view.key_history.at(&key, epoch)?.clone();
+                Some(KeyUpdate {
+                    epoch: Some(Epoch { epoch }),
+                    key,
+                    value: version.value,
+                    proof: version.proof,
+                    head: version.head,
+                })
+            })
+            .collect()
+    }
+
+    /// Streams the new value and inclusion proof of every key matching `filter` each time a
+    /// committed epoch writes to it. Subscribers falling behind to an epoch that is no longer
+    /// retained receive `DATA_LOSS` and are disconnected.
+    pub fn subscribe_keys(
+        &self,
+        keys: Vec<Vec<u8>>,
+        prefixes: Vec<(Vec<u8>, u32)>,
+    ) -> ReceiverStream<Result<KeyUpdate, Status>> {
+        let filter = KeyFilter::new(keys, prefixes, self.length);
+        let mut commits = self.certificates.commit_receiver();
+        let mut next = self.current_epoch();
+
+        let (tx, rx) = mpsc::channel(WATCH_BUFFER);
+        let srv = self.clone();
+        tokio::spawn(async move {
+            while commits.changed().await.is_ok() {
+                let committed = *commits.borrow_and_update();
+                let Some(epochs) = catch_up(&srv.pool, next, committed) else {
+                    let _ = tx
+                        .send(Err(Status::data_loss(format!(
+                            "Epoch {} is no longer retained",
+                            next
+                        ))))
+                        .await;
+                    return;
+                };
+                for epoch in epochs {
+                    for update in srv.key_updates(epoch, &filter) {
+                        if tx.send(Ok(update)).await.is_err() {
+                            return; // the subscriber went away
+                        }
+                    }
+                    next = epoch + 1;
+                }
+            }
+        });
+        ReceiverStream::new(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AdmissionConfig, PoolConfig};
+
+    #[test]
+    fn tst_key_filter() {
+        let filter = KeyFilter::new(vec![vec![0b110000]], vec![(vec![0b01], 2)], 6);
+        assert!(filter.matches(&[0b010110]));
+        assert!(filter.matches(&[0b110000]));
+        assert!(!filter.matches(&[0b100000]));
+    }
+
+    #[test]
+    fn tst_publishable() {
+        let records: Vec<EpochRecord> = (3..6)
+            .map(|epoch| EpochRecord {
+                epoch,
+                root: vec![epoch as u8],
+                chain: vec![],
+            })
+            .collect();
+        // a later certified epoch carries the earlier ones along
+        assert_eq!(publishable(&records, |r| r.epoch == 4, false).len(), 2);
+
+        // without a quorum nothing is sent until the wait for one stalls
+        assert!(publishable(&records, |_| false, false).is_empty());
+        assert_eq!(publishable(&records, |_| false, true).len(), 3);
+    }
+
+    #[test]
+    fn tst_key_catch_up() {
+        let pool = ShardedPool::new(
+            PoolConfig {
+                retained_epochs: 2,
+                ..Default::default()
+            },
+            AdmissionConfig::default(),
+        );
+        for epoch in 0..4 {
+            pool.commit(epoch, &Default::default());
+        }
+        // epochs 2 and 3 are retained, a subscriber still waiting for epoch 1 lost updates
+        assert_eq!(catch_up(&pool, 2, 4), Some(2..4));
+        assert_eq!(catch_up(&pool, 4, 4), Some(4..4));
+        assert_eq!(catch_up(&pool, 1, 4), None);
+    }
+}
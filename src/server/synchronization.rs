@@ -1,13 +1,18 @@
 use crate::grpc_handler::inner::mversegrpc::Epoch;
+use crate::grpc_handler::outer::TransactionRequest;
 use crate::grpc_handler::outer::mverseouter::{
-    ClientTransactionRequest, PeerCommitRequest, PeerPrepareRequest, PeerTransactionRequest,
-    ServerIdentity,
+    ClientTransactionRequest, MembershipProposal, PeerCommitRequest, PeerPrepareRequest,
+    PeerTransactionRequest, ServerIdentity,
 };
-use crate::grpc_handler::outer::TransactionRequest;
-use crate::server::transactions::{Transaction, TransactionPool};
-use crate::server::{MerkleVerseServer, ServerId};
+use crate::server::gossip::ObservationStore;
+use crate::server::history::EpochRecord;
+use crate::server::membership::Membership;
+use crate::server::peer_auth::ReplayWindow;
+use crate::server::rotation::KeyRegistry;
+use crate::server::transactions::Receipt;
+use crate::server::{MerkleVerseServer, ServerCluster, ServerId};
 use anyhow::{anyhow, Result};
-use bls_signatures::{aggregate, Serialize, Signature};
+use bls_signatures::{Serialize, Signature};
 use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
 use std::future::Future;
@@ -18,20 +23,12 @@ use tonic::IntoRequest;
 use tracing::instrument;
 use crate::grpc_handler::inner::mversegrpc;
 
-const PREPARE_AFTER: u128 = 1000; // try to trigger prepare n milliseconds after the commit
+const DEFAULT_EPOCH_INTERVAL: u32 = 1000; // try to trigger prepare n milliseconds after the commit
 const COMMIT_AFTER: u128 = 1000; // try to trigger commit n milliseconds after the prepare
 const LOOP_INTERVAL: u64 = 1000; // epoch watch loop interval in milliseconds
 const MIN_TRANSACTIONS: usize = 1; // minimum number of transactions to automatically trigger prepare
 const MAX_TRANSACTIONS: usize = 20; // automatically trigger prepare if the number of transactions reaches this number
 
-#[derive(Debug)]
-pub struct MultiSig {
-    pub epoch: u64,
-    pub root: Vec<u8>,
-    pub aggregate: Signature,
-    pub signatures: HashMap<ServerId, Signature>,
-}
-
 #[derive(Debug, Default, Copy, Clone)]
 pub enum RunState {
     Prepare(u64),
@@ -39,23 +36,38 @@ pub enum RunState {
     Normal,
 }
 
+/// What this server knows of a parallel server: the phase it announced, and how the last
+/// broadcasts to it went.
+#[derive(Debug, Default, Clone)]
+pub struct PeerState {
+    pub run_state: RunState,
+    pub failures: u32, // consecutive failed broadcasts
+    pub last_error: Option<String>,
+    pub leaving: bool, // announced its shutdown, and has not answered since
+}
+
+/// The consensus state of a server: its phase, what it knows of its peers, keys and membership.
+/// The transaction pool and the committed epochs are synchronized separately.
 pub struct MerkleVerseServerState {
-    pub current_root: Vec<u8>,
-    pub current_epoch: u64,
-    multi_sigs: HashMap<u64, MultiSig>,
     run_state: RunState,
-    peer_states: HashMap<ServerId, RunState>,
-    transaction_pool: TransactionPool,
+    peer_states: HashMap<ServerId, PeerState>,
+    observations: ObservationStore,
+    keys: KeyRegistry,
+    membership: Membership,
+    replay_window: ReplayWindow,
+    superior: Option<ServerCluster>,
+    gossip_peers: Option<ServerCluster>,
+    epoch_interval: u32, // try to trigger prepare n milliseconds after the commit
     last_commit_time: Option<Instant>,
     last_prepare_time: Option<Instant>,
     prepare_notify: (Sender<u64>, Receiver<u64>),
-    commit_notify: (Sender<u64>, Receiver<u64>)
+    ready: (Sender<bool>, Receiver<bool>), // whether the server takes traffic
 }
 
 impl Debug for MerkleVerseServerState{
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("MerkleVerseServerState")
-            .field("current_epoch", &self.current_epoch)
+            .field("run_state", &self.run_state)
             .finish()
     }
 }
@@ -63,16 +75,18 @@ impl Debug for MerkleVerseServerState{
 impl MerkleVerseServerState {
     pub fn new() -> Self {
         let (prepare_tx, prepare_rx) = channel(0);
-        let (commit_tx, commit_rx) = channel(0);
         Self {
             prepare_notify: (prepare_tx, prepare_rx),
-            commit_notify: (commit_tx, commit_rx),
-            current_root: Default::default(),
-            current_epoch: 0,
-            multi_sigs: Default::default(),
+            ready: channel(false),
             run_state: RunState::Normal,
             peer_states: Default::default(),
-            transaction_pool: Default::default(),
+            observations: Default::default(),
+            keys: Default::default(),
+            membership: Default::default(),
+            replay_window: Default::default(),
+            superior: None,
+            gossip_peers: None,
+            epoch_interval: DEFAULT_EPOCH_INTERVAL,
             last_commit_time: None,
             last_prepare_time: None,
         }
@@ -80,104 +94,131 @@ impl MerkleVerseServerState {
 }
 
 impl MerkleVerseServer {
-    fn server_identity(&self) -> ServerIdentity {
+    pub fn current_epoch(&self) -> u64 {
+        self.certificates.current_epoch()
+    }
+
+    pub(super) fn server_identity(&self) -> ServerIdentity {
         ServerIdentity {
             server_id: self.id.0.clone(),
         }
     }
 
     pub async fn broadcast_prepare(&self) -> Result<()> {
-        let cur_epoch = {
-            let mut serv_state = self.state.lock().unwrap();
+        let (cur_epoch, membership) = {
+            let mut serv_state = self.state.lock();
             if matches!(serv_state.run_state, RunState::Prepare(_)) {
                 tracing::warn!("Server is already in prepare state");
                 return Ok(());
             }
-            serv_state.run_state = RunState::Prepare(serv_state.current_epoch);
+            if self.is_draining() {
+                tracing::info!("Not preparing a new epoch, the server is shutting down");
+                return Ok(());
+            }
+            let cur_epoch = self.current_epoch();
+            serv_state.run_state = RunState::Prepare(cur_epoch);
             serv_state.last_prepare_time = Some(Instant::now());
-            serv_state.current_epoch
+            self.pool.set_intake_epoch(cur_epoch + 1);
+            (cur_epoch, serv_state.membership.pending(cur_epoch))
         };
-        if let Some(servers) = &self.parallel {
-            let mut futures = vec![];
-            for (_, srv) in &servers.servers {
-                let srv = srv.clone();
-                let mut client = srv.get_client().await?;
-                let fut = async move {
-                    client
-                        .peer_prepare(
-                            PeerPrepareRequest {
-                                epoch: Some(Epoch { epoch: cur_epoch }),
-                                peer_identity: Some(ServerIdentity {
-                                    server_id: self.id.0.clone(),
-                                }),
-                            }
-                            .into_request(),
-                        )
-                        .await
-                };
-                futures.push(fut);
-            }
-            futures::future::join_all(futures).await;
+        let Some(servers) = self.parallel() else {
+            return Ok(());
+        };
+        // every attempt is signed anew, a retry of a request that arrived would be a replay
+        let outcome = self
+            .broadcast("prepare", servers.servers.into_values(), |mut client| {
+                let request = self.sign_rpc(PeerPrepareRequest {
+                    epoch: Some(Epoch { epoch: cur_epoch }),
+                    peer_identity: Some(self.server_identity()),
+                    auth: None,
+                    membership: membership.clone(),
+                });
+                async move { client.peer_prepare(request.into_request()).await }
+            })
+            .await;
+        if !outcome.has_quorum() {
+            return Err(anyhow!(
+                "Prepare of epoch {} {}, below quorum",
+                cur_epoch,
+                outcome.summary()
+            ));
         }
         Ok(())
     }
 
-    pub async fn receive_prepare(&self, epoch: u64, server_id: ServerId) -> Result<()> {
+    /// Records the prepare of `server_id`, with the membership changes it commits with `epoch`,
+    /// and prepares the epoch too if this server has not yet.
+    pub async fn receive_prepare(
+        &self,
+        epoch: u64,
+        server_id: ServerId,
+        membership: Vec<MembershipProposal>,
+    ) -> Result<()> {
         let serv_runstate = {
-            let mut serv_state = self.state.lock().unwrap();
-            serv_state.peer_states.insert(server_id, RunState::Prepare(epoch));
+            let mut serv_state = self.state.lock();
+            serv_state.peer_states.entry(server_id).or_default().run_state =
+                RunState::Prepare(epoch);
+            self.receive_prepared_membership(&mut serv_state, epoch, membership);
             serv_state.run_state
         };
 
         if !matches!(serv_runstate, RunState::Prepare(_)) {
-            self.broadcast_prepare().await?;
+            // the prepare of the sender is recorded, whether this server reaches a quorum or not
+            if let Err(e) = self.broadcast_prepare().await {
+                tracing::warn!("{}", e);
+            }
         }
         Ok(())
     }
 
     pub async fn sign_and_broadcast(&self) -> Result<()> {
-        /// Signs the current tree root with BLS, and broadcasts it to the parallel servers.
-        let (epoch, sig, head) = {
-            let mut serv_state = self.state.lock().unwrap();
-            let sig = self.private_key.bls.sign(&serv_state.current_root);
-            let cur_epoch = serv_state.current_epoch;
-            match serv_state.multi_sigs.get_mut(&cur_epoch) {
-                Some(multi_sig) => {
-                    multi_sig.signatures.insert(self.id.clone(), sig);
-                    multi_sig.aggregate = aggregate([multi_sig.aggregate, sig].as_slice())?;
-                }
-                None => {
-                    let mut multi_sig = MultiSig {
-                        epoch: serv_state.current_epoch,
-                        root: serv_state.current_root.clone(),
-                        aggregate: sig,
-                        signatures: HashMap::new(),
-                    };
-                    multi_sig.signatures.insert(self.id.clone(), sig);
-                    serv_state.multi_sigs.insert(cur_epoch, multi_sig);
-                }
-            }
-            (cur_epoch, sig, serv_state.current_root.clone())
+        // Signs the chain digest of the current tree root with BLS, and broadcasts it to the parallel servers.
+        let view = self.certificates.view();
+        let cur_epoch = view.current_epoch;
+        let root = view.current_root.clone();
+        let chain = view.history.next_chain(cur_epoch, &root);
+        // the chain is persisted before it is signed, a restart must not sign another one
+        self.data_dir.save_commit(&EpochRecord {
+            epoch: cur_epoch,
+            root: root.clone(),
+            chain: chain.clone(),
+        })?;
+        let (epoch, sig, head, membership_sig, parallel) = {
+            let serv_state = self.state.lock();
+            let sig = self.signing_key_in(&serv_state, cur_epoch).bls.sign(&chain);
+            self.certificates
+                .add_signature(cur_epoch, root.clone(), chain, self.id.clone(), sig)?;
+            let membership_sig = self.membership_signature(&serv_state, cur_epoch);
+            let parallel = serv_state.membership.parallel().cloned();
+            (cur_epoch, sig, root, membership_sig, parallel)
         };
-        if let Some(servers) = &self.parallel {
-            let mut futures = vec![];
-            for (_, srv) in &servers.servers {
-                let srv = srv.clone();
-                let mut client = srv.get_client().await?;
-                let h = head.clone();
-                let fut = async move {
-                    client
-                        .peer_commit(PeerCommitRequest {
-                            peer_identity: Some(self.server_identity()),
-                            epoch: Some(Epoch { epoch }),
-                            signature: sig.as_bytes().to_vec(),
-                            head: h,
-                        })
-                        .await
-                };
-                futures.push(fut);
-            }
-            futures::future::join_all(futures).await;
+        let (membership_version, membership_signature) = match membership_sig {
+            Some((version, sig)) => (version, sig.as_bytes()),
+            None => (0, vec![]),
+        };
+        let Some(servers) = parallel else {
+            return Ok(());
+        };
+        let outcome = self
+            .broadcast("commit", servers.servers.into_values(), |mut client| {
+                let request = self.sign_rpc(PeerCommitRequest {
+                    peer_identity: Some(self.server_identity()),
+                    epoch: Some(Epoch { epoch }),
+                    signature: sig.as_bytes().to_vec(),
+                    head: head.clone(),
+                    membership_version,
+                    membership_signature: membership_signature.clone(),
+                    auth: None,
+                });
+                async move { client.peer_commit(request).await }
+            })
+            .await;
+        if !outcome.has_quorum() {
+            return Err(anyhow!(
+                "Signature of epoch {} {}, below quorum, the root may not be certified",
+                epoch,
+                outcome.summary()
+            ));
         }
         Ok(())
     }
@@ -185,40 +226,47 @@ impl MerkleVerseServer {
     pub async fn receive_signatures(
         &self,
         epoch: u64,
+        server_id: ServerId,
         head: &Vec<u8>,
         sig_bytes: &Vec<u8>,
     ) -> Result<()> {
         /// receives the signatures from the parallel servers regarding an epoch. If the contents match
-        /// the current root, then the state of multisignature is updated.
-        let mut serv_state = self.state.lock().unwrap();
-        if epoch != serv_state.current_epoch {
-            return Err(anyhow!(
-                "Received signatures for an epoch that is not equal to current epoch"
-            ));
-        }
-        if *head != serv_state.current_root {
-            return Err(anyhow!(
-                "Received signatures for a root that is not equal to current root"
-            ));
+        /// the root this server committed (or is about to commit) for that epoch and the signature
+        /// is valid, then the state of multisignature is updated.
+        if self
+            .parallel()
+            .and_then(|p| p.get_server(&server_id).cloned())
+            .is_none()
+        {
+            return Err(anyhow!("Server {} is not a member of this cluster", server_id.0));
         }
+        let peer_key = self
+            .public_key_at(&server_id, epoch)
+            .ok_or(anyhow!("No key is known for server {}", server_id.0))?;
         let sig = Signature::from_bytes(sig_bytes)?;
-        match serv_state.multi_sigs.get_mut(&epoch) {
-            Some(multi_sig) => {
-                multi_sig.signatures.insert(self.id.clone(), sig);
-                multi_sig.aggregate = aggregate([multi_sig.aggregate, sig].as_slice())?;
-            }
+        let view = self.certificates.view();
+        let (root, chain) = match view.history.get(epoch) {
+            Some(record) => (record.root.clone(), record.chain.clone()),
+            None if epoch == view.current_epoch => (
+                view.current_root.clone(),
+                view.history.next_chain(epoch, &view.current_root),
+            ),
             None => {
-                let mut multi_sig = MultiSig {
-                    epoch: serv_state.current_epoch,
-                    root: serv_state.current_root.clone(),
-                    aggregate: sig,
-                    signatures: HashMap::new(),
-                };
-                multi_sig.signatures.insert(self.id.clone(), sig);
-                serv_state.multi_sigs.insert(epoch, multi_sig);
+                return Err(anyhow!(
+                    "Received signatures for an epoch that is neither retained nor current"
+                ))
             }
+        };
+        if *head != root {
+            return Err(anyhow!(
+                "Received signatures for a root that is not equal to the committed root"
+            ));
         }
-        Ok(())
+        if !peer_key.bls.verify(sig, &chain) {
+            return Err(anyhow!("Signature of server {} is invalid", server_id.0));
+        }
+        self.certificates
+            .add_signature(epoch, root, chain, server_id, sig)
     }
 
     pub async fn watch_trigger_prepare(&self) -> Result<()> {
@@ -227,32 +275,31 @@ impl MerkleVerseServer {
         /// loop every 10 seconds, trigger commit if the epoch interval is reached.
         loop {
             tokio::time::sleep(tokio::time::Duration::from_millis(LOOP_INTERVAL)).await;
+            if self.is_draining() {
+                continue;
+            }
             let trigger_prep = {
-                let serv_state = self
-                    .state
-                    .lock()
-                    .map_err(|_| anyhow!("Failed to lock state"))?;
-                if matches!(serv_state.run_state, RunState::Prepare(_)) {
-                    continue;
-                }
-                let t_trigger = match serv_state.last_commit_time {
-                    Some(t) => t.elapsed().as_millis() > PREPARE_AFTER,
-                    None => true,
-                };
-                let transaction_cnt = match serv_state
-                    .transaction_pool
-                    .get_epoch(serv_state.current_epoch)
-                {
-                    Some(transactions) => transactions.len(),
-                    None => 0,
+                let t_trigger = {
+                    let serv_state = self.state.lock();
+                    if matches!(serv_state.run_state, RunState::Prepare(_)) {
+                        continue;
+                    }
+                    match serv_state.last_commit_time {
+                        Some(t) => t.elapsed().as_millis() > serv_state.epoch_interval as u128,
+                        None => true,
+                    }
                 };
+                self.pool.expire(Instant::now());
+                let transaction_cnt = self.pool.len(self.current_epoch());
                 (t_trigger || transaction_cnt >= MAX_TRANSACTIONS)
                     && transaction_cnt >= MIN_TRANSACTIONS
             };
 
             if trigger_prep {
                 tracing::info!("Triggering prepare phase");
-                self.broadcast_prepare().await?;
+                if let Err(e) = self.broadcast_prepare().await {
+                    tracing::warn!("{}", e);
+                }
             }
         }
     }
@@ -263,10 +310,7 @@ impl MerkleVerseServer {
         loop {
             tokio::time::sleep(tokio::time::Duration::from_millis(LOOP_INTERVAL)).await;
             let trigger_commit = {
-                let serv_state = self
-                    .state
-                    .lock()
-                    .map_err(|_| anyhow!("Failed to lock state"))?;
+                let serv_state = self.state.lock();
                 if matches!(serv_state.run_state, RunState::Normal) {
                     continue;
                 }
@@ -285,6 +329,13 @@ impl MerkleVerseServer {
     }
 
     pub async fn routine(&self) -> Result<()> {
+        if let Err(e) = self.sync_membership().await {
+            tracing::warn!("Failed to synchronize the cluster membership: {}", e);
+        }
+        if !self.is_draining() {
+            self.state.lock().set_ready(true);
+            tracing::info!("Startup resync finished, the server is ready");
+        }
 
         let prep_loop = async move {
             self.watch_trigger_prepare().await
@@ -294,7 +345,15 @@ impl MerkleVerseServer {
             self.watch_commit_loop().await
         };
 
-        tokio::try_join!(prep_loop, commit_loop)?;
+        let gossip_loop = async move {
+            self.gossip_loop().await
+        };
+
+        let health_loop = async move {
+            self.health_loop().await
+        };
+
+        tokio::try_join!(prep_loop, commit_loop, gossip_loop, health_loop)?;
         Ok(())
     }
 
@@ -304,35 +363,55 @@ impl MerkleVerseServer {
         // Note: might need to base this on the server configuration
         // TODO: support bulk transactions
         tracing::info!("Triggering commit");
-        {
-            let transactions = {
-                let serv_state = self.state.lock().unwrap();
-                let res = serv_state
-                    .transaction_pool
-                    .get_epoch(serv_state.current_epoch);
-                match res {
-                    None => {vec![]}
-                    Some(transet) => {
-                        transet
-                            .iter().map(|t| TransactionRequest::from(t.clone()))
-                            .collect()
-                    }
-                }
-            };
+        let sent = {
+            let epoch = self.current_epoch();
+            let sent = self.pool.pending(epoch);
+            let transactions: Vec<TransactionRequest> =
+                sent.iter().map(TransactionRequest::from).collect();
             if transactions.len()>0{
-                let mut inner_client = self.get_inner_client().await?;
-                for t in transactions {
+                let mut inner_client = self.get_inner_client()?;
+                for t in &transactions {
                     inner_client
-                        .transaction(t)
+                        .transaction(t.clone())
                         .await?;
                 }
-                inner_client.trigger_epoch(mversegrpc::Empty{}).await?;
+                let res = inner_client
+                    .trigger_epoch(mversegrpc::Empty{})
+                    .await?
+                    .into_inner();
+                self.certificates.set_root(res.head);
+                if let Err(e) = self
+                    .record_key_versions(&mut inner_client, epoch, &transactions)
+                    .await
+                {
+                    tracing::error!("Failed to record the key versions of epoch {}: {}", epoch, e);
+                    self.certificates.skip_versions(epoch);
+                }
+            }
+            sent
+        };
+        {
+            let mut serv_state = self.state.lock();
+            let epoch = self.current_epoch();
+            if let Err(e) = self.apply_membership(&mut serv_state, epoch) {
+                tracing::error!("Failed to apply membership changes: {}", e);
             }
         }
-        let mut serv_state = self.state.lock().unwrap();
+        // Certification: every commit signs the chain of its root and sends the signature to the
+        // parallel servers, whose signatures arriving through PeerCommit complete the certificate.
+        // A failure leaves the epoch committed locally but uncertified.
+        if let Err(e) = self.sign_and_broadcast().await {
+            tracing::error!("Failed to sign and broadcast the committed root: {}", e);
+        }
+        // the epoch moves on while the phase is still held, so that readers of both agree, and
+        // only what was sent is committed, transactions pooled since then move on with it
+        let mut serv_state = self.state.lock();
+        let committed = self.certificates.commit();
+        self.pool.set_intake_epoch(committed + 1);
+        self.pool.commit(committed, &sent);
         serv_state.run_state = RunState::Normal;
-        serv_state.current_epoch += 1;
-        serv_state.commit_notify.0.send(serv_state.current_epoch)?;
+        serv_state.last_commit_time = Some(Instant::now());
+        drop(serv_state);
         Ok(())
     }
 
@@ -342,93 +421,223 @@ impl MerkleVerseServer {
     ) -> Result<Option<()>> {
         // receives a transaction from a peer server, and inserts it into the transaction pool.
         // Note: currently, if the transaction already exists, it is not inserted, and no error message is returned.
-        let mut serv_state = self.state.lock().unwrap();
         self.verify_peer_transaction(&req)?;
-        serv_state.transaction_pool.insert_peer(req)
+        self.pool.insert_peer(req)
     }
 
+    /// Receives a transaction from `client`, identified by its address, forwards it to the
+    /// parallel servers and optionally waits for its commit.
     pub async fn receive_client_transaction(
         &self,
+        client: &str,
         req: ClientTransactionRequest,
         wait: bool,
-    ) -> Result<Option<()>> {
-        tracing::info!("Received client transaction {:?}", req);
-        let (r, target_epoch) = {
-            let mut serv_state = self.state.lock().unwrap();
-            let epoch = match serv_state.run_state {
-                RunState::Prepare(_) => serv_state.current_epoch + 1,
-                RunState::Normal => serv_state.current_epoch,
-            };
-            let res = serv_state.transaction_pool.insert_client(epoch, &req)?;
-            if res.is_some() {
-                return Ok(res);
-            }
-            if let Some(parallels) = &self.parallel {
-                let trans = req.transaction.unwrap();
-                let signature = self.sign_transaction(&trans)?;
-                for (_, ps) in parallels.servers.iter() {
-                    let pc = ps.clone();
-                    let ts = trans.clone();
-                    let sig = signature.clone();
-                    let my_id = self.id.0.clone();
-                    tokio::spawn(async move {
-                        let mut client = pc.get_client().await.unwrap();
-                        let res = client
-                            .peer_transaction(PeerTransactionRequest {
-                                transaction: Some(ts),
-                                server_id: my_id,
-                                epoch: Some(Epoch { epoch }),
-                                signature: sig,
-                                auxiliary: None,
-                            })
-                            .await;
-                        if let Err(e) = res {
-                            tracing::error!("Failed to send peer transaction to {}: {}", pc.id.0, e);
-                        }
-                    });
+    ) -> Result<Receipt> {
+        tracing::debug!("Received client transaction {:?}", req);
+        // subscribe first, so that a commit right after the transaction is pooled is not missed
+        let mut recv_chan = self.certificates.commit_receiver();
+        let receipt = self.pool.submit(client, &req)?;
+        if receipt.duplicate {
+            return Ok(receipt);
+        }
+        if !receipt.replayed {
+            self.forward_transaction(&req, receipt.epoch)?;
+        }
+        // retries wait for the original commit, if it is still pending
+        let target_epoch = receipt.epoch + 1;
+        if !wait || *recv_chan.borrow_and_update() >= target_epoch {
+            return Ok(receipt);
+        }
+        let mut try_cnt = 0;
+        while try_cnt < 3 {
+            tracing::debug!("Waiting for commit notification, waiting until epoch {}", target_epoch);
+            match recv_chan.changed().await {
+                Ok(_) if *recv_chan.borrow_and_update() >= target_epoch => return Ok(receipt),
+                Ok(_) => try_cnt += 1,
+                Err(e) => {
+                    tracing::error!("Failed to receive commit notification: {}", e);
+                    return Err(anyhow!("Failed to receive commit notification"));
                 }
-            }
-            (Ok(res), epoch+1)
-        };
-        if wait {
-            let mut recv_chan = {
-                let srv_state = self.state.lock().unwrap();
-                let recv_chan = srv_state.commit_notify.1.clone();
-                recv_chan
             };
-            let mut try_cnt = 0;
-            while try_cnt<3 {
-                tracing::info!("Waiting for commit notification, waiting until epoch {}", target_epoch);
-                match recv_chan.changed().await {
-                    Ok(_) if *recv_chan.borrow_and_update() == target_epoch => {
-                        tracing::info!("wheee");
-                        return r;
-                    }
-                    Ok(_) => {
-                        tracing::info!("whelp: {} ( I want {})", *recv_chan.borrow_and_update(), target_epoch);
-                        try_cnt += 1
-                    }
+        }
+        tracing::error!("Failed to receive commit notification");
+        Err(anyhow!("Failed to receive commit notification"))
+    }
+
+    /// Signs the transaction of `req`, pooled for `epoch`, and sends it to the parallel servers
+    /// in the background. No lock is held while signing.
+    fn forward_transaction(&self, req: &ClientTransactionRequest, epoch: u64) -> Result<()> {
+        let Some(parallels) = self.parallel() else {
+            return Ok(());
+        };
+        let trans = req
+            .transaction
+            .clone()
+            .ok_or(anyhow!("A valid transaction must be provided"))?;
+        let signature = self.sign_transaction(&trans, epoch, &self.signing_key(epoch))?;
+        for (_, ps) in parallels.servers.iter() {
+            let pc = ps.clone();
+            let ts = trans.clone();
+            let sig = signature.clone();
+            let my_id = self.id.0.clone();
+            let client = self.peer_client(ps);
+            tokio::spawn(async move {
+                let mut client = match client {
+                    Ok(client) => client,
                     Err(e) => {
-                        tracing::error!("Failed to receive commit notification: {}", e);
-                        return Err(anyhow!("Failed to receive commit notification"));
+                        tracing::warn!("Failed to forward transaction to {}: {}", pc.id.0, e);
+                        return;
                     }
                 };
-            }
-            tracing::error!("Failed to receive commit notification");
-            Err(anyhow!("Failed to receive commit notification"))
-        } else {
-            r
+                let res = client
+                    .peer_transaction(PeerTransactionRequest {
+                        transaction: Some(ts),
+                        server_id: my_id,
+                        epoch: Some(Epoch { epoch }),
+                        signature: sig,
+                        auxiliary: None,
+                    })
+                    .await;
+                if let Err(e) = res {
+                    tracing::error!("Failed to send peer transaction to {}: {}", pc.id.0, e);
+                }
+            });
         }
+        Ok(())
     }
 }
 
 
 impl MerkleVerseServerState {
     pub fn add_peer(&mut self, server_id: ServerId) -> Result<()> {
-        self.peer_states.insert(
-            server_id,
-            RunState::Normal
-        );
+        self.peer_states.insert(server_id, PeerState::default());
         Ok(())
     }
+
+    /// records the result of a broadcast to a peer, `error` being why it failed
+    pub fn record_broadcast(&mut self, server_id: &ServerId, error: Option<&String>) {
+        let Some(peer) = self.peer_states.get_mut(server_id) else {
+            return;
+        };
+        match error {
+            None => {
+                peer.failures = 0;
+                peer.last_error = None;
+                peer.leaving = false;
+            }
+            Some(e) => {
+                peer.failures += 1;
+                peer.last_error = Some(e.clone());
+            }
+        }
+    }
+
+    /// records that a peer announced its shutdown
+    pub fn mark_leaving(&mut self, server_id: &ServerId) {
+        if let Some(peer) = self.peer_states.get_mut(server_id) {
+            peer.leaving = true;
+        }
+    }
+
+    pub fn is_leaving(&self, server_id: &ServerId) -> bool {
+        self.peer_states
+            .get(server_id)
+            .is_some_and(|peer| peer.leaving)
+    }
+
+    pub fn remove_peer(&mut self, server_id: &ServerId) {
+        self.peer_states.remove(server_id);
+    }
+
+    pub fn run_state(&self) -> RunState {
+        self.run_state
+    }
+
+    pub fn peer_states(&self) -> &HashMap<ServerId, PeerState> {
+        &self.peer_states
+    }
+
+    pub fn last_commit_time(&self) -> Option<Instant> {
+        self.last_commit_time
+    }
+
+    pub fn is_ready(&self) -> bool {
+        *self.ready.1.borrow()
+    }
+
+    pub fn set_ready(&mut self, ready: bool) {
+        self.ready.0.send_replace(ready);
+    }
+
+    /// a receiver that is notified every time the server becomes ready or stops being ready
+    pub fn ready_receiver(&self) -> Receiver<bool> {
+        self.ready.1.clone()
+    }
+}
+
+impl MerkleVerseServerState {
+    pub fn observations(&self) -> &ObservationStore {
+        &self.observations
+    }
+
+    pub fn observations_mut(&mut self) -> &mut ObservationStore {
+        &mut self.observations
+    }
+
+    pub fn keys(&self) -> &KeyRegistry {
+        &self.keys
+    }
+
+    pub fn keys_mut(&mut self) -> &mut KeyRegistry {
+        &mut self.keys
+    }
+
+    pub fn membership(&self) -> &Membership {
+        &self.membership
+    }
+
+    pub fn membership_mut(&mut self) -> &mut Membership {
+        &mut self.membership
+    }
+
+    pub fn replay_window_mut(&mut self) -> &mut ReplayWindow {
+        &mut self.replay_window
+    }
+
+    pub fn superior(&self) -> Option<&ServerCluster> {
+        self.superior.as_ref()
+    }
+
+    pub fn set_superior(&mut self, superior: Option<ServerCluster>) {
+        self.superior = superior;
+    }
+
+    pub fn gossip_peers(&self) -> Option<&ServerCluster> {
+        self.gossip_peers.as_ref()
+    }
+
+    pub fn set_gossip_peers(&mut self, gossip_peers: Option<ServerCluster>) {
+        self.gossip_peers = gossip_peers;
+    }
+
+    pub fn epoch_interval(&self) -> u32 {
+        self.epoch_interval
+    }
+
+    pub fn set_epoch_interval(&mut self, epoch_interval: u32) {
+        self.epoch_interval = epoch_interval;
+    }
+
+    /// Points every known copy of server `id` to `connection_string`. Returns whether the server
+    /// is known.
+    pub fn set_address(&mut self, id: &ServerId, connection_string: &str) -> bool {
+        let mut known = self.membership.set_address(id, connection_string);
+        for cluster in [&mut self.superior, &mut self.gossip_peers]
+            .into_iter()
+            .flatten()
+        {
+            known |= cluster.set_address(id, connection_string);
+        }
+        known
+    }
 }
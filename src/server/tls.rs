@@ -0,0 +1,223 @@
+use crate::args::TlsArgs;
+use crate::config::TlsConfig;
+use crate::server::MerkleVerseServer;
+use crate::utils::endpoint_url;
+use anyhow::{anyhow, Context, Result};
+use rcgen::{
+    BasicConstraints, Certificate as IssuedCertificate, CertificateParams, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyUsagePurpose, SanType,
+};
+use std::net::IpAddr;
+use std::path::Path;
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity, ServerTlsConfig};
+use tonic::Request;
+use x509_parser::extensions::GeneralName;
+
+/// DNS suffix of the names binding peer certificates to server ids
+const PEER_DOMAIN: &str = "peer.merkleverse";
+
+/// the name a peer certificate must carry to act as server `id`
+pub fn tls_name(id: &str) -> String {
+    format!("{}.{}", id, PEER_DOMAIN)
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+/// The CA and identity of a server, used both by its listener and by its channels to peers.
+#[derive(Debug, Clone)]
+pub struct Tls {
+    ca: Certificate,
+    identity: Identity,
+}
+
+impl Tls {
+    pub fn load(config: &TlsConfig) -> Result<Self> {
+        Ok(Self {
+            ca: Certificate::from_pem(read(&config.ca)?),
+            identity: Identity::from_pem(read(&config.cert)?, read(&config.key)?),
+        })
+    }
+
+    /// Client certificates are optional so that plain clients can still connect, peer RPCs
+    /// check them with `authenticate_peer`.
+    pub fn server_config(&self) -> ServerTlsConfig {
+        ServerTlsConfig::new()
+            .identity(self.identity.clone())
+            .client_ca_root(self.ca.clone())
+            .client_auth_optional(true)
+    }
+
+    /// the config of a channel to the peer `id`, whose certificate must be issued for its id
+    pub fn peer_config(&self, id: &str) -> ClientTlsConfig {
+        ClientTlsConfig::new()
+            .ca_certificate(self.ca.clone())
+            .identity(self.identity.clone())
+            .domain_name(tls_name(id))
+    }
+}
+
+/// the server ids a certificate, in DER, was issued for
+pub fn peer_ids(der: &[u8]) -> Result<Vec<String>> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der)?;
+    let Some(san) = cert.subject_alternative_name()? else {
+        return Ok(vec![]);
+    };
+    let suffix = format!(".{}", PEER_DOMAIN);
+    Ok(san
+        .value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(dns) => dns.strip_suffix(&suffix).map(String::from),
+            _ => None,
+        })
+        .collect())
+}
+
+/// A certificate authority issuing the certificates of a cluster, written by `gen-peers`.
+pub struct Authority {
+    cert: IssuedCertificate,
+}
+
+/// a certificate and its private key, in PEM
+pub struct IssuedPem {
+    pub cert: String,
+    pub key: String,
+}
+
+impl Authority {
+    pub fn new() -> Result<Self> {
+        let mut params = CertificateParams::default();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "MerkleVerse CA");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        Ok(Self {
+            cert: IssuedCertificate::from_params(params)?,
+        })
+    }
+
+    pub fn pem(&self) -> Result<IssuedPem> {
+        Ok(IssuedPem {
+            cert: self.cert.serialize_pem()?,
+            key: self.cert.serialize_private_key_pem(),
+        })
+    }
+
+    fn issue(&self, name: &str, sans: Vec<SanType>) -> Result<IssuedPem> {
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.subject_alt_names = sans;
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+        let cert = IssuedCertificate::from_params(params)?;
+        Ok(IssuedPem {
+            cert: cert.serialize_pem_with_signer(&self.cert)?,
+            key: cert.serialize_private_key_pem(),
+        })
+    }
+
+    /// Issues the certificate of server `id`, reachable at `connection_string`. The certificate
+    /// names the id, so that peers can check who is calling, and the host, for clients.
+    pub fn issue_server(&self, id: &str, connection_string: &str) -> Result<IssuedPem> {
+        let host = connection_string
+            .rsplit_once(':')
+            .map_or(connection_string, |(host, _)| host)
+            .trim_matches(|c| c == '[' || c == ']');
+        let mut sans = vec![SanType::DnsName(tls_name(id))];
+        sans.push(match host.parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(host.to_string()),
+        });
+        self.issue(id, sans)
+    }
+
+    /// Issues a certificate for clients, which does not name any server.
+    pub fn issue_client(&self) -> Result<IssuedPem> {
+        self.issue(
+            "client",
+            vec![SanType::DnsName("client.merkleverse".into())],
+        )
+    }
+}
+
+impl MerkleVerseServer {
+    pub fn tls_server_config(&self) -> Option<ServerTlsConfig> {
+        self.connections.tls().map(Tls::server_config)
+    }
+
+    /// Checks that a peer RPC claiming to come from `claimed` was sent with a certificate issued
+    /// for that server. Without TLS, claimed identities are taken as they are.
+    pub fn authenticate_peer<T>(&self, request: &Request<T>, claimed: &str) -> Result<()> {
+        if self.connections.tls().is_none() {
+            return Ok(());
+        }
+        let certs = request
+            .peer_certs()
+            .ok_or(anyhow!("Peer RPCs need a client certificate"))?;
+        for cert in certs.iter() {
+            if peer_ids(cert.get_ref())?.iter().any(|id| id == claimed) {
+                return Ok(());
+            }
+        }
+        Err(anyhow!(
+            "The client certificate was not issued for server {}",
+            claimed
+        ))
+    }
+
+    /// `authenticate_peer` for RPCs whose sender may name itself, which it must do when TLS is
+    /// configured.
+    pub fn authenticate_claimed<T>(
+        &self,
+        request: &Request<T>,
+        claimed: Option<&str>,
+    ) -> Result<()> {
+        match claimed {
+            Some(claimed) => self.authenticate_peer(request, claimed),
+            None if self.connections.tls().is_none() => Ok(()),
+            None => Err(anyhow!("Peer RPCs must name the server sending them")),
+        }
+    }
+}
+
+/// the endpoint of a server at `addr` for clients, over TLS when a CA is given
+pub fn client_endpoint(addr: &str, args: &TlsArgs) -> Result<Endpoint> {
+    let url = endpoint_url(addr);
+    let Some(ca) = &args.tls_ca else {
+        return Ok(Endpoint::from_shared(url)?);
+    };
+    let mut tls = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(read(ca)?));
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        tls = tls.identity(Identity::from_pem(read(cert)?, read(key)?));
+    }
+    if let Some(domain) = &args.tls_domain {
+        tls = tls.domain_name(domain);
+    }
+    Endpoint::from_shared(url.replacen("http://", "https://", 1))?
+        .tls_config(tls)
+        .map_err(|e| anyhow!("Invalid TLS config: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tst_peer_identity() -> Result<()> {
+        let ca = Authority::new()?;
+        let server = ca.issue_server("s1", "10.0.0.1:8000")?;
+        let (_, pem) = x509_parser::pem::parse_x509_pem(server.cert.as_bytes())?;
+        assert_eq!(peer_ids(&pem.contents)?, vec!["s1".to_string()]);
+        let client = ca.issue_client()?;
+        let (_, pem) = x509_parser::pem::parse_x509_pem(client.cert.as_bytes())?;
+        assert!(peer_ids(&pem.contents)?.is_empty());
+        Ok(())
+    }
+}
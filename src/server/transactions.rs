@@ -1,10 +1,15 @@
 use crate::grpc_handler::inner::mversegrpc::transaction_request;
 use crate::grpc_handler::outer::mverseouter::{ClientTransactionRequest, PeerTransactionRequest};
 use crate::grpc_handler::outer::TransactionRequest;
+use crate::config::PoolConfig;
 use crate::server::{Index, ServerId};
 use anyhow::{anyhow, Result};
-use std::collections::{HashMap, HashSet};
+use prost::Message;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::hash::{Hash, Hasher};
+use std::time::Duration;
+use tokio::time::Instant;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum TransactionSource {
@@ -58,7 +63,7 @@ impl TryFrom<&TransactionRequest> for TransactionOp {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Transaction {
     pub source: TransactionSource,
     pub operation: TransactionOp,
@@ -84,7 +89,7 @@ impl Transaction {
         })
     }
 
-    fn from_client(trans: &ClientTransactionRequest) -> Result<Self> {
+    pub(super) fn from_client(trans: &ClientTransactionRequest) -> Result<Self> {
         Ok(Self {
             auxiliary: trans.auxiliary.clone(),
             source: TransactionSource::Client,
@@ -113,18 +118,171 @@ impl From<&Transaction> for TransactionRequest {
     }
 }
 
+/// What a client was told about its transaction, returned again to retries with the same
+/// idempotency id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Receipt {
+    pub epoch: u64,
+    pub duplicate: bool,
+    pub replayed: bool,
+}
+
+/// a client and the idempotency id it chose
+pub type ReceiptKey = (String, String);
+
+#[derive(Debug)]
+struct IssuedReceipt {
+    receipt: Receipt,
+    digest: Vec<u8>, // of the transaction the id was first used for
+    issued: Instant,
+}
+
+/// the digest of what a client request asks for, retries with the same id must match it
+fn request_digest(req: &ClientTransactionRequest) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(
+        req.transaction
+            .as_ref()
+            .map(|t| t.encode_to_vec())
+            .unwrap_or_default(),
+    );
+    if let Some(auxiliary) = &req.auxiliary {
+        hasher.update(auxiliary);
+    }
+    hasher.finalize().to_vec()
+}
+
+/// The receipts issued for idempotency ids, by client and id, whatever key the transaction writes.
+#[derive(Debug, Default)]
+pub struct Receipts {
+    issued: HashMap<ReceiptKey, IssuedReceipt>,
+}
+
+impl Receipts {
+    /// The receipt issued to `client` for the idempotency id of `req`, if it is still remembered.
+    /// A retry asking for another transaction than the one the id was used for is an error.
+    pub fn get(&self, client: &str, req: &ClientTransactionRequest) -> Result<Option<Receipt>> {
+        let Some(id) = &req.idempotency_id else {
+            return Ok(None);
+        };
+        let Some(issued) = self.issued.get(&(client.to_string(), id.clone())) else {
+            return Ok(None);
+        };
+        if issued.digest != request_digest(req) {
+            return Err(anyhow!(
+                "Idempotency id {} was used for another transaction",
+                id
+            ));
+        }
+        Ok(Some(Receipt {
+            replayed: true,
+            ..issued.receipt
+        }))
+    }
+
+    /// remembers `receipt` for the idempotency id of `req` from `client`, if it has one
+    pub fn insert(&mut self, client: &str, req: &ClientTransactionRequest, receipt: Receipt) {
+        if let Some(id) = &req.idempotency_id {
+            let issued = IssuedReceipt {
+                receipt,
+                digest: request_digest(req),
+                issued: Instant::now(),
+            };
+            self.issued.insert((client.to_string(), id.clone()), issued);
+        }
+    }
+
+    /// forgets the receipt of `key`, whose transaction was dropped, so that retries pool it again
+    pub fn remove(&mut self, key: &ReceiptKey) {
+        self.issued.remove(key);
+    }
+
+    /// records that the transaction of `key` was pooled again for `epoch`
+    pub fn move_to(&mut self, key: &ReceiptKey, epoch: u64) {
+        if let Some(issued) = self.issued.get_mut(key) {
+            issued.receipt.epoch = epoch;
+        }
+    }
+
+    /// forgets the receipts older than `window`
+    pub fn expire(&mut self, now: Instant, window: Duration) {
+        self.issued
+            .retain(|_, receipt| now.saturating_duration_since(receipt.issued) < window);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    idempotency_ids: Vec<ReceiptKey>, // moved along with the transaction if it is pooled again
+    first_epoch: u64,                 // the epoch the transaction was first pooled for
+}
+
+/// The idempotency ids of the transactions a commit pooled again, whose receipts must follow
+/// them, and of those it dropped, whose receipts must be forgotten.
+#[derive(Debug, Default)]
+pub struct Repooled {
+    pub moved: Vec<ReceiptKey>,
+    pub dropped: Vec<ReceiptKey>,
+}
+
+/// Transactions pending per epoch and those of recently committed epochs. A pending transaction
+/// leaves the pool only with the commit of an epoch, so that every server of the cluster commits
+/// or drops the same transactions.
 #[derive(Debug, Default)]
 pub struct TransactionPool {
-    existence_set: HashMap<u64, HashSet<Transaction>>, // Epoch -> Transactions
+    config: PoolConfig,
+    existence_set: HashMap<u64, HashMap<Transaction, Pending>>, // Epoch -> Transactions
+    key_counts: HashMap<u64, HashMap<Vec<u8>, usize>>, // Epoch -> key -> pending writes
+    committed: BTreeMap<u64, HashSet<Transaction>>, // the last committed epochs
+    committed_index: HashMap<Transaction, u64>, // transaction -> last retained epoch committing it
 }
 
 impl TransactionPool {
     pub fn new() -> Self {
+        Self::with_config(PoolConfig::default())
+    }
+
+    pub fn with_config(config: PoolConfig) -> Self {
         Self {
-            existence_set: HashMap::new(),
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// the number of transactions pending for each epoch that has some
+    pub fn pending_per_epoch(&self) -> impl Iterator<Item = (u64, usize)> + '_ {
+        self.existence_set.iter().map(|(epoch, set)| (*epoch, set.len()))
+    }
+
+    /// The epoch of `transaction` if it is a duplicate: pending for `epoch`, or committed within
+    /// the retained epochs unless it is `idempotent`. A request with an idempotency id writes
+    /// again what an earlier request wrote, its retries are recognized by their receipt.
+    pub fn duplicate_of(
+        &self,
+        epoch: u64,
+        transaction: &Transaction,
+        idempotent: bool,
+    ) -> Option<u64> {
+        let pending = self
+            .existence_set
+            .get(&epoch)
+            .is_some_and(|set| set.contains_key(transaction));
+        match pending {
+            true => Some(epoch),
+            false if idempotent => None,
+            false => self.committed_index.get(transaction).copied(),
         }
     }
 
+    /// the number of writes to `key` pending for `epoch`
+    pub fn pending_writes(&self, epoch: u64, key: &[u8]) -> usize {
+        self.key_counts
+            .get(&epoch)
+            .and_then(|counts| counts.get(key))
+            .copied()
+            .unwrap_or_default()
+    }
+
     pub fn insert_peer(&mut self, req: PeerTransactionRequest) -> Result<Option<()>> {
         self.insert_transaction(
             req.epoch
@@ -132,46 +290,257 @@ impl TransactionPool {
                 .ok_or(anyhow!("An epoch number must be provided!"))?
                 .epoch,
             Transaction::from_peer(&req)?,
+            None,
+            true, // the server forwarding it already answered duplicates of committed ones
         )
+        .map(|res| res.map(|_| ()))
     }
 
+    /// Inserts the transaction of `req` from `client` into the pool of `epoch`. The receipt is
+    /// remembered by the caller, see `Receipts`.
     pub fn insert_client(
         &mut self,
         epoch: u64,
+        client: &str,
         req: &ClientTransactionRequest,
-    ) -> Result<Option<()>> {
-        self.insert_transaction(epoch, Transaction::from_client(req)?)
+    ) -> Result<Receipt> {
+        let key = req
+            .idempotency_id
+            .as_ref()
+            .map(|id| (client.to_string(), id.clone()));
+        let idempotent = key.is_some();
+        let res =
+            self.insert_transaction(epoch, Transaction::from_client(req)?, key, idempotent)?;
+        Ok(Receipt {
+            epoch: res.unwrap_or(epoch),
+            duplicate: res.is_some(),
+            replayed: false,
+        })
+    }
+
+    /// the transactions of `epoch`, pending or committed within the retained epochs
+    pub fn get_epoch(&self, epoch: u64) -> Vec<&Transaction> {
+        match self.existence_set.get(&epoch) {
+            Some(pending) => pending.keys().collect(),
+            None => self.committed.get(&epoch).into_iter().flatten().collect(),
+        }
     }
 
-    pub fn get_epoch(&self, epoch: u64) -> Option<&HashSet<Transaction>> {
-        self.existence_set.get(&epoch)
+    /// the transactions pending for `epoch`
+    pub fn pending(&self, epoch: u64) -> HashSet<Transaction> {
+        self.existence_set
+            .get(&epoch)
+            .map(|pending| pending.keys().cloned().collect())
+            .unwrap_or_default()
     }
 
-    pub fn purge_before(&mut self, epoch: u64) -> Result<()> {
-        let mut to_remove = vec![];
-        for (ep, _) in self.existence_set.iter() {
-            if *ep < epoch {
-                to_remove.push(*ep);
+    /// the oldest committed epoch whose transactions are retained
+    pub fn oldest_committed(&self) -> Option<u64> {
+        self.committed.keys().next().copied()
+    }
+
+    /// the transactions received from clients that are pending for any epoch, with their epoch
+    pub fn pending_client(&self) -> Vec<(u64, &Transaction)> {
+        self.existence_set
+            .iter()
+            .flat_map(|(epoch, pending)| pending.keys().map(move |t| (*epoch, t)))
+            .filter(|(_, t)| matches!(t.source, TransactionSource::Client))
+            .collect()
+    }
+
+    /// Retires the transactions of `epoch` that were `sent` to the inner provider into the
+    /// retained epochs, once it is committed. Those pooled for it after they were sent, and
+    /// those left for earlier epochs, which the cluster committed past, are pooled again for the
+    /// next epoch, unless they were first pooled `transaction_ttl_epochs` before it. The TTL
+    /// counts epochs, so that every server of the cluster drops the same transactions.
+    pub fn commit(&mut self, epoch: u64, sent: &HashSet<Transaction>) -> Repooled {
+        let stale: Vec<u64> = self
+            .existence_set
+            .keys()
+            .filter(|ep| **ep < epoch)
+            .copied()
+            .collect();
+        let mut left: Vec<(Transaction, Pending)> = stale
+            .into_iter()
+            .flat_map(|ep| self.remove_epoch(ep))
+            .collect();
+        if !left.is_empty() {
+            tracing::warn!("{} transactions of uncommitted epochs are left", left.len());
+        }
+        let (transactions, late): (HashMap<_, _>, HashMap<_, _>) = self
+            .remove_epoch(epoch)
+            .into_iter()
+            .partition(|(transaction, _)| sent.contains(transaction));
+        left.extend(late);
+        let mut res = Repooled::default();
+        let (mut repooled, mut dropped) = (0, 0);
+        for (transaction, pending) in left {
+            if epoch + 1 >= pending.first_epoch + self.config.transaction_ttl_epochs {
+                res.dropped.extend(pending.idempotency_ids);
+                dropped += 1;
+                continue;
             }
+            res.moved.extend(pending.idempotency_ids.iter().cloned());
+            self.insert_pending(epoch + 1, transaction, pending);
+            repooled += 1;
         }
-        for ep in to_remove {
-            self.existence_set.remove(&ep);
+        if repooled > 0 {
+            tracing::debug!(
+                "Pooled {} transactions again for epoch {}",
+                repooled,
+                epoch + 1
+            );
         }
-        Ok(())
+        if dropped > 0 {
+            tracing::warn!(
+                "Dropped {} transactions pending for {} epochs",
+                dropped,
+                self.config.transaction_ttl_epochs
+            );
+        }
+        for transaction in transactions.keys() {
+            self.committed_index.insert(transaction.clone(), epoch);
+        }
+        self.committed
+            .insert(epoch, transactions.into_keys().collect());
+        while self.committed.len() > self.config.retained_epochs {
+            let Some((ep, evicted)) = self.committed.pop_first() else {
+                break;
+            };
+            for transaction in evicted {
+                if self.committed_index.get(&transaction) == Some(&ep) {
+                    self.committed_index.remove(&transaction);
+                }
+            }
+        }
+        res
     }
 
-    fn insert_transaction(&mut self, epoch: u64, transaction: Transaction) -> Result<Option<()>> {
-        let haset = match self.existence_set.get_mut(&epoch) {
-            Some(v) => v,
-            None => {
-                self.existence_set.insert(epoch, HashSet::new());
-                self.existence_set.get_mut(&epoch).unwrap() // this should not fail
-            }
-        };
-        if haset.contains(&transaction) {
-            return Ok(Some(())); // the transaction exists
+    fn remove_epoch(&mut self, epoch: u64) -> HashMap<Transaction, Pending> {
+        self.key_counts.remove(&epoch);
+        self.existence_set.remove(&epoch).unwrap_or_default()
+    }
+
+    /// Inserts `transaction` into the pool of `epoch`, unless it is a duplicate, in which case the
+    /// epoch it belongs to is returned. See `duplicate_of`.
+    fn insert_transaction(
+        &mut self,
+        epoch: u64,
+        transaction: Transaction,
+        idempotency_id: Option<ReceiptKey>,
+        idempotent: bool,
+    ) -> Result<Option<u64>> {
+        if let Some(existing) = self
+            .existence_set
+            .get_mut(&epoch)
+            .and_then(|pending| pending.get_mut(&transaction))
+        {
+            existing.idempotency_ids.extend(idempotency_id);
+            return Ok(Some(epoch)); // the transaction exists
+        }
+        if let Some(committed) = self.duplicate_of(epoch, &transaction, idempotent) {
+            return Ok(Some(committed));
         }
-        haset.insert(transaction);
+        let pending = Pending {
+            idempotency_ids: idempotency_id.into_iter().collect(),
+            first_epoch: epoch,
+        };
+        self.insert_pending(epoch, transaction, pending);
         Ok(None)
     }
+
+    fn insert_pending(&mut self, epoch: u64, transaction: Transaction, pending: Pending) {
+        let (key, _) = transaction.operation.to_raw();
+        let existing = self.existence_set.entry(epoch).or_default();
+        if let Some(existing) = existing.get_mut(&transaction) {
+            existing.idempotency_ids.extend(pending.idempotency_ids);
+            existing.first_epoch = existing.first_epoch.min(pending.first_epoch);
+            return;
+        }
+        existing.insert(transaction, pending);
+        *self
+            .key_counts
+            .entry(epoch)
+            .or_default()
+            .entry(key)
+            .or_default() += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc_handler::inner::mversegrpc::transaction_request::TransactionType;
+
+    fn request(key: u8, idempotency_id: Option<&str>) -> ClientTransactionRequest {
+        ClientTransactionRequest {
+            transaction: Some(TransactionRequest {
+                key: vec![key],
+                value: Some(vec![1]),
+                transaction_type: TransactionType::Update.into(),
+            }),
+            auxiliary: None,
+            wait: false,
+            idempotency_id: idempotency_id.map(String::from),
+        }
+    }
+
+    #[test]
+    fn tst_pool_lifecycle() -> Result<()> {
+        let mut pool = TransactionPool::with_config(PoolConfig {
+            retained_epochs: 2,
+            idempotency_window_ms: 2000,
+            transaction_ttl_epochs: 3,
+        });
+        let mut receipts = Receipts::default();
+        let c = "10.0.0.1";
+        for (epoch, req) in [(0, request(1, Some("late"))), (1, request(2, Some("a")))] {
+            let receipt = pool.insert_client(epoch, c, &req)?;
+            receipts.insert(c, &req, receipt);
+        }
+        pool.insert_client(3, c, &request(3, None))?;
+        assert!(pool.insert_client(1, c, &request(2, None))?.duplicate);
+
+        // committing epoch 1 pools what was left for epoch 0 again for epoch 2, along with its
+        // receipt, while retries of committed transactions get their original receipt
+        for key in pool.commit(1, &pool.pending(1)).moved {
+            receipts.move_to(&key, 2);
+        }
+        let pending = |pool: &TransactionPool, epoch| pool.get_epoch(epoch).len();
+        assert_eq!((pending(&pool, 0), pending(&pool, 2)), (0, 1));
+        assert_eq!(pool.pending_writes(2, &[1]), 1);
+        assert_eq!(pool.get_epoch(1).len(), 1);
+        let late = receipts.get(c, &request(1, Some("late")))?.unwrap();
+        assert_eq!(late.epoch, 2);
+        let receipt = receipts.get(c, &request(2, Some("a")))?.unwrap();
+        assert_eq!((receipt.epoch, receipt.duplicate, receipt.replayed), (1, false, true));
+
+        // an id is bound to its client and to the transaction it was first used for
+        assert_eq!(receipts.get("10.0.0.2", &request(2, Some("a")))?, None);
+        let mut other = request(2, Some("a"));
+        other.transaction.as_mut().unwrap().value = Some(vec![2]);
+        assert!(receipts.get(c, &other).is_err());
+
+        // resubmissions without an id are duplicates of the retained epoch that committed them,
+        // while a new id writes the same value again
+        let resubmitted = pool.insert_client(4, c, &request(2, None))?;
+        assert_eq!((resubmitted.epoch, resubmitted.duplicate), (1, true));
+        assert!(!pool.insert_client(4, c, &request(2, Some("b")))?.duplicate);
+        // the transaction first pooled for epoch 0 is not sent with epoch 2, its third, it is
+        // dropped rather than pooled again, along with the id of its receipt
+        let repooled = pool.commit(2, &HashSet::new());
+        assert_eq!(repooled.dropped, [(c.to_string(), "late".to_string())]);
+        assert!(repooled.moved.is_empty());
+        assert_eq!(pool.pending_writes(3, &[1]), 0);
+        pool.commit(3, &pool.pending(3));
+        assert!(pool.get_epoch(1).is_empty());
+        assert!(pool.insert_client(4, c, &request(2, None))?.duplicate);
+
+        // pending transactions do not expire by time, receipts do after the idempotency window
+        let later = Instant::now() + Duration::from_millis(2000);
+        receipts.expire(later, Duration::from_millis(2000));
+        assert_eq!((pending(&pool, 4), pool.pending_writes(4, &[2])), (1, 1));
+        assert_eq!(receipts.get(c, &request(2, Some("a")))?, None);
+        Ok(())
+    }
 }
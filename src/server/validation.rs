@@ -1,13 +1,15 @@
 use crate::grpc_handler::inner::mversegrpc;
-use crate::grpc_handler::outer::mverseouter::PeerTransactionRequest;
+use crate::grpc_handler::outer::mverseouter::{EpochCertificate, PeerTransactionRequest};
 
-use crate::server::MerkleVerseServer;
+use crate::server::{MerkleVerseServer, ServerId};
 use anyhow::{anyhow, Result};
 use bls_signatures::Serialize;
 use ed25519_dalek::{Signer, Verifier};
-use std::collections::hash_map::DefaultHasher;
+use hkdf::Hkdf;
+use prost::Message;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
 use std::fmt::{Debug, Formatter};
-use std::hash::{Hash, Hasher};
 
 // For public keys, the bls and dalek keys are specified separately.
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -16,39 +18,94 @@ pub struct PublicKey {
     pub dalek: ed25519_dalek::VerifyingKey,
 }
 
-// For private keys, the bls and dalek private keys are derived deterministically from the raw key.
+const PEER_EPOCH_AHEAD: u64 = 1; // epochs past its own a server accepts peer transactions for
+const KEY_FORMAT_HKDF: u8 = 1; // version byte of encoded keys whose subkeys are derived with HKDF
+const HKDF_SALT: &[u8] = b"merkleverse-key-derivation";
+const BLS_LABEL: &[u8] = b"merkleverse/bls12-381";
+const DALEK_LABEL: &[u8] = b"merkleverse/ed25519";
+const PEER_TRANSACTION_DOMAIN: &[u8] = b"merkleverse-peer-transaction";
+
+/// How the bls and dalek private keys are obtained from the seed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum KeyDerivation {
+    Legacy, // the seed is used as both keys, encoded as the bare 32 bytes
+    Hkdf,   // each key is expanded from the seed with its own label, encoded with a version byte
+}
+
+// For private keys, the bls and dalek private keys are derived deterministically from a 32 byte seed.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct PrivateKey {
     raw: Vec<u8>,
+    derivation: KeyDerivation,
     pub bls: bls_signatures::PrivateKey,
     pub dalek: ed25519_dalek::SigningKey,
 }
 
+fn expand(seed: &[u8; 32], label: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(HKDF_SALT), seed)
+        .expand(label, &mut out)
+        .expect("32 bytes is a valid HKDF output length");
+    out
+}
+
 impl PrivateKey {
+    /// the encoded key, as stored in configs and keystores
     pub fn as_bytes(&self) -> &[u8] {
         self.raw.as_slice()
     }
+
+    pub fn derivation(&self) -> KeyDerivation {
+        self.derivation
+    }
+
+    /// a new private key from a random seed
+    pub fn generate() -> Self {
+        let mut seed = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut seed);
+        Self::from(seed)
+    }
+
+    /// the key older versions derived from `seed`, where both keys share the same bytes
+    pub fn legacy(seed: [u8; 32]) -> Self {
+        Self {
+            bls: bls_signatures::PrivateKey::new(seed),
+            dalek: ed25519_dalek::SigningKey::from_bytes(&seed),
+            raw: seed.to_vec(),
+            derivation: KeyDerivation::Legacy,
+        }
+    }
 }
 
 impl TryFrom<Vec<u8>> for PrivateKey {
     type Error = anyhow::Error;
+    /// Decodes a key: 32 bytes are a legacy seed, 33 bytes are a version byte followed by the seed.
     fn try_from(value: Vec<u8>) -> std::result::Result<Self, Self::Error> {
-        if value.len()<32 {
-            return Err(anyhow!("Private key invalid length"));
+        match value.as_slice() {
+            seed if seed.len() == 32 => Ok(Self::legacy(seed.try_into()?)),
+            [KEY_FORMAT_HKDF, seed @ ..] if seed.len() == 32 => {
+                Ok(Self::from(<[u8; 32]>::try_from(seed)?))
+            }
+            [version, ..] if value.len() == 33 => {
+                Err(anyhow!("Unsupported private key format version {}", version))
+            }
+            _ => Err(anyhow!(
+                "Private key must be 32 or 33 bytes long, got {}",
+                value.len()
+            )),
         }
-        let vals: [u8;32] = value.as_slice().try_into()?;
-        Ok(Self::from(vals))
     }
 }
 
 impl From<[u8; 32]> for PrivateKey {
     fn from(value: [u8; 32]) -> Self {
+        let mut raw = vec![KEY_FORMAT_HKDF];
+        raw.extend_from_slice(&value);
         Self {
-            bls: bls_signatures::PrivateKey::new(&value),
-            dalek: ed25519_dalek::SigningKey::from_bytes(
-                &value
-            ),
-            raw: value.to_vec(),
+            bls: bls_signatures::PrivateKey::new(expand(&value, BLS_LABEL)),
+            dalek: ed25519_dalek::SigningKey::from_bytes(&expand(&value, DALEK_LABEL)),
+            raw,
+            derivation: KeyDerivation::Hkdf,
         }
     }
 }
@@ -71,24 +128,62 @@ impl PublicKey {
     }
 }
 
-impl Hash for mversegrpc::TransactionRequest {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.key.hash(state);
-        self.value.hash(state);
-        self.transaction_type.hash(state);
+/// Verifies a certificate over `message` whose signers hold `keys`, in the order they are listed.
+/// Each signature is checked against the key of its own signer, and the aggregate must combine
+/// exactly these signatures. Checking the aggregate against the sum of the keys instead would let
+/// a server register a key that cancels out the keys of the other signers.
+pub fn verify_multisig(
+    cert: &EpochCertificate,
+    message: &[u8],
+    keys: &[bls_signatures::PublicKey],
+) -> bool {
+    if keys.is_empty() || keys.len() != cert.signers.len() || keys.len() != cert.signatures.len()
+    {
+        return false;
+    }
+    let Ok(signatures) = cert
+        .signatures
+        .iter()
+        .map(|sig| bls_signatures::Signature::from_bytes(sig))
+        .collect::<Result<Vec<_>, _>>()
+    else {
+        return false;
+    };
+    if !keys.iter().zip(&signatures).all(|(key, sig)| key.verify(*sig, message)) {
+        return false;
+    }
+    match (
+        bls_signatures::aggregate(&signatures),
+        bls_signatures::Signature::from_bytes(&cert.aggregate_signature),
+    ) {
+        (Ok(combined), Ok(aggregate)) => combined == aggregate,
+        _ => false,
     }
 }
 
+/// The bytes a server signs to forward `transaction`, pooled for `epoch`, to its peers: a digest
+/// of the encoded transaction that every build of the server computes alike.
+fn transaction_message(transaction: &mversegrpc::TransactionRequest, epoch: u64) -> Vec<u8> {
+    let encoded = transaction.encode_to_vec();
+    let mut hasher = Sha256::new();
+    hasher.update(PEER_TRANSACTION_DOMAIN);
+    hasher.update((encoded.len() as u64).to_be_bytes());
+    hasher.update(&encoded);
+    hasher.update(epoch.to_be_bytes());
+    hasher.finalize().to_vec()
+}
+
 impl MerkleVerseServer {
     pub fn verify_peer_transaction(&self, transaction: &PeerTransactionRequest) -> Result<()> {
         // verifies the peer transaction with dalek
-        let parallel = match &self.parallel {
+        let parallel = match self.parallel() {
             None => Err(anyhow!("Peer server cluster does not exist!")),
             Some(p) => anyhow::Ok(p),
         }?;
 
-        let peer = parallel
-            .get_server(&transaction.server_id.clone().into())
+        let server_id = ServerId(transaction.server_id.clone());
+        parallel
+            .get_server(&server_id)
             .ok_or(anyhow!("Peer server {} does not exist in server cluster {:?}!", &transaction.server_id, parallel))?;
 
         let target_transaction = match &transaction.transaction {
@@ -96,25 +191,39 @@ impl MerkleVerseServer {
             Some(t) => anyhow::Ok(t),
         }?;
 
-        let peer_key = peer.public_key.dalek;
+        // the key is the one valid at this server's epoch, or at the next one a peer that
+        // committed first already pools for. The epoch the peer claims is signed along.
+        let current_epoch = self.current_epoch();
+        let epoch = transaction
+            .epoch
+            .as_ref()
+            .ok_or(anyhow!("An epoch number must be provided!"))?
+            .epoch;
+        if !(current_epoch..=current_epoch + PEER_EPOCH_AHEAD).contains(&epoch) {
+            return Err(anyhow!(
+                "Peer transaction is for epoch {}, this server is at epoch {}",
+                epoch,
+                current_epoch
+            ));
+        }
+        let peer_key = self
+            .public_key_at(&server_id, epoch)
+            .ok_or(anyhow!("No key is known for server {}", server_id.0))?
+            .dalek;
         let peer_sig = ed25519_dalek::Signature::try_from(&transaction.signature[..])?;
-        let mut hasher = DefaultHasher::new();
-        target_transaction.hash(&mut hasher);
-        let msg = hasher.finish().to_le_bytes();
         peer_key
-            .verify(&msg, &peer_sig)
+            .verify(&transaction_message(target_transaction, epoch), &peer_sig)
             .map_err(|e| anyhow!("Peer transaction signature is invalid: {}", e))
     }
 
     pub fn sign_transaction(
         &self,
         transaction: &mversegrpc::TransactionRequest,
+        epoch: u64,
+        key: &PrivateKey,
     ) -> Result<Vec<u8>> {
-        // signs the transaction with dalek
-        let mut hasher = DefaultHasher::new();
-        transaction.hash(&mut hasher);
-        let msg = hasher.finish().to_le_bytes();
-        let sig = self.private_key.dalek.sign(&msg);
+        // signs the transaction, and the epoch it is pooled for, with dalek
+        let sig = key.dalek.sign(&transaction_message(transaction, epoch));
         Ok(sig.to_bytes().to_vec())
     }
 }
@@ -131,4 +240,66 @@ mod tests {
         let priv_key = PrivateKey::from(src_bytes);
         Ok(())
     }
+
+    #[test]
+    fn tst_key_derivation() -> Result<()> {
+        let seed = [5u8; 32];
+        let key = PrivateKey::from(seed);
+        assert_eq!(key.as_bytes().len(), 33);
+        assert_ne!(key.bls.as_bytes(), PrivateKey::legacy(seed).bls.as_bytes());
+        assert_ne!(key.dalek.to_bytes(), seed);
+        assert_eq!(PrivateKey::try_from(key.as_bytes().to_vec())?, key);
+
+        let legacy = PrivateKey::try_from(seed.to_vec())?;
+        assert_eq!(legacy.derivation(), KeyDerivation::Legacy);
+        assert_eq!(legacy.dalek.to_bytes(), seed);
+
+        assert!(PrivateKey::try_from(vec![5u8; 31]).is_err());
+        assert!(PrivateKey::try_from(vec![5u8; 34]).is_err());
+        assert!(PrivateKey::try_from(vec![2u8; 33]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tst_multisig() {
+        let keys: Vec<PrivateKey> = (1..=3u8).map(|i| PrivateKey::from([i; 32])).collect();
+        let msg = b"chain";
+        let sigs: Vec<_> = keys.iter().map(|k| k.bls.sign(msg)).collect();
+        let cert = EpochCertificate {
+            aggregate_signature: bls_signatures::aggregate(&sigs).unwrap().as_bytes(),
+            signers: vec!["a".into(), "b".into(), "c".into()],
+            signatures: sigs.iter().map(|s| s.as_bytes()).collect(),
+        };
+        let pubs: Vec<_> = keys.iter().map(|k| k.bls.public_key()).collect();
+        assert!(verify_multisig(&cert, msg, &pubs));
+        assert!(!verify_multisig(&cert, msg, &pubs[..2]));
+        assert!(!verify_multisig(&cert, b"other", &pubs));
+
+        // each signature is checked against the key of its own signer, not against the sum
+        let mut swapped = pubs.clone();
+        swapped.swap(0, 1);
+        assert!(!verify_multisig(&cert, msg, &swapped));
+        let mut partial = cert.clone();
+        partial.aggregate_signature = sigs[0].as_bytes();
+        assert!(!verify_multisig(&partial, msg, &pubs));
+    }
+
+    #[test]
+    fn tst_transaction_message() {
+        let transaction = mversegrpc::TransactionRequest {
+            key: vec![1, 2],
+            value: Some(vec![3]),
+            transaction_type: mversegrpc::transaction_request::TransactionType::Update.into(),
+        };
+        // pinned, so that servers built with any toolchain verify each other's signatures
+        let msg = transaction_message(&transaction, 7);
+        assert_eq!(crate::utils::b64(&msg), "jaQI9U57dj5f7zgCERfcmHISZ4lK76SBdWxD1q3DTqA=");
+        assert_ne!(msg, transaction_message(&transaction, 8));
+        let deleted = mversegrpc::TransactionRequest {
+            value: None,
+            transaction_type: mversegrpc::transaction_request::TransactionType::Delete.into(),
+            ..transaction.clone()
+        };
+        assert_ne!(msg, transaction_message(&deleted, 7));
+    }
 }
@@ -35,6 +35,29 @@ pub fn binary_string(bytes: &Vec<u8>, length: usize) -> String {
     binary_string
 }
 
+/// parses a string of `0`s and `1`s into big-endian bytes, the inverse of `binary_string`
+pub fn binary_to_bytes(binary: &str) -> Result<Vec<u8>> {
+    if binary.is_empty() || !binary.chars().all(|c| c == '0' || c == '1') {
+        return Err(anyhow!("{:?} is not a binary string", binary));
+    }
+    let width = binary.len().div_ceil(8) * 8;
+    let padded = format!("{:0>width$}", binary, width = width);
+    padded
+        .as_bytes()
+        .chunks(8)
+        .map(|byte| Ok(u8::from_str_radix(std::str::from_utf8(byte)?, 2)?))
+        .collect()
+}
+
+/// the URL of a gRPC endpoint given as `host:port`, or as a full URL
+pub fn endpoint_url(addr: &str) -> String {
+    if addr.contains("://") {
+        addr.to_string()
+    } else {
+        format!("http://{}", addr)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -48,4 +71,14 @@ mod tests {
         assert_eq!(binary_string(&b64_to_loc(base_2, 9)?, 9), "100111011");
         Ok(())
     }
+
+    #[test]
+    fn tst_binary_to_bytes() -> Result<()> {
+        assert_eq!(binary_to_bytes("100111011")?, vec![0b1, 0b00111011]);
+        assert_eq!(b64(&binary_to_bytes("10")?), "Ag==");
+        assert_eq!(binary_string(&binary_to_bytes("010")?, 3), "010");
+        assert!(binary_to_bytes("012").is_err());
+        assert!(binary_to_bytes("").is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,720 @@
+use crate::args::ValidateConfigArgs;
+use crate::config::{ServerConfig, ServersConfig};
+use crate::keystore::{Keystore, PRIVATE_KEY_ENV};
+use crate::server::{peer_ids, tls_name, PrivateKey, PublicKey};
+use crate::utils::binary_string;
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine as _};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use toml::Spanned;
+use tracing_subscriber::EnvFilter;
+
+/// A problem found in a config file, at the line and column of the offending field when the file
+/// could be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub file: PathBuf,
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.position {
+            Some((line, column)) => write!(
+                f,
+                "{}:{}:{}: {}",
+                self.file.display(),
+                line,
+                column,
+                self.message
+            ),
+            None => write!(f, "{}: {}", self.file.display(), self.message),
+        }
+    }
+}
+
+type RawTable = BTreeMap<String, Spanned<toml::Value>>;
+
+/// the tables of a config file with their spans, used to locate problems
+#[derive(Deserialize)]
+struct RawConfig {
+    server: Option<Spanned<RawTable>>,
+    peers: Option<Vec<Spanned<RawTable>>>,
+}
+
+/// the byte ranges of the fields of a config file, by names like `server.prefix` or
+/// `peers[2].prefix`
+struct Locations {
+    source: String,
+    fields: HashMap<String, Range<usize>>,
+}
+
+impl Locations {
+    fn parse(source: String) -> Result<Self, toml::de::Error> {
+        let raw: RawConfig = toml::from_str(&source)?;
+        let mut fields = HashMap::new();
+        let mut add_table = |name: String, table: &Spanned<RawTable>| {
+            for (key, value) in table.get_ref() {
+                fields.insert(format!("{}.{}", name, key), value.span());
+            }
+            fields.insert(name, table.span());
+        };
+        if let Some(server) = &raw.server {
+            add_table("server".into(), server);
+        }
+        for (i, peer) in raw.peers.iter().flatten().enumerate() {
+            add_table(format!("peers[{}]", i), peer);
+        }
+        if let Some(first) = raw.peers.iter().flatten().next() {
+            fields.insert("peers".into(), first.span());
+        }
+        Ok(Self { source, fields })
+    }
+
+    /// the line and column of `field`, or of the closest enclosing table found
+    fn position(&self, field: &str) -> Option<(usize, usize)> {
+        let mut name = field;
+        loop {
+            if let Some(span) = self.fields.get(name) {
+                return Some(line_column(&self.source, span.start));
+            }
+            name = &name[..name.rfind('.')?];
+        }
+    }
+}
+
+fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or_default();
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// the problems found in one config file
+struct Report {
+    file: PathBuf,
+    locations: Option<Locations>,
+    problems: Vec<Problem>,
+}
+
+impl Report {
+    fn add(&mut self, field: &str, message: impl Into<String>) {
+        self.problems.push(Problem {
+            file: self.file.clone(),
+            position: self.locations.as_ref().and_then(|l| l.position(field)),
+            message: message.into(),
+        });
+    }
+
+    /// `path` resolved against the directory of the config, or the file of the same name next to
+    /// the config when that does not exist, as configs written with a manifest point to where
+    /// their files are deployed
+    fn local_path(&self, path: &Path) -> PathBuf {
+        let dir = self.file.parent().unwrap_or(Path::new(""));
+        let resolved = dir.join(path);
+        path.file_name()
+            .map(|name| dir.join(name))
+            .filter(|beside| !resolved.exists() && beside.exists())
+            .unwrap_or(resolved)
+    }
+}
+
+fn port_of(addr: &str) -> Option<u16> {
+    addr.rsplit_once(':')?.1.parse().ok()
+}
+
+fn decode_key(report: &mut Report, name: &str, field: &str, value: &str) -> Option<Vec<u8>> {
+    match general_purpose::STANDARD.decode(value) {
+        Ok(bytes) => Some(bytes),
+        Err(e) => {
+            report.add(
+                &format!("{}.{}", name, field),
+                format!("{} is not valid base64: {}", field, e),
+            );
+            None
+        }
+    }
+}
+
+/// checks the fields shared by the local server and its peers, returns the configured public key
+/// if it is valid
+fn check_server(report: &mut Report, name: &str, srv: &ServerConfig) -> Option<PublicKey> {
+    let field = |f: &str| format!("{}.{}", name, f);
+    if srv.id.trim().is_empty() {
+        report.add(&field("id"), "id must not be empty");
+    }
+    match port_of(&srv.connection_string) {
+        Some(port) if port > 0 => {}
+        _ => report.add(
+            &field("connection_string"),
+            format!(
+                "connection_string {:?} is not of the form host:port",
+                srv.connection_string
+            ),
+        ),
+    }
+    match (&srv.prefix, srv.prefix_length) {
+        (Some(_), None) => report.add(
+            &field("prefix"),
+            "prefix is set without prefix_length, add the number of bits of the prefix",
+        ),
+        (None, Some(_)) => report.add(
+            &field("prefix_length"),
+            "prefix_length is set without prefix",
+        ),
+        (Some(prefix), Some(prefix_length)) => {
+            if prefix_length > srv.length {
+                report.add(
+                    &field("prefix_length"),
+                    format!(
+                        "prefix_length {} exceeds the length {} of the tree",
+                        prefix_length, srv.length
+                    ),
+                );
+            }
+            if let Some(bytes) = decode_key(report, name, "prefix", prefix) {
+                let bits = binary_string(&bytes, bytes.len() * 8);
+                let overflow = bits.len().saturating_sub(prefix_length as usize);
+                if bits[..overflow].contains('1') {
+                    report.add(
+                        &field("prefix"),
+                        format!(
+                            "prefix {:?} does not fit in prefix_length {} bits",
+                            prefix, prefix_length
+                        ),
+                    );
+                }
+            }
+        }
+        (None, None) => {}
+    }
+    let bls = decode_key(report, name, "bls_pub_key", &srv.bls_pub_key)?;
+    let dalek = decode_key(report, name, "dalek_pub_key", &srv.dalek_pub_key)?;
+    match PublicKey::new(&bls, &dalek) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            report.add(&field("bls_pub_key"), format!("invalid public key: {}", e));
+            None
+        }
+    }
+}
+
+/// checks that the private key configured for the local server matches its public keys, without
+/// prompting for a keystore passphrase
+fn check_private_key(report: &mut Report, config: &ServersConfig, public_key: Option<&PublicKey>) {
+    let local = &config.server;
+    let (field, key) = match (&local.private_key, &local.keystore) {
+        (Some(_), Some(_)) => {
+            report.add(
+                "server.keystore",
+                "both private_key and keystore are set, keep only one",
+            );
+            return;
+        }
+        (Some(key), None) => (
+            "server.private_key",
+            general_purpose::STANDARD
+                .decode(key)
+                .map_err(|e| anyhow!(e))
+                .and_then(PrivateKey::try_from)
+                .map(|key| key.public_key()),
+        ),
+        (None, Some(path)) => (
+            "server.keystore",
+            Keystore::read(report.local_path(path)).and_then(|keystore| keystore.public_key()),
+        ),
+        (None, None) => {
+            if std::env::var(PRIVATE_KEY_ENV).is_err() {
+                report.add(
+                    "server",
+                    format!(
+                        "neither private_key nor keystore is set, and {} is not set",
+                        PRIVATE_KEY_ENV
+                    ),
+                );
+            }
+            return;
+        }
+    };
+    match (key, public_key) {
+        (Err(e), _) => report.add(field, format!("invalid private key: {}", e)),
+        (Ok(key), Some(public_key)) if key != *public_key => report.add(
+            field,
+            "private key does not match bls_pub_key and dalek_pub_key",
+        ),
+        _ => {}
+    }
+}
+
+/// checks that the TLS files of the local server can be read, and that its certificate is issued
+/// for its id
+fn check_tls(report: &mut Report, config: &ServersConfig) {
+    let Some(tls) = &config.server.tls else {
+        return;
+    };
+    for (field, path) in [("ca", &tls.ca), ("cert", &tls.cert), ("key", &tls.key)] {
+        let path = report.local_path(path);
+        let field = format!("server.tls.{}", field);
+        match std::fs::read(&path) {
+            Err(e) => report.add(&field, format!("cannot read {}: {}", path.display(), e)),
+            Ok(pem) if field == "server.tls.cert" => {
+                let id = &config.server.server_config.id;
+                let issued_for = x509_parser::pem::parse_x509_pem(&pem)
+                    .map_err(|e| anyhow!(e))
+                    .and_then(|(_, pem)| peer_ids(&pem.contents));
+                match issued_for {
+                    Err(e) => report.add(&field, format!("invalid certificate: {}", e)),
+                    Ok(ids) if !ids.contains(id) => report.add(
+                        &field,
+                        format!("certificate is not issued for {}", tls_name(id)),
+                    ),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn check_config(report: &mut Report, config: &ServersConfig) {
+    let local = &config.server;
+    let public_key = check_server(report, "server", &local.server_config);
+    check_private_key(report, config, public_key.as_ref());
+    check_tls(report, config);
+
+    for (field, port) in [
+        ("server.outer_port", local.outer_port),
+        ("server.inner_port", local.inner_port),
+    ] {
+        if port == 0 {
+            report.add(field, "port must not be 0");
+        }
+    }
+    if local.outer_port == local.inner_port {
+        report.add(
+            "server.inner_port",
+            format!("inner_port is the same as outer_port {}", local.outer_port),
+        );
+    }
+    if port_of(&local.server_config.connection_string).is_some_and(|port| port != local.outer_port)
+    {
+        report.add(
+            "server.connection_string",
+            format!(
+                "connection_string {:?} does not point to outer_port {}, peers cannot reach this server",
+                local.server_config.connection_string, local.outer_port
+            ),
+        );
+    }
+    match local.outer_addr.parse::<SocketAddr>() {
+        Ok(addr) if addr.port() != local.outer_port => report.add(
+            "server.outer_addr",
+            format!(
+                "outer_addr {:?} does not listen on outer_port {}",
+                local.outer_addr, local.outer_port
+            ),
+        ),
+        Ok(_) => {}
+        Err(e) => report.add(
+            "server.outer_addr",
+            format!(
+                "outer_addr {:?} is not an address to listen on: {}",
+                local.outer_addr, e
+            ),
+        ),
+    }
+    if local.epoch_interval == 0 {
+        report.add("server.epoch_interval", "epoch_interval must be positive");
+    }
+    let timeouts = &local.connections;
+    for (field, value) in [
+        ("connect_timeout_ms", timeouts.connect_timeout_ms),
+        ("request_timeout_ms", timeouts.request_timeout_ms),
+        ("health_interval_ms", timeouts.health_interval_ms),
+        ("backoff_initial_ms", timeouts.backoff_initial_ms),
+        ("broadcast_deadline_ms", timeouts.broadcast_deadline_ms),
+    ] {
+        if value == 0 {
+            report.add(
+                &format!("server.connections.{}", field),
+                format!("{} must be positive", field),
+            );
+        }
+    }
+    if timeouts.backoff_initial_ms > timeouts.backoff_max_ms {
+        report.add(
+            "server.connections.backoff_max_ms",
+            "backoff_max_ms must not be below backoff_initial_ms",
+        );
+    }
+    let admission = &local.admission;
+    for (field, value) in [
+        ("client_rate", admission.client_rate as usize),
+        ("client_burst", admission.client_burst as usize),
+        ("pool_capacity", admission.pool_capacity),
+        ("pending_per_key", admission.pending_per_key),
+    ] {
+        if value == 0 {
+            report.add(
+                &format!("server.admission.{}", field),
+                format!("{} must be positive, or no client transaction is admitted", field),
+            );
+        }
+    }
+    if local.pool.transaction_ttl_epochs == 0 {
+        report.add(
+            "server.pool.transaction_ttl_epochs",
+            "transaction_ttl_epochs must be positive, transactions would be dropped before they commit",
+        );
+    }
+    let pending_ms = local.pool.transaction_ttl_epochs.max(1) * local.epoch_interval as u64;
+    if local.pool.idempotency_window_ms < pending_ms {
+        report.add(
+            "server.pool.idempotency_window_ms",
+            format!(
+                "idempotency_window_ms is below {} ms, transaction_ttl_epochs times epoch_interval, retries of pending transactions would not be recognized",
+                pending_ms
+            ),
+        );
+    }
+    if local.shutdown.drain_timeout_ms < timeouts.broadcast_deadline_ms {
+        report.add(
+            "server.shutdown.drain_timeout_ms",
+            format!(
+                "drain_timeout_ms is below broadcast_deadline_ms {}, peers may not learn that this server is leaving",
+                timeouts.broadcast_deadline_ms
+            ),
+        );
+    }
+    if let Some(level) = &local.log_level {
+        if let Err(e) = EnvFilter::try_new(level) {
+            report.add(
+                "server.log_level",
+                format!("log_level {:?} is not a valid filter: {}", level, e),
+            );
+        }
+    }
+
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    for (i, peer) in config.peers.iter().flatten().enumerate() {
+        let name = format!("peers[{}]", i);
+        check_server(report, &name, peer);
+        if peer.id == local.server_config.id {
+            report.add(
+                &format!("{}.id", name),
+                format!("peer {} has the id of this server", peer.id),
+            );
+        }
+        if let Some(first) = seen.insert(&peer.id, i) {
+            report.add(
+                &format!("{}.id", name),
+                format!("peer {} is already listed as peers[{}]", peer.id, first),
+            );
+        }
+    }
+}
+
+/// Validates the config at `path` as the server would load it. Returns the config if it could
+/// be loaded, and every problem found.
+pub fn validate_file(path: &Path) -> (Option<ServersConfig>, Vec<Problem>) {
+    let mut report = Report {
+        file: path.to_path_buf(),
+        locations: None,
+        problems: vec![],
+    };
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            report.add("", format!("cannot read the config: {}", e));
+            return (None, report.problems);
+        }
+    };
+    match Locations::parse(source.clone()) {
+        Ok(locations) => report.locations = Some(locations),
+        Err(e) => {
+            report.problems.push(Problem {
+                file: path.to_path_buf(),
+                position: e.span().map(|span| line_column(&source, span.start)),
+                message: e.message().trim().to_string(),
+            });
+            return (None, report.problems);
+        }
+    }
+    let config = match ServersConfig::with_path(path) {
+        Ok(config) => config,
+        Err(e) => {
+            report.add("server", e.to_string());
+            return (None, report.problems);
+        }
+    };
+    check_config(&mut report, &config);
+    (Some(config), report.problems)
+}
+
+/// the fields a server and the peer entries describing it must agree on
+fn shared_fields(srv: &ServerConfig) -> [(&'static str, String); 6] {
+    [
+        ("connection_string", srv.connection_string.clone()),
+        ("prefix", format!("{:?}", srv.prefix)),
+        ("prefix_length", format!("{:?}", srv.prefix_length)),
+        ("length", srv.length.to_string()),
+        ("bls_pub_key", srv.bls_pub_key.clone()),
+        ("dalek_pub_key", srv.dalek_pub_key.clone()),
+    ]
+}
+
+/// Validates every `.toml` config of `dir`, as written by `gen-peers`, and checks that they
+/// describe the same cluster: unique ids, consistent and mutual peer entries, and no port clashes.
+pub fn validate_dir(dir: &Path) -> Result<Vec<Problem>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|p| p.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+    paths.sort();
+    if paths.is_empty() {
+        return Err(anyhow!("{} contains no .toml config", dir.display()));
+    }
+
+    let mut problems = vec![];
+    let mut reports = vec![];
+    for path in paths {
+        let (config, found) = validate_file(&path);
+        problems.extend(found);
+        if let Some(config) = config {
+            let locations = std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|source| Locations::parse(source).ok());
+            let report = Report {
+                file: path,
+                locations,
+                problems: vec![],
+            };
+            reports.push((report, config));
+        }
+    }
+
+    let mut issues: Vec<(usize, String, String)> = vec![]; // config index, field, message
+    let mut servers: HashMap<&str, usize> = HashMap::new();
+    for (i, (_, config)) in reports.iter().enumerate() {
+        let id = config.server.server_config.id.as_str();
+        match servers.get(id) {
+            Some(first) => issues.push((
+                i,
+                "server.id".into(),
+                format!(
+                    "server {} is also configured by {}",
+                    id,
+                    reports[*first].0.file.display()
+                ),
+            )),
+            None => {
+                servers.insert(id, i);
+            }
+        }
+    }
+
+    for (i, (_, config)) in reports.iter().enumerate() {
+        let local = &config.server.server_config;
+        let peers = config.peers.as_deref().unwrap_or_default();
+        for (j, peer) in peers.iter().enumerate() {
+            let Some(&k) = servers.get(peer.id.as_str()) else {
+                issues.push((
+                    i,
+                    format!("peers[{}].id", j),
+                    format!("peer {} has no config in {}", peer.id, dir.display()),
+                ));
+                continue;
+            };
+            let (file, srv) = (&reports[k].0.file, &reports[k].1.server.server_config);
+            for ((field, listed), (_, actual)) in
+                shared_fields(peer).into_iter().zip(shared_fields(srv))
+            {
+                if listed != actual {
+                    issues.push((
+                        i,
+                        format!("peers[{}].{}", j, field),
+                        format!(
+                            "{} of peer {} is {}, but {} has {}",
+                            field,
+                            peer.id,
+                            listed,
+                            file.display(),
+                            actual
+                        ),
+                    ));
+                }
+            }
+        }
+        for (j, peer) in peers.iter().enumerate() {
+            let Some(&k) = servers.get(peer.id.as_str()) else {
+                continue;
+            };
+            let listed_back = reports[k]
+                .1
+                .peers
+                .iter()
+                .flatten()
+                .any(|p| p.id == local.id);
+            if !listed_back {
+                issues.push((
+                    i,
+                    format!("peers[{}].id", j),
+                    format!(
+                        "peer {} does not list {} as a peer in {}",
+                        peer.id,
+                        local.id,
+                        reports[k].0.file.display()
+                    ),
+                ));
+            }
+        }
+    }
+
+    // servers sharing a host must not bind the same ports
+    let mut bound: HashMap<(&str, u16), (usize, &str)> = HashMap::new();
+    for (i, (_, config)) in reports.iter().enumerate() {
+        let local = &config.server;
+        let host = local
+            .server_config
+            .connection_string
+            .rsplit_once(':')
+            .map(|(host, _)| host)
+            .unwrap_or_default();
+        for (field, port) in [
+            ("outer_port", local.outer_port),
+            ("inner_port", local.inner_port),
+        ] {
+            if let Some((first, first_field)) = bound.insert((host, port), (i, field)) {
+                issues.push((
+                    i,
+                    format!("server.{}", field),
+                    format!(
+                        "port {} on {} is also the {} of {}",
+                        port,
+                        host,
+                        first_field,
+                        reports[first].0.file.display()
+                    ),
+                ));
+            }
+        }
+    }
+
+    for (i, field, message) in issues {
+        reports[i].0.add(&field, message);
+    }
+    for (report, _) in reports {
+        problems.extend(report.problems);
+    }
+    Ok(problems)
+}
+
+/// Validates the config at `path`, failing with every problem found.
+pub fn check_file(path: &Path) -> Result<ServersConfig> {
+    match validate_file(path) {
+        (Some(config), problems) if problems.is_empty() => Ok(config),
+        (_, problems) => Err(anyhow!(
+            "invalid config:\n{}",
+            problems
+                .iter()
+                .map(|p| format!("  {}", p))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )),
+    }
+}
+
+pub fn validate_config(args: ValidateConfigArgs) -> Result<()> {
+    let problems = match args.path.is_dir() {
+        true => validate_dir(&args.path)?,
+        false => validate_file(&args.path).1,
+    };
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    match problems.len() {
+        0 => {
+            eprintln!("{} is valid", args.path.display());
+            Ok(())
+        }
+        n => Err(anyhow!("found {} problem(s) in {}", n, args.path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::b64;
+    use bls_signatures::Serialize as _;
+
+    #[test]
+    fn tst_validate_file() -> Result<()> {
+        let key = PrivateKey::from([3u8; 32]);
+        let public_key = key.public_key();
+        let source = format!(
+            r#"[server]
+connection_string = "127.0.0.1:8000"
+id = "srv_0"
+prefix = "Aw=="
+length = 4
+bls_pub_key = "{}"
+dalek_pub_key = "{}"
+outer_port = 8000
+outer_addr = "0.0.0.0:8001"
+inner_port = 8000
+private_key = "{}"
+epoch_interval = 1000
+
+[[peers]]
+connection_string = "127.0.0.1:8001"
+id = "srv_1"
+prefix = "Aw=="
+prefix_length = 8
+length = 4
+bls_pub_key = "not base64"
+dalek_pub_key = "{}"
+"#,
+            b64(&public_key.bls.as_bytes()),
+            b64(public_key.dalek.as_bytes()),
+            b64(key.as_bytes()),
+            b64(public_key.dalek.as_bytes()),
+        );
+        let path = std::env::temp_dir().join(format!("validate-{}.toml", std::process::id()));
+        std::fs::write(&path, source)?;
+        let (config, problems) = validate_file(&path);
+        std::fs::remove_file(&path)?;
+
+        assert!(config.is_some());
+        let found: Vec<(Option<(usize, usize)>, &str)> = problems
+            .iter()
+            .map(|p| (p.position, p.message.as_str()))
+            .collect();
+        assert_eq!(found.len(), 5, "{:#?}", found);
+        assert_eq!(found[0].0, Some((4, 10)));
+        assert!(found[0]
+            .1
+            .starts_with("prefix is set without prefix_length"));
+        assert_eq!(found[1].0, Some((10, 14)));
+        assert!(found[1]
+            .1
+            .starts_with("inner_port is the same as outer_port"));
+        assert_eq!(found[2].0, Some((9, 14)));
+        assert!(found[2].1.ends_with("does not listen on outer_port 8000"));
+        assert_eq!(found[3].0, Some((18, 17)));
+        assert!(found[3].1.starts_with("prefix_length 8 exceeds"));
+        assert_eq!(found[4].0, Some((20, 15)));
+        assert!(found[4].1.starts_with("bls_pub_key is not valid base64"));
+        assert!(check_file(&path).is_err());
+        Ok(())
+    }
+}